@@ -0,0 +1,238 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use dashmap::DashMap;
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+
+use crate::config_loader::{Access, Cidr, Config};
+
+/// How often list files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+struct ListFile {
+    nets: Vec<IpNet>,
+    modified: Option<SystemTime>,
+}
+
+impl ListFile {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Loaded along with the config naming them, keyed by path.
+static LIST_FILES: Lazy<DashMap<String, Arc<ListFile>>> = Lazy::new(DashMap::new);
+
+/// `country` is the client's country code, if known.
+pub fn allowed(access: &Access, ip: IpAddr, country: Option<&str>) -> bool {
+    // Clients connecting over IPv4 to a dual stack socket show up as `::ffff:a.b.c.d`
+    let ip = ip.to_canonical();
+    let in_countries = |codes: &[String]| {
+        country.is_some_and(|c| codes.iter().any(|code| code.eq_ignore_ascii_case(c)))
+    };
+
+    let denied = access.deny.iter().any(|Cidr(net)| net.contains(&ip))
+        || access.deny_files.iter().any(|f| in_list_file(f, &ip))
+        || in_countries(&access.deny_countries);
+    if denied {
+        return false;
+    }
+
+    if access.allow.is_empty() && access.allow_files.is_empty() && access.allow_countries.is_empty()
+    {
+        return true;
+    }
+
+    access.allow.iter().any(|Cidr(net)| net.contains(&ip))
+        || access.allow_files.iter().any(|f| in_list_file(f, &ip))
+        || in_countries(&access.allow_countries)
+}
+
+/// Lists are only read by `load_lists`, so checking never waits on the disk.
+fn in_list_file(path: &str, ip: &IpAddr) -> bool {
+    LIST_FILES.get(path).is_some_and(|list| list.contains(ip))
+}
+
+/// Read the list files `config` names which are not loaded yet, before it is
+/// used. This blocks, so is run off the runtime.
+pub fn load_lists(config: &Config) {
+    let routes = config.hosts.values().flat_map(|host| &host.routes);
+    let accesses = config
+        .global
+        .access
+        .iter()
+        .chain(
+            config
+                .hosts
+                .values()
+                .filter_map(|host| host.access.as_ref()),
+        )
+        .chain(routes.filter_map(|route| route.access.as_ref()));
+
+    for access in accesses {
+        for path in access.allow_files.iter().chain(&access.deny_files) {
+            if !LIST_FILES.contains_key(path) {
+                LIST_FILES.insert(path.clone(), Arc::new(load(path)));
+            }
+        }
+    }
+}
+
+/// A file which cannot be read is treated as empty, so an allow list fails closed.
+fn load(path: &str) -> ListFile {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::error!("Could not read address list {path}: {e}");
+            return ListFile {
+                nets: Vec::new(),
+                modified,
+            };
+        }
+    };
+
+    let nets = data
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| match line.parse::<Cidr>() {
+            Ok(Cidr(net)) => Some(net),
+            Err(_) => {
+                tracing::warn!("Ignoring invalid address {line:?} in {path}");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    tracing::info!("Loaded {} address(es) from {path}", nets.len());
+
+    ListFile { nets, modified }
+}
+
+/// Reload list files when they change on disk, forever.
+pub async fn reload_changed() {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        _ = tokio::task::spawn_blocking(reload_lists).await;
+    }
+}
+
+fn reload_lists() {
+    let changed: Vec<String> = LIST_FILES
+        .iter()
+        .filter(|list| {
+            let modified = std::fs::metadata(list.key())
+                .and_then(|m| m.modified())
+                .ok();
+            modified != list.modified
+        })
+        .map(|list| list.key().clone())
+        .collect();
+
+    for path in changed {
+        let list = Arc::new(load(&path));
+        LIST_FILES.insert(path, list);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn access(value: serde_json::Value) -> Access {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn cidrs_matched() {
+        let access = access(json!({ "allow": ["10.0.0.0/8", "192.168.1.5", "fd00::/8"] }));
+
+        assert!(allowed(&access, ip("10.1.2.3"), None));
+        assert!(allowed(&access, ip("192.168.1.5"), None));
+        assert!(allowed(&access, ip("fd12::1"), None));
+        // IPv4 clients of a dual stack socket
+        assert!(allowed(&access, ip("::ffff:10.1.2.3"), None));
+        assert!(!allowed(&access, ip("192.168.1.6"), None));
+        assert!(!allowed(&access, ip("11.0.0.1"), None));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = access(json!({
+            "allow": ["10.0.0.0/8"],
+            "deny": ["10.0.0.0/24"],
+            "deny_countries": ["RU"],
+        }));
+
+        assert!(allowed(&access, ip("10.1.0.1"), Some("GB")));
+        assert!(!allowed(&access, ip("10.0.0.1"), Some("GB")));
+        assert!(!allowed(&access, ip("10.1.0.1"), Some("ru")));
+    }
+
+    #[test]
+    fn everything_allowed_without_allow_list() {
+        let access = access(json!({ "deny": ["10.0.0.1"] }));
+
+        assert!(allowed(&access, ip("10.0.0.2"), None));
+        assert!(!allowed(&access, ip("10.0.0.1"), None));
+        assert!(allowed(&Access::default(), ip("10.0.0.1"), None));
+    }
+
+    #[test]
+    fn countries_allowed() {
+        let access = access(json!({ "allow_countries": ["GB"] }));
+
+        assert!(allowed(&access, ip("10.0.0.1"), Some("gb")));
+        assert!(!allowed(&access, ip("10.0.0.1"), Some("FR")));
+        // Unknown countries are not in the list
+        assert!(!allowed(&access, ip("10.0.0.1"), None));
+    }
+
+    #[test]
+    fn list_files_loaded_with_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let allow = dir.path().join("allow.txt");
+        let deny = dir.path().join("deny.txt");
+        std::fs::write(&allow, "# office\n10.0.0.0/8 # all of it\n\nnonsense\n").unwrap();
+        std::fs::write(&deny, "10.0.0.66\n").unwrap();
+        let missing = dir.path().join("missing.txt");
+
+        let value = json!({
+            "allow_files": [allow, missing],
+            "deny_files": [deny],
+        });
+        let config = json!({ "access": value, "hosts": [] }).to_string();
+        let access = access(value);
+
+        // Not read until the config naming them is loaded
+        assert!(!allowed(&access, ip("10.0.0.1"), None));
+        load_lists(&Config::parse(&config).unwrap());
+
+        assert!(allowed(&access, ip("10.0.0.1"), None));
+        assert!(!allowed(&access, ip("10.0.0.66"), None));
+        assert!(!allowed(&access, ip("11.0.0.1"), None));
+    }
+
+    #[test]
+    fn unreadable_allow_list_fails_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.txt");
+
+        let value = json!({ "allow_files": [missing] });
+        let config = json!({ "access": value, "hosts": [] }).to_string();
+        load_lists(&Config::parse(&config).unwrap());
+
+        assert!(!allowed(&access(value), ip("10.0.0.1"), None));
+    }
+}
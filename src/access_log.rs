@@ -0,0 +1,503 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderValue, HOST, REFERER, USER_AGENT};
+use hyper::{Request, StatusCode};
+use once_cell::sync::Lazy;
+
+use crate::body::BytesIn;
+use crate::config_loader::{AccessLog, AccessLogFormat, Rotate};
+use crate::proxy::ClientInfo;
+use crate::HOSTS;
+
+/// Entries are written by a thread of their own, so requests never wait on the disk.
+static LOG: Lazy<Option<Sender<Entry>>> = Lazy::new(|| {
+    let config = HOSTS.load().global.access_log.clone()?;
+    let path = config.path.clone();
+
+    let writer = match Writer::open(config) {
+        Ok(writer) => writer,
+        Err(e) => {
+            tracing::error!("Could not open access log {path}: {e}");
+            return None;
+        }
+    };
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("access-log".into())
+        .spawn(move || writer.run(rx))
+        .map_err(|e| tracing::error!("Could not start access log writer: {e}"))
+        .ok()?;
+    Some(tx)
+});
+
+/// One request, as written to the access log.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub client: Option<IpAddr>,
+    pub method: String,
+    pub host: String,
+    /// Path and query.
+    pub path: String,
+    pub version: String,
+    pub status: u16,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub upstream: Option<String>,
+    pub upstream_ms: Option<u64>,
+    pub total_ms: u64,
+    pub tls: Option<&'static str>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub request_id: String,
+}
+
+/// Added to the extensions of a proxied response.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub destination: String,
+    /// Until the response headers arrived, or the request failed.
+    pub latency: Duration,
+}
+
+/// A request whose response has not been sent yet.
+pub struct Pending {
+    entry: Entry,
+    started: Instant,
+    bytes_in: BytesIn,
+}
+
+/// Starts an entry for `req`, if there is an access log.
+pub fn start<B>(req: &Request<B>, request_id: &str, bytes_in: &BytesIn) -> Option<Pending> {
+    LOG.as_ref()?;
+
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+            .map(str::to_owned)
+    };
+    let client = req.extensions().get::<ClientInfo>();
+
+    let entry = Entry {
+        time: SystemTime::now(),
+        client: client.map(|c| c.addr.ip()),
+        method: req.method().to_string(),
+        host: header(HOST).unwrap_or_default(),
+        path: req
+            .uri()
+            .path_and_query()
+            .map_or("/", |p| p.as_str())
+            .to_owned(),
+        version: format!("{:?}", req.version()),
+        status: 0,
+        bytes_in: 0,
+        bytes_out: 0,
+        upstream: None,
+        upstream_ms: None,
+        total_ms: 0,
+        tls: client.and_then(|c| c.tls_version),
+        user_agent: header(USER_AGENT),
+        referer: header(REFERER),
+        request_id: request_id.to_owned(),
+    };
+
+    Some(Pending {
+        entry,
+        started: Instant::now(),
+        bytes_in: bytes_in.clone(),
+    })
+}
+
+impl Pending {
+    /// Called once the response body has been sent, or dropped.
+    pub fn write(mut self, status: StatusCode, upstream: Option<&Upstream>, bytes_out: u64) {
+        let Some(log) = LOG.as_ref() else {
+            return;
+        };
+
+        self.entry.status = status.as_u16();
+        if let Some(upstream) = upstream {
+            self.entry.upstream = Some(upstream.destination.clone());
+            self.entry.upstream_ms = Some(upstream.latency.as_millis() as u64);
+        }
+        self.entry.bytes_in = self.bytes_in.get();
+        self.entry.bytes_out = bytes_out;
+        self.entry.total_ms = self.started.elapsed().as_millis() as u64;
+
+        _ = log.send(self.entry);
+    }
+}
+
+struct Writer {
+    config: AccessLog,
+    file: BufWriter<File>,
+    size: u64,
+    /// The rotation period the current file belongs to.
+    period: u64,
+}
+
+impl Writer {
+    fn open(config: AccessLog) -> io::Result<Writer> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        let period = metadata
+            .modified()
+            .map(|modified| period(config.rotate, modified))
+            .unwrap_or_default();
+
+        Ok(Writer {
+            size: metadata.len(),
+            period,
+            config,
+            file: BufWriter::new(file),
+        })
+    }
+
+    fn run(mut self, entries: Receiver<Entry>) {
+        while let Ok(entry) = entries.recv() {
+            self.write(&entry);
+            // Flushing once the burst is over
+            while let Ok(entry) = entries.try_recv() {
+                self.write(&entry);
+            }
+            if let Err(e) = self.file.flush() {
+                tracing::warn!("Could not write access log {}: {e}", self.config.path);
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &Entry) {
+        let full = self.config.max_size.is_some_and(|max| self.size >= max);
+        let next_period =
+            self.config.rotate.is_some() && period(self.config.rotate, entry.time) != self.period;
+
+        if full || next_period {
+            if let Err(e) = self.rotate(entry.time) {
+                tracing::warn!("Could not rotate access log {}: {e}", self.config.path);
+            }
+        }
+
+        let mut line = match self.config.format {
+            AccessLogFormat::Combined => combined(entry),
+            AccessLogFormat::Json => json(entry),
+        };
+        line.push('\n');
+
+        match self.file.write_all(line.as_bytes()) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => tracing::warn!("Could not write access log {}: {e}", self.config.path),
+        }
+    }
+
+    /// Moves the current file aside with the time appended, then starts a new one.
+    fn rotate(&mut self, time: SystemTime) -> io::Result<()> {
+        self.file.flush()?;
+
+        let (y, mo, d, h, mi, s, _) = utc(time);
+        let stamp = format!("{y:04}{mo:02}{d:02}-{h:02}{mi:02}{s:02}");
+        let mut rotated = format!("{}.{stamp}", self.config.path);
+        let mut n = 1;
+        while Path::new(&rotated).exists() {
+            rotated = format!("{}.{stamp}-{n}", self.config.path);
+            n += 1;
+        }
+        std::fs::rename(&self.config.path, &rotated)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        self.period = period(self.config.rotate, time);
+
+        self.prune()
+    }
+
+    /// Deletes the oldest rotated files beyond `keep`.
+    fn prune(&self) -> io::Result<()> {
+        let path = Path::new(&self.config.path);
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+
+        let mut rotated = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(self.config.keep);
+        for old in &rotated[..excess] {
+            std::fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which hour or day (UTC) `time` falls in.
+fn period(rotate: Option<Rotate>, time: SystemTime) -> u64 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match rotate {
+        Some(Rotate::Hourly) => secs / 3600,
+        Some(Rotate::Daily) => secs / 86400,
+        None => 0,
+    }
+}
+
+/// Year, month, day, hour, minute, second and millisecond in UTC.
+fn utc(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Howard Hinnant's days to civil date
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+        since.subsec_millis(),
+    )
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Combined Log Format, followed by host, request ID, upstream, upstream and
+/// total milliseconds, TLS version and request body bytes.
+fn combined(entry: &Entry) -> String {
+    let (y, mo, d, h, mi, s, _) = utc(entry.time);
+    let dash = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+
+    format!(
+        "{} - - [{d:02}/{}/{y:04}:{h:02}:{mi:02}:{s:02} +0000] \"{} {} {}\" {} {} \"{}\" \"{}\" \"{}\" \"{}\" \"{}\" {} {} {} {}",
+        dash(entry.client.map(|ip| ip.to_string())),
+        MONTHS[mo as usize - 1],
+        quoted(&entry.method),
+        quoted(&entry.path),
+        entry.version,
+        entry.status,
+        entry.bytes_out,
+        quoted(entry.referer.as_deref().unwrap_or("-")),
+        quoted(entry.user_agent.as_deref().unwrap_or("-")),
+        quoted(&entry.host),
+        quoted(&entry.request_id),
+        quoted(entry.upstream.as_deref().unwrap_or("-")),
+        dash(entry.upstream_ms.map(|ms| ms.to_string())),
+        entry.total_ms,
+        entry.tls.unwrap_or("-"),
+        entry.bytes_in,
+    )
+}
+
+/// Escapes what would end or break up a quoted field.
+fn quoted(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `time` in UTC, to the millisecond.
+pub fn rfc3339(time: SystemTime) -> String {
+    let (y, mo, d, h, mi, s, ms) = utc(time);
+    format!("{y:04}-{mo:02}-{d:02}T{h:02}:{mi:02}:{s:02}.{ms:03}Z")
+}
+
+fn json(entry: &Entry) -> String {
+    serde_json::json!({
+        "time": rfc3339(entry.time),
+        "client": entry.client,
+        "method": entry.method,
+        "host": entry.host,
+        "path": entry.path,
+        "version": entry.version,
+        "status": entry.status,
+        "bytes_in": entry.bytes_in,
+        "bytes_out": entry.bytes_out,
+        "upstream": entry.upstream,
+        "upstream_ms": entry.upstream_ms,
+        "total_ms": entry.total_ms,
+        "tls": entry.tls,
+        "user_agent": entry.user_agent,
+        "referer": entry.referer,
+        "request_id": entry.request_id,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry {
+        Entry {
+            // 2026-10-19 09:05:03.250 UTC
+            time: UNIX_EPOCH + Duration::from_millis(1_792_400_703_250),
+            client: Some("81.2.69.160".parse().unwrap()),
+            method: "GET".into(),
+            host: "media.citrusfire.co.uk".into(),
+            path: "/Items/42/Images/Primary?quality=90".into(),
+            version: "HTTP/1.1".into(),
+            status: 200,
+            bytes_in: 0,
+            bytes_out: 5120,
+            upstream: Some("http://10.0.0.5:8096".into()),
+            upstream_ms: Some(12),
+            total_ms: 15,
+            tls: Some("TLSv1.3"),
+            user_agent: Some("Emby \"Theater\"".into()),
+            referer: None,
+            request_id: "0000000100000002".into(),
+        }
+    }
+
+    #[test]
+    fn calendar() {
+        assert_eq!(utc(UNIX_EPOCH), (1970, 1, 1, 0, 0, 0, 0));
+        assert_eq!(utc(entry().time), (2026, 10, 19, 9, 5, 3, 250));
+        // Leap day
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(utc(leap), (2000, 2, 29, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn combined_format() {
+        assert_eq!(
+            combined(&entry()),
+            "81.2.69.160 - - [19/Oct/2026:09:05:03 +0000] \"GET /Items/42/Images/Primary?quality=90 HTTP/1.1\" 200 5120 \"-\" \"Emby \\\"Theater\\\"\" \"media.citrusfire.co.uk\" \"0000000100000002\" \"http://10.0.0.5:8096\" 12 15 TLSv1.3 0"
+        );
+
+        let mut local = entry();
+        local.client = None;
+        local.upstream = None;
+        local.upstream_ms = None;
+        local.tls = None;
+        local.path = "/a\nb".into();
+        assert_eq!(
+            combined(&local),
+            "- - - [19/Oct/2026:09:05:03 +0000] \"GET /a\\x0ab HTTP/1.1\" 200 5120 \"-\" \"Emby \\\"Theater\\\"\" \"media.citrusfire.co.uk\" \"0000000100000002\" \"-\" - 15 - 0"
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let line: serde_json::Value = serde_json::from_str(&json(&entry())).unwrap();
+        assert_eq!(line["time"], "2026-10-19T09:05:03.250Z");
+        assert_eq!(line["client"], "81.2.69.160");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["upstream_ms"], 12);
+        assert_eq!(line["user_agent"], "Emby \"Theater\"");
+        assert_eq!(line["referer"], serde_json::Value::Null);
+    }
+
+    fn rotated(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "access.log")
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let mut writer = Writer::open(AccessLog {
+            path: path.to_string_lossy().into_owned(),
+            format: AccessLogFormat::Json,
+            max_size: Some(1),
+            rotate: None,
+            keep: 2,
+        })
+        .unwrap();
+
+        for n in 0..4u64 {
+            let mut entry = entry();
+            entry.time += Duration::from_secs(n);
+            writer.write(&entry);
+        }
+        writer.file.flush().unwrap();
+
+        // Each write found the file full, and only two old ones are kept
+        assert_eq!(
+            rotated(dir.path()),
+            ["access.log.20261019-090505", "access.log.20261019-090506"]
+        );
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        assert!(current.contains("09:05:06"));
+    }
+
+    #[test]
+    fn rotates_hourly() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let mut writer = Writer::open(AccessLog {
+            path: path.to_string_lossy().into_owned(),
+            format: AccessLogFormat::Combined,
+            max_size: None,
+            rotate: Some(Rotate::Hourly),
+            keep: 7,
+        })
+        .unwrap();
+        // As if the file was created during this hour
+        writer.period = period(Some(Rotate::Hourly), entry().time);
+
+        let mut later = entry();
+        writer.write(&later);
+        later.time += Duration::from_secs(600);
+        writer.write(&later);
+        assert!(rotated(dir.path()).is_empty());
+
+        later.time += Duration::from_secs(3600);
+        writer.write(&later);
+        writer.file.flush().unwrap();
+
+        assert_eq!(rotated(dir.path()), ["access.log.20261019-101503"]);
+        let old = std::fs::read_to_string(dir.path().join("access.log.20261019-101503")).unwrap();
+        assert_eq!(old.lines().count(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+}
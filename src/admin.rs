@@ -0,0 +1,850 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http_body_util::{BodyExt, Limited};
+use hyper::body::Incoming;
+use hyper::header::{
+    AUTHORIZATION, CACHE_CONTROL, CONTENT_SECURITY_POLICY, CONTENT_TYPE, ETAG, HOST, IF_MATCH,
+    ORIGIN, WWW_AUTHENTICATE, X_FRAME_OPTIONS,
+};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::access_log::rfc3339;
+use crate::config_edit::{self, Change, EditError, Editor};
+use crate::config_loader::{Action, Admin, BasicAuth, Config, UnknownHost, CONFIG};
+use crate::proxy::{full, ProxyBody};
+use crate::upstream::{self, Mode};
+use crate::{
+    auth, cache, config_history, handlers, listener, metrics, rate_limit, shutdown, tls, HOSTS,
+};
+
+const PORTAL: &str = include_str!("../res/admin/portal.html");
+
+const REALM: &str = "Envoi admin";
+
+/// Largest request body the API reads.
+const BODY_LIMIT: usize = 1024 * 1024;
+
+/// Plain HTTP, so only meant to be bound to localhost or a private network.
+pub async fn serve(config: Admin) {
+    let addr = config.listen;
+    // Inherited and handed over like the proxy's own, so it survives upgrades
+    let listener = match listener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Could not start admin listener on {addr}: {e}");
+            return;
+        }
+    };
+
+    tracing::info!("Starting admin listener on {addr}");
+    if !signs_in(&config) && !addr.ip().is_loopback() {
+        tracing::warn!(
+            "Admin API on {addr} has no token or users, anyone who can reach it can use it"
+        );
+    }
+
+    let config = Arc::new(config);
+
+    loop {
+        let (stream, client) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::debug!("Admin listener: {e}");
+                    continue;
+                }
+            },
+            _ = shutdown::triggered() => return,
+        };
+
+        let config = config.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle(config.clone(), client, req));
+            let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = conn.await {
+                tracing::debug!("Error serving admin connection: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(
+    config: Arc<Admin>,
+    client: SocketAddr,
+    req: Request<Incoming>,
+) -> Result<Response<ProxyBody>, Infallible> {
+    let path = req.uri().path().to_owned();
+    let method = req.method().clone();
+
+    match (&method, path.as_str()) {
+        (&Method::GET, "/metrics") => {
+            return Ok(Response::builder()
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(full(metrics::render()))
+                .unwrap())
+        }
+        // Holds nothing itself, everything it shows comes from the API
+        (&Method::GET, "/") => {
+            return Ok(Response::builder()
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .header(
+                    CONTENT_SECURITY_POLICY,
+                    "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self'",
+                )
+                .header(X_FRAME_OPTIONS, "DENY")
+                .body(full(PORTAL))
+                .unwrap())
+        }
+        _ => {}
+    }
+
+    let Some(api) = path.strip_prefix("/api/") else {
+        return Ok(handlers::not_found());
+    };
+
+    let Some(user) = caller(&config, &req).await else {
+        let mut res = error(StatusCode::UNAUTHORIZED, "sign in or send the admin token");
+        let challenge = match config.htpasswd {
+            Some(_) => auth::challenge(REALM),
+            None => "Bearer".parse().unwrap(),
+        };
+        res.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+        return Ok(res);
+    };
+
+    if method != Method::GET && cross_origin(&req) {
+        return Ok(error(
+            StatusCode::FORBIDDEN,
+            "cross-origin requests are refused",
+        ));
+    }
+
+    // Changes have to be put down to someone, and without anyone to sign in any
+    // page the operator visits could make them, as browsers send some requests
+    // without an Origin, and DNS rebinding gets past comparing it with Host
+    if method != Method::GET && !signs_in(&config) {
+        return Ok(error(
+            StatusCode::FORBIDDEN,
+            "set admin.token or admin.htpasswd to change anything",
+        ));
+    }
+
+    let editor = Editor {
+        user,
+        client: Some(client.ip()),
+    };
+    let expected = req
+        .headers()
+        .get(IF_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_matches('"').to_owned());
+
+    let query = req.uri().query().map(str::to_owned);
+    let segments = api.split('/').collect::<Vec<_>>();
+    Ok(match (&method, segments.as_slice()) {
+        (&Method::GET, ["hosts"]) => json_response(StatusCode::OK, hosts()),
+        (&Method::GET, ["upstreams"]) => json_response(StatusCode::OK, upstreams()),
+        (&Method::POST, ["upstreams", address, action]) => {
+            set_mode(&config, &editor, address, action)
+        }
+        (&Method::GET, ["connections"]) => json_response(StatusCode::OK, connections()),
+        (&Method::GET, ["certificates"]) => json_response(StatusCode::OK, certificates()),
+        (&Method::GET, ["cache"]) => {
+            let stats = cache::stats();
+            json_response(
+                StatusCode::OK,
+                json!({
+                    "entries": stats.entries,
+                    "bytes": stats.bytes,
+                    "disk_entries": stats.disk_entries,
+                    "disk_bytes": stats.disk_bytes,
+                }),
+            )
+        }
+        (&Method::POST, ["cache", "purge"]) => match read_json(req).await {
+            Ok(body) => purge_cache(&config, &editor, &body),
+            Err(res) => res,
+        },
+        (&Method::POST, ["reload"]) => match config_edit::reload().await {
+            Ok(hosts) => {
+                config_edit::audit(&config.audit_log, &editor, "reload", "", None, None);
+                keep_snapshot(&config);
+                json_response(StatusCode::OK, json!({ "hosts": hosts }))
+            }
+            Err(e) => error(StatusCode::UNPROCESSABLE_ENTITY, &e),
+        },
+        (&Method::GET, ["config"]) => config_file(),
+        (&Method::GET, ["config", "history"]) => history(&config),
+        (&Method::GET, ["config", "history", id]) => snapshot(&config, id),
+        (&Method::GET, ["config", "diff"]) => diff(&config, query.as_deref()),
+        (&Method::POST, ["config", "history", id, "rollback"]) => {
+            rollback(&config, &editor, id, expected).await
+        }
+        (&Method::POST, ["config", "validate"]) => match read_json(req).await {
+            Ok(host) => {
+                let report = config_edit::validate(&host);
+                json_response(
+                    StatusCode::OK,
+                    json!({ "errors": report.errors, "warnings": report.warnings }),
+                )
+            }
+            Err(res) => res,
+        },
+        (&Method::POST, ["config", "hosts"]) => match read_json(req).await {
+            Ok(host) => edit(&config, &editor, Change::Create(host), expected).await,
+            Err(res) => res,
+        },
+        (&Method::PUT, ["config", "hosts", name]) => match read_json(req).await {
+            Ok(host) => {
+                let change = Change::Update((*name).to_owned(), host);
+                edit(&config, &editor, change, expected).await
+            }
+            Err(res) => res,
+        },
+        (&Method::DELETE, ["config", "hosts", name]) => {
+            let change = Change::Delete((*name).to_owned());
+            edit(&config, &editor, change, expected).await
+        }
+        _ => error(StatusCode::NOT_FOUND, "no such endpoint"),
+    })
+}
+
+/// Whether anyone has to sign in to use the API.
+fn signs_in(config: &Admin) -> bool {
+    config.token.is_some() || config.htpasswd.is_some()
+}
+
+/// Who is making `req`, `None` if they did not sign in. Without a token or users
+/// to sign in as, everyone who can reach the listener is let in.
+async fn caller<B>(config: &Admin, req: &Request<B>) -> Option<String> {
+    if !signs_in(config) {
+        return Some("anonymous".into());
+    }
+
+    if let Some(token) = &config.token {
+        let given = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        // Comparing digests takes the same time however much of the token is right
+        if given.is_some_and(|given| Sha256::digest(given) == Sha256::digest(token)) {
+            return Some("token".into());
+        }
+    }
+
+    let basic = BasicAuth {
+        htpasswd: config.htpasswd.clone()?,
+        realm: REALM.into(),
+    };
+    auth::basic_user(&basic, req.headers()).await
+}
+
+/// Browsers say where cross-site requests come from, which could otherwise ride
+/// on the Basic credentials of a signed in admin.
+fn cross_origin<B>(req: &Request<B>) -> bool {
+    let Some(origin) = req.headers().get(ORIGIN).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let origin = origin.parse::<Uri>().ok();
+    let host = req.headers().get(HOST).and_then(|v| v.to_str().ok());
+
+    origin
+        .as_ref()
+        .and_then(|o| o.authority())
+        .map(|a| a.as_str())
+        != host
+}
+
+async fn read_json(req: Request<Incoming>) -> Result<Value, Response<ProxyBody>> {
+    let body = match Limited::new(req.into_body(), BODY_LIMIT).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                &format!("reading body: {e}"),
+            ))
+        }
+    };
+    serde_json::from_slice(&body)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, &format!("invalid JSON: {e}")))
+}
+
+fn hosts() -> Value {
+    let config = HOSTS.load();
+    let hosts = config
+        .hosts
+        .iter()
+        .map(|(name, host)| {
+            let mut value = serde_json::to_value(host).unwrap_or_default();
+            config_edit::redact(&mut value);
+            (name.clone(), value)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    json!({ "hosts": hosts.into_values().collect::<Vec<_>>() })
+}
+
+/// The hosts as written in the config file, for editing.
+fn config_file() -> Response<ProxyBody> {
+    let (file, version) = match config_edit::read() {
+        Ok(read) => read,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e),
+    };
+
+    let mut hosts = config_edit::hosts(&file).to_vec();
+    hosts.iter_mut().for_each(config_edit::redact);
+
+    let mut res = json_response(
+        StatusCode::OK,
+        json!({ "version": version, "hosts": hosts }),
+    );
+    res.headers_mut()
+        .insert(ETAG, format!("\"{version}\"").parse().unwrap());
+    res.headers_mut()
+        .insert(CACHE_CONTROL, "no-store".parse().unwrap());
+    res
+}
+
+async fn edit(
+    config: &Admin,
+    editor: &Editor,
+    change: Change,
+    expected: Option<String>,
+) -> Response<ProxyBody> {
+    match config_edit::apply(change, expected.as_deref(), editor, config).await {
+        Ok((version, warnings)) => json_response(
+            StatusCode::OK,
+            json!({ "version": version, "warnings": warnings }),
+        ),
+        Err(e) => edit_error(e),
+    }
+}
+
+fn edit_error(e: EditError) -> Response<ProxyBody> {
+    match e {
+        EditError::Conflict => error(
+            StatusCode::CONFLICT,
+            "the config changed since it was read, load it again",
+        ),
+        EditError::NotFound => error(StatusCode::NOT_FOUND, "not found"),
+        EditError::Invalid(errors) => json_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            json!({ "error": "invalid config", "errors": errors }),
+        ),
+        EditError::Failed(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e),
+    }
+}
+
+/// Keep a copy of the config file once it has been reloaded, in case it was
+/// edited by hand.
+fn keep_snapshot(config: &Admin) {
+    let kept = fs::read(CONFIG)
+        .and_then(|data| config_history::record(&config.history, config.history_keep, &data));
+    if let Err(e) = kept {
+        tracing::error!(
+            "Could not keep a copy of {CONFIG} in {}: {e}",
+            config.history
+        );
+    }
+}
+
+fn history(config: &Admin) -> Response<ProxyBody> {
+    let snapshots = match config_history::list(&config.history) {
+        Ok(snapshots) => snapshots,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    let current = fs::read(CONFIG)
+        .ok()
+        .map(|data| config_edit::version(&data));
+    // An older copy of the same version is only where it was rolled back to
+    let newest = snapshots
+        .iter()
+        .position(|snapshot| Some(&snapshot.version) == current.as_ref());
+
+    let versions = snapshots
+        .iter()
+        .enumerate()
+        .map(|(i, snapshot)| {
+            json!({
+                "id": snapshot.id,
+                "version": snapshot.version,
+                "current": Some(i) == newest,
+            })
+        })
+        .collect::<Vec<_>>();
+    json_response(StatusCode::OK, json!({ "versions": versions }))
+}
+
+/// A version of the config file with its secrets redacted, "current" for the
+/// file at `current` as it is now.
+fn redacted_version(
+    config: &Admin,
+    current: &str,
+    id: &str,
+) -> Result<Value, (StatusCode, String)> {
+    let data = match id {
+        "current" => fs::read(current).map(Some),
+        id => config_history::read(&config.history, id),
+    };
+    let data = match data {
+        Ok(Some(data)) => data,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "no such version".into())),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+
+    let mut value = serde_json::from_slice(&data).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("{id} is not JSON: {e}"),
+        )
+    })?;
+    config_edit::redact_config(&mut value);
+    Ok(value)
+}
+
+fn snapshot(config: &Admin, id: &str) -> Response<ProxyBody> {
+    match redacted_version(config, CONFIG, id) {
+        Ok(value) => json_response(StatusCode::OK, json!({ "id": id, "config": value })),
+        Err((status, message)) => error(status, &message),
+    }
+}
+
+/// The changes from version `from` to version `to`, which is the current file
+/// unless given.
+fn diff(config: &Admin, query: Option<&str>) -> Response<ProxyBody> {
+    let mut from = None;
+    let mut to = "current".to_owned();
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match &*key {
+            "from" => from = Some(value.into_owned()),
+            "to" => to = value.into_owned(),
+            _ => {}
+        }
+    }
+    let Some(from) = from else {
+        return error(StatusCode::BAD_REQUEST, "say which version to diff from");
+    };
+
+    // Both are pretty printed the same way, so only real changes show
+    let text = |id: &str| {
+        redacted_version(config, CONFIG, id)
+            .map(|value| serde_json::to_string_pretty(&value).unwrap_or_default() + "\n")
+    };
+    let (old, new) = match (text(&from), text(&to)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err((status, message)), _) | (_, Err((status, message))) => {
+            return error(status, &message)
+        }
+    };
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/x-diff; charset=utf-8")
+        .body(full(config_history::diff(&old, &new, &from, &to)))
+        .unwrap()
+}
+
+async fn rollback(
+    config: &Admin,
+    editor: &Editor,
+    id: &str,
+    expected: Option<String>,
+) -> Response<ProxyBody> {
+    match config_edit::rollback(id, expected.as_deref(), editor, config).await {
+        Ok(version) => json_response(StatusCode::OK, json!({ "version": version })),
+        Err(e) => edit_error(e),
+    }
+}
+
+/// Drop kept responses, for one host or all of them, under a path prefix.
+fn purge_cache(config: &Admin, editor: &Editor, body: &Value) -> Response<ProxyBody> {
+    let host = body.get("host").and_then(Value::as_str);
+    let prefix = body.get("prefix").and_then(Value::as_str).unwrap_or("/");
+
+    let purged = cache::purge(host, prefix);
+    let target = format!("{}{prefix}", host.unwrap_or("*"));
+    config_edit::audit(
+        &config.audit_log,
+        editor,
+        "purge_cache",
+        &target,
+        None,
+        None,
+    );
+    json_response(StatusCode::OK, json!({ "purged": purged }))
+}
+
+/// Upstream addresses from the config, with the hosts proxying to them.
+fn configured_upstreams(config: &Config) -> BTreeMap<String, Vec<String>> {
+    let mut upstreams = BTreeMap::<String, Vec<String>>::new();
+    let mut add = |action: &Action, host: &str| {
+        if let Action::Destination(destination) = action {
+            if let Some(address) = destination
+                .parse()
+                .ok()
+                .as_ref()
+                .and_then(upstream::address)
+            {
+                let hosts = upstreams.entry(address).or_default();
+                if !hosts.iter().any(|h| h == host) {
+                    hosts.push(host.to_owned());
+                }
+            }
+        }
+    };
+
+    for host in config.hosts.values() {
+        add(&host.action, &host.host);
+        for action in host.routes.iter().filter_map(|r| r.action.as_ref()) {
+            add(action, &host.host);
+        }
+    }
+    if let Some(UnknownHost::Action(action)) = &config.global.unknown_host {
+        add(action, "*");
+    }
+    upstreams
+}
+
+fn upstreams() -> Value {
+    let mut upstreams = configured_upstreams(&HOSTS.load());
+    for address in upstream::addresses() {
+        upstreams.entry(address).or_default();
+    }
+
+    let upstreams = upstreams
+        .into_iter()
+        .map(|(address, hosts)| {
+            let status = upstream::status(&address);
+            let health = &status.health;
+            json!({
+                "address": address,
+                "hosts": hosts,
+                "mode": status.mode.to_string(),
+                "health": match status.healthy {
+                    Some(true) => "healthy",
+                    Some(false) => "unhealthy",
+                    None => "unknown",
+                },
+                "consecutive_failures": health.consecutive_failures,
+                "last_success": health.last_success.map(rfc3339),
+                "last_failure": health.last_failure.map(rfc3339),
+                "last_error": health.last_error,
+                "requests": status.requests,
+                "failures": status.failures,
+                "in_flight": status.in_flight,
+                "pool": {
+                    "open": status.connections,
+                    "idle": status.connections.saturating_sub(status.in_flight),
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({ "upstreams": upstreams })
+}
+
+fn set_mode(config: &Admin, editor: &Editor, address: &str, action: &str) -> Response<ProxyBody> {
+    let mode = match action {
+        "drain" => Mode::Draining,
+        "disable" => Mode::Disabled,
+        "enable" => Mode::Active,
+        _ => return error(StatusCode::NOT_FOUND, "no such endpoint"),
+    };
+
+    let known = configured_upstreams(&HOSTS.load()).contains_key(address)
+        || upstream::addresses().iter().any(|a| a == address);
+    if !known {
+        return error(StatusCode::NOT_FOUND, "no such upstream");
+    }
+
+    let before = upstream::status(address).mode;
+    upstream::set_mode(address, mode);
+    config_edit::audit(
+        &config.audit_log,
+        editor,
+        &format!("{action}_upstream"),
+        address,
+        Some(json!({ "mode": before.to_string() })),
+        Some(json!({ "mode": mode.to_string() })),
+    );
+    let status = upstream::status(address);
+    json_response(
+        StatusCode::OK,
+        json!({
+            "address": address,
+            "mode": status.mode.to_string(),
+            "in_flight": status.in_flight,
+        }),
+    )
+}
+
+fn connections() -> Value {
+    let (open, in_flight) = metrics::active();
+
+    let mut by_ip = rate_limit::connections();
+    by_ip.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let clients = by_ip
+        .into_iter()
+        .map(|(ip, open)| json!({ "ip": ip, "open": open }))
+        .collect::<Vec<_>>();
+
+    let upstreams = upstream::addresses()
+        .into_iter()
+        .map(|address| (address.clone(), upstream::status(&address).connections))
+        .collect::<BTreeMap<_, _>>();
+
+    json!({
+        "open": open,
+        "in_flight": in_flight,
+        "clients": clients,
+        "upstreams": upstreams,
+    })
+}
+
+fn certificates() -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let time = |secs: i64| rfc3339(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64));
+
+    let certificates = tls::certificates()
+        .iter()
+        .map(|cert| {
+            json!({
+                "usage": cert.usage,
+                "host": cert.host,
+                "path": cert.path,
+                "subject": cert.subject,
+                "issuer": cert.issuer,
+                "not_before": time(cert.not_before),
+                "not_after": time(cert.not_after),
+                "expires_in_days": (cert.not_after - now).div_euclid(86400),
+                "fingerprint": cert.fingerprint,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({ "certificates": certificates })
+}
+
+fn json_response(status: StatusCode, value: Value) -> Response<ProxyBody> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(value.to_string()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<ProxyBody> {
+    json_response(status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut req = Request::builder().method(Method::POST);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    async fn caller_of(config: &Admin, auth: Option<&str>) -> Option<String> {
+        let headers = auth
+            .map(|auth| ("authorization", auth))
+            .into_iter()
+            .collect::<Vec<_>>();
+        caller(config, &request(&headers)).await
+    }
+
+    #[tokio::test]
+    async fn token_required() {
+        let config = Admin {
+            listen: "127.0.0.1:9090".parse().unwrap(),
+            token: Some("letmein".into()),
+            htpasswd: None,
+            audit_log: "audit.log".into(),
+            history: "history".into(),
+            history_keep: 50,
+        };
+
+        assert_eq!(
+            caller_of(&config, Some("Bearer letmein")).await.as_deref(),
+            Some("token")
+        );
+        assert_eq!(caller_of(&config, Some("Bearer letme")).await, None);
+        assert_eq!(caller_of(&config, Some("letmein")).await, None);
+        assert_eq!(caller_of(&config, None).await, None);
+
+        let open = Admin {
+            token: None,
+            ..config.clone()
+        };
+        assert_eq!(caller_of(&open, None).await.as_deref(), Some("anonymous"));
+
+        // Users to sign in as, but no token
+        let users = Admin {
+            token: None,
+            htpasswd: Some("res/test/missing.htpasswd".into()),
+            ..config
+        };
+        assert_eq!(caller_of(&users, Some("Bearer letmein")).await, None);
+    }
+
+    #[test]
+    fn versions_redacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = dir.path().join("Hosts.json");
+        let file = json!({
+            "admin": { "listen": "127.0.0.1:9090", "token": "letmein" },
+            "telemetry": {
+                "endpoint": "http://127.0.0.1:4318/v1/traces",
+                "headers": { "x-api-key": "k3y" }
+            },
+            "hosts": [{
+                "host": "a.test",
+                "destination": "http://127.0.0.1:8096",
+                "auth": { "jwt": { "secret": "s3cret" } },
+                "headers": { "request": { "set": { "x-real-ip": "{{client_ip}}" } } }
+            }]
+        });
+        fs::write(&current, file.to_string()).unwrap();
+
+        let config = Admin {
+            listen: "127.0.0.1:9090".parse().unwrap(),
+            token: Some("letmein".into()),
+            htpasswd: None,
+            audit_log: "audit.log".into(),
+            history: dir.path().join("history").to_str().unwrap().into(),
+            history_keep: 50,
+        };
+        let shown = redacted_version(&config, current.to_str().unwrap(), "current").unwrap();
+        let text = shown.to_string();
+        for secret in ["letmein", "k3y", "s3cret"] {
+            assert!(!text.contains(secret), "{secret} in {text}");
+        }
+        assert_eq!(
+            shown["hosts"][0]["headers"]["request"]["set"]["x-real-ip"],
+            "{{client_ip}}"
+        );
+    }
+
+    #[test]
+    fn cross_origin_refused() {
+        let host = ("host", "127.0.0.1:9100");
+        assert!(!cross_origin(&request(&[host])));
+        assert!(!cross_origin(&request(&[
+            host,
+            ("origin", "http://127.0.0.1:9100")
+        ])));
+        assert!(cross_origin(&request(&[
+            host,
+            ("origin", "https://evil.test")
+        ])));
+        assert!(cross_origin(&request(&[host, ("origin", "null")])));
+    }
+
+    /// Send `method path` to the API, returning the status, headers and body.
+    async fn call(
+        config: &Admin,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> (StatusCode, hyper::HeaderMap, String) {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(HOST, "127.0.0.1:9090");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let req = testing::incoming(req.body(full("{}")).unwrap()).await;
+
+        let client = "127.0.0.1:50000".parse().unwrap();
+        let res = handle(Arc::new(config.clone()), client, req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8_lossy(&body).into_owned(),
+        )
+    }
+
+    fn admin(dir: &std::path::Path, token: Option<&str>) -> Admin {
+        Admin {
+            listen: "127.0.0.1:9090".parse().unwrap(),
+            token: token.map(Into::into),
+            htpasswd: None,
+            audit_log: dir.join("audit.log").to_str().unwrap().into(),
+            history: dir.join("history").to_str().unwrap().into(),
+            history_keep: 50,
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_need_someone_to_sign_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = admin(dir.path(), None);
+
+        let (status, _, _) = call(&open, Method::GET, "/api/cache", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        for path in [
+            "/api/reload",
+            "/api/upstreams/127.0.0.1:8096/disable",
+            "/api/cache/purge",
+            "/api/config/hosts",
+        ] {
+            let (status, _, _) = call(&open, Method::POST, path, &[]).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{path}");
+        }
+        assert!(!dir.path().join("audit.log").exists());
+    }
+
+    #[tokio::test]
+    async fn endpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = admin(dir.path(), Some("letmein"));
+        let token = ("authorization", "Bearer letmein");
+
+        let (status, headers, _) = call(&config, Method::POST, "/api/cache/purge", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(headers[WWW_AUTHENTICATE], "Bearer");
+
+        let evil = ("origin", "https://evil.test");
+        let (status, _, _) = call(&config, Method::POST, "/api/cache/purge", &[token, evil]).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _, body) = call(&config, Method::POST, "/api/cache/purge", &[token]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"purged":0}"#);
+        let audit = fs::read_to_string(dir.path().join("audit.log")).unwrap();
+        assert!(audit.contains(r#""user":"token""#) && audit.contains("purge_cache"));
+
+        let path = "/api/upstreams/unknown.test:80/disable";
+        let (status, _, _) = call(&config, Method::POST, path, &[token]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _, _) = call(&config, Method::GET, "/api/nothing", &[token]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Not behind the token
+        let (status, _, _) = call(&config, Method::GET, "/metrics", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
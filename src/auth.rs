@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use dashmap::DashMap;
+use hyper::header::{
+    HeaderName, HeaderValue, AUTHORIZATION, CONNECTION, CONTENT_LENGTH, HOST, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use tokio::time::Instant;
+
+use crate::config_loader::{Auth, BasicAuth, ForwardAuth, Timeouts};
+use crate::error_pages::UpstreamError;
+use crate::proxy::{full, ProxyBody};
+use crate::{jwt, oidc, upstream};
+
+/// How often htpasswd files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long checked credentials are remembered, as bcrypt and argon2 are
+/// deliberately slow and browsers send them with every request.
+const VERIFIED_FOR: Duration = Duration::from_secs(300);
+
+/// Request headers about the connection rather than the request, which are not
+/// passed on to the auth service.
+const NOT_FORWARDED: [HeaderName; 7] = [
+    CONNECTION,
+    CONTENT_LENGTH,
+    HOST,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Why a request was not let through.
+pub enum Denied {
+    /// Missing or wrong credentials, with the `WWW-Authenticate` challenge to send.
+    Unauthorized(HeaderValue),
+    /// The auth service's answer, sent back to the client as it is.
+    Response(Response<ProxyBody>),
+    /// The auth service could not be asked.
+    Failed(UpstreamError),
+    /// Answered with an error page, logging why.
+    Status(StatusCode, String),
+}
+
+struct Htpasswd {
+    /// Users and their password hashes.
+    users: HashMap<String, String>,
+    modified: Option<SystemTime>,
+}
+
+/// Loaded on first use, keyed by path.
+static HTPASSWD_FILES: Lazy<DashMap<String, Arc<Htpasswd>>> = Lazy::new(DashMap::new);
+
+/// When credentials stop being trusted without checking them again, keyed by
+/// htpasswd path and `Authorization` header.
+static VERIFIED: Lazy<DashMap<(String, String), Instant>> = Lazy::new(DashMap::new);
+
+/// Check `req` against `auth`, possibly adding headers for the upstream.
+pub async fn authenticate<B>(
+    auth: &Auth,
+    req: &mut Request<B>,
+    client: Option<IpAddr>,
+    timeouts: &Timeouts,
+) -> Result<(), Denied> {
+    match auth {
+        Auth::Basic(basic) => match basic_user(basic, req.headers()).await {
+            Some(_) => Ok(()),
+            None => Err(Denied::Unauthorized(challenge(&basic.realm))),
+        },
+        Auth::Forward(forward) => check_forward(forward, req, client, timeouts).await,
+        Auth::Oidc(oidc) => oidc::authenticate(oidc, req).await,
+        Auth::Jwt(jwt) => jwt::authenticate(jwt, req).await,
+        Auth::Off => Ok(()),
+    }
+}
+
+pub fn challenge(realm: &str) -> HeaderValue {
+    let realm = realm.replace(['"', '\\'], "");
+    HeaderValue::from_str(&format!("Basic realm=\"{realm}\", charset=\"UTF-8\""))
+        .unwrap_or_else(|_| HeaderValue::from_static("Basic charset=\"UTF-8\""))
+}
+
+/// The user whose Basic credentials are in `headers`, if they are right.
+pub async fn basic_user(config: &BasicAuth, headers: &HeaderMap) -> Option<String> {
+    let header = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok())?;
+    let (user, password) = credentials(header)?;
+
+    let key = (config.htpasswd.clone(), header.to_owned());
+    if VERIFIED
+        .get(&key)
+        .is_some_and(|until| *until > Instant::now())
+    {
+        return Some(user);
+    }
+
+    let Some(hash) = htpasswd(&config.htpasswd).await.users.get(&user).cloned() else {
+        tracing::info!("Unknown user {user:?}");
+        return None;
+    };
+
+    let verified = tokio::task::spawn_blocking(move || verify(&password, &hash))
+        .await
+        .unwrap_or(false);
+
+    if verified {
+        VERIFIED.insert(key, Instant::now() + VERIFIED_FOR);
+        Some(user)
+    } else {
+        tracing::info!("Wrong password for {user:?}");
+        None
+    }
+}
+
+/// The user and password in a Basic `Authorization` header.
+fn credentials(header: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+async fn htpasswd(path: &str) -> Arc<Htpasswd> {
+    if let Some(file) = HTPASSWD_FILES.get(path) {
+        return file.clone();
+    }
+
+    // Read off the runtime, like the password checks
+    let owned = path.to_owned();
+    let file = match tokio::task::spawn_blocking(move || load(&owned)).await {
+        Ok(file) => Arc::new(file),
+        Err(e) => {
+            tracing::error!("Could not load htpasswd file {path}: {e}");
+            return Arc::new(Htpasswd {
+                users: HashMap::new(),
+                modified: None,
+            });
+        }
+    };
+    HTPASSWD_FILES.insert(path.to_owned(), file.clone());
+    file
+}
+
+/// A file which cannot be read is treated as empty, so nobody gets in.
+fn load(path: &str) -> Htpasswd {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::error!("Could not read htpasswd file {path}: {e}");
+            return Htpasswd {
+                users: HashMap::new(),
+                modified,
+            };
+        }
+    };
+
+    let users = data
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (user, hash) = line.split_once(':')?;
+            // bcrypt is `$2a$`, `$2b$` or `$2y$`
+            if hash.starts_with("$2") || hash.starts_with("$argon2") {
+                Some((user.to_owned(), hash.to_owned()))
+            } else {
+                tracing::warn!(
+                    "Ignoring {user:?} in {path}, only bcrypt and argon2 hashes are supported"
+                );
+                None
+            }
+        })
+        .collect::<HashMap<_, _>>();
+
+    tracing::info!("Loaded {} user(s) from {path}", users.len());
+
+    Htpasswd { users, modified }
+}
+
+/// Reload htpasswd files when they change on disk and forget expired
+/// credentials, forever.
+pub async fn reload_changed() {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let now = Instant::now();
+        VERIFIED.retain(|_, until| *until > now);
+
+        _ = tokio::task::spawn_blocking(reload_files).await;
+    }
+}
+
+fn reload_files() {
+    let changed: Vec<String> = HTPASSWD_FILES
+        .iter()
+        .filter(|file| {
+            let modified = std::fs::metadata(file.key())
+                .and_then(|m| m.modified())
+                .ok();
+            modified != file.modified
+        })
+        .map(|file| file.key().clone())
+        .collect();
+
+    for path in changed {
+        // Removed users and changed passwords take effect straight away
+        VERIFIED.retain(|(htpasswd, _), _| *htpasswd != path);
+        let file = Arc::new(load(&path));
+        HTPASSWD_FILES.insert(path, file);
+    }
+}
+
+async fn check_forward<B>(
+    config: &ForwardAuth,
+    req: &mut Request<B>,
+    client: Option<IpAddr>,
+    timeouts: &Timeouts,
+) -> Result<(), Denied> {
+    let mut check = Request::builder()
+        .method(Method::GET)
+        .uri(config.url.as_str());
+
+    if let Some(headers) = check.headers_mut() {
+        for (name, value) in req.headers() {
+            if !NOT_FORWARDED.contains(name) {
+                headers.append(name, value.clone());
+            }
+        }
+
+        let mut set = |name: &'static str, value: &str| {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        };
+        set("x-forwarded-method", req.method().as_str());
+        set("x-forwarded-proto", "https");
+        if let Some(host) = req.headers().get(HOST).and_then(|h| h.to_str().ok()) {
+            set("x-forwarded-host", host);
+        }
+        if let Some(uri) = req.uri().path_and_query() {
+            set("x-forwarded-uri", uri.as_str());
+        }
+        if let Some(client) = client {
+            set("x-forwarded-for", &client.to_canonical().to_string());
+        }
+    }
+
+    let check = check.body(full("")).map_err(|e| {
+        Denied::Failed(UpstreamError::Unavailable(format!(
+            "invalid auth url {:?}: {e}",
+            config.url
+        )))
+    })?;
+
+    let res = upstream::send(check, timeouts)
+        .await
+        .map_err(Denied::Failed)?;
+
+    if !res.status().is_success() {
+        return Err(Denied::Response(res));
+    }
+
+    // Replacing anything the client sent under the same names
+    for name in &config.copy_headers {
+        let Ok(name) = HeaderName::try_from(name.as_str()) else {
+            tracing::warn!("Invalid header name {name:?} in forward auth");
+            continue;
+        };
+
+        let headers = req.headers_mut();
+        headers.remove(&name);
+        for value in res.headers().get_all(&name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::io::Write;
+
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use hyper::body::Incoming;
+    use hyper::header::LOCATION;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::StatusCode;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn basic(user: &str, password: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let encoded = BASE64.encode(format!("{user}:{password}"));
+        headers.insert(AUTHORIZATION, format!("Basic {encoded}").parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn parses_credentials() {
+        assert_eq!(
+            credentials("Basic YWxpY2U6cGE6c3M="),
+            Some(("alice".into(), "pa:ss".into()))
+        );
+        assert_eq!(credentials("Bearer YWxpY2U6cGFzcw=="), None);
+        assert_eq!(credentials("Basic not base64!"), None);
+        assert_eq!(credentials("Basic YWxpY2U="), None);
+    }
+
+    #[tokio::test]
+    async fn htpasswd_bcrypt_and_argon2() {
+        let bcrypt_hash = bcrypt::hash("hunter2", 4).unwrap();
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon2_hash = Argon2::default()
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "# users").unwrap();
+        writeln!(file, "alice:{bcrypt_hash}").unwrap();
+        writeln!(file, "bob:{argon2_hash}").unwrap();
+        writeln!(file, "carol:$apr1$salt$unsupportedmd5hash").unwrap();
+
+        let config = BasicAuth {
+            htpasswd: file.path().to_str().unwrap().to_owned(),
+            realm: "Test".into(),
+        };
+
+        assert_eq!(
+            basic_user(&config, &basic("alice", "hunter2"))
+                .await
+                .as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            basic_user(&config, &basic("bob", "correct horse"))
+                .await
+                .as_deref(),
+            Some("bob")
+        );
+        // Remembered the second time round
+        assert_eq!(
+            basic_user(&config, &basic("alice", "hunter2"))
+                .await
+                .as_deref(),
+            Some("alice")
+        );
+
+        assert!(basic_user(&config, &basic("alice", "hunter3"))
+            .await
+            .is_none());
+        assert!(basic_user(&config, &basic("bob", "hunter2"))
+            .await
+            .is_none());
+        assert!(basic_user(&config, &basic("carol", "anything"))
+            .await
+            .is_none());
+        assert!(basic_user(&config, &basic("dave", "hunter2"))
+            .await
+            .is_none());
+        assert!(basic_user(&config, &HeaderMap::new()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn missing_htpasswd_lets_nobody_in() {
+        let config = BasicAuth {
+            htpasswd: "res/test/missing.htpasswd".into(),
+            realm: "Test".into(),
+        };
+        let mut req = Request::new(());
+        *req.headers_mut() = basic("alice", "hunter2");
+
+        let denied = authenticate(&Auth::Basic(config), &mut req, None, &Timeouts::default()).await;
+        let Err(Denied::Unauthorized(challenge)) = denied else {
+            panic!("expected a challenge");
+        };
+        assert_eq!(challenge, "Basic realm=\"Test\", charset=\"UTF-8\"");
+    }
+
+    /// Stands in for an auth service: lets through requests with the right
+    /// token, sends everything else to a login page.
+    async fn auth_service() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let service = service_fn(|req: Request<Incoming>| async move {
+            let header = |name: &str| {
+                req.headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+
+            let res = if header("authorization") == "Bearer good" {
+                Response::builder()
+                    .header("remote-user", "alice")
+                    .header("remote-groups", "admins")
+                    .header("remote-groups", "users")
+                    .header(
+                        "x-seen",
+                        format!(
+                            "{} {}",
+                            header("x-forwarded-for"),
+                            header("x-forwarded-uri")
+                        ),
+                    )
+                    .body(full(""))
+            } else {
+                Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header(
+                        LOCATION,
+                        format!("https://login.test/?rd={}", header("x-forwarded-host")),
+                    )
+                    .body(full(""))
+            };
+            Ok::<_, Infallible>(res.unwrap())
+        });
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        format!("http://{addr}/verify")
+    }
+
+    fn request(authorization: &str) -> Request<()> {
+        Request::builder()
+            .uri("/downloads?page=2")
+            .header(HOST, "transmission.test")
+            .header(AUTHORIZATION, authorization)
+            .header("remote-user", "mallory")
+            .body(())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn forward_auth() {
+        let auth = Auth::Forward(ForwardAuth {
+            url: auth_service().await,
+            copy_headers: vec!["Remote-User".into(), "Remote-Groups".into()],
+        });
+        let client = Some("192.0.2.7".parse().unwrap());
+
+        let mut req = request("Bearer good");
+        assert!(authenticate(&auth, &mut req, client, &Timeouts::default())
+            .await
+            .is_ok());
+        assert_eq!(req.headers()["remote-user"], "alice");
+        assert_eq!(
+            req.headers()
+                .get_all("remote-groups")
+                .iter()
+                .collect::<Vec<_>>(),
+            ["admins", "users"]
+        );
+        assert!(req.headers().get("x-seen").is_none());
+
+        let mut req = request("Bearer bad");
+        let denied = authenticate(&auth, &mut req, client, &Timeouts::default()).await;
+        let Err(Denied::Response(res)) = denied else {
+            panic!("expected the auth service's response");
+        };
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://login.test/?rd=transmission.test"
+        );
+        assert_eq!(req.headers()["remote-user"], "mallory");
+    }
+
+    #[tokio::test]
+    async fn forward_auth_sees_the_original_request() {
+        let url = auth_service().await;
+        let auth = Auth::Forward(ForwardAuth {
+            url,
+            copy_headers: vec!["X-Seen".into()],
+        });
+
+        let mut req = request("Bearer good");
+        let client = Some("::ffff:192.0.2.7".parse().unwrap());
+        assert!(authenticate(&auth, &mut req, client, &Timeouts::default())
+            .await
+            .is_ok());
+        assert_eq!(req.headers()["x-seen"], "192.0.2.7 /downloads?page=2");
+    }
+
+    #[tokio::test]
+    async fn forward_auth_unreachable() {
+        let auth = Auth::Forward(ForwardAuth {
+            url: "http://127.0.0.1:1/verify".into(),
+            copy_headers: Vec::new(),
+        });
+
+        let mut req = request("Bearer good");
+        let denied = authenticate(&auth, &mut req, None, &Timeouts::default()).await;
+        assert!(matches!(
+            denied,
+            Err(Denied::Failed(UpstreamError::Connect(_)))
+        ));
+    }
+}
@@ -0,0 +1,110 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+
+use crate::proxy::ProxyBody;
+
+/// Request body bytes read so far, added to the extensions of a request so its
+/// body is counted on the way upstream.
+#[derive(Clone, Debug, Default)]
+pub struct BytesIn(Arc<AtomicU64>);
+
+impl BytesIn {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the request body as it is read, when `counter` is set.
+pub struct Counted<B> {
+    inner: B,
+    counter: Option<BytesIn>,
+}
+
+impl<B> Counted<B> {
+    pub fn new(inner: B, counter: Option<BytesIn>) -> Self {
+        Counted { inner, counter }
+    }
+}
+
+impl<B> Body for Counted<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let (Poll::Ready(Some(Ok(frame))), Some(BytesIn(counter))) = (&poll, &self.counter) {
+            let len = frame.data_ref().map_or(0, |data| data.len() as u64);
+            counter.fetch_add(len, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Calls `done` with the number of bytes sent once the body is dropped, whether it
+/// was sent in full or the client went away.
+pub fn on_sent(body: ProxyBody, done: impl FnOnce(u64) + Send + 'static) -> ProxyBody {
+    Sent {
+        inner: body,
+        bytes_out: 0,
+        done: Some(Box::new(done)),
+    }
+    .boxed_unsync()
+}
+
+struct Sent {
+    inner: ProxyBody,
+    bytes_out: u64,
+    done: Option<Box<dyn FnOnce(u64) + Send>>,
+}
+
+impl Body for Sent {
+    type Data = Bytes;
+    type Error = <ProxyBody as Body>::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            self.bytes_out += frame.data_ref().map_or(0, |data| data.len() as u64);
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Sent {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            done(self.bytes_out);
+        }
+    }
+}
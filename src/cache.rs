@@ -0,0 +1,1576 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING,
+    CONTENT_LENGTH, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, PRAGMA,
+    RANGE, SET_COOKIE, VARY,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use crate::config_loader::{Cache, Timeouts};
+use crate::error_pages::UpstreamError;
+use crate::metrics::{self, CacheResult};
+use crate::proxy::{full, ProxyBody};
+use crate::{upstream, HOSTS};
+
+/// Statuses which may be kept, those RFC 9111 lets a cache work out a freshness
+/// for when the upstream gives none.
+const STORABLE: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Longest a response is guessed to stay fresh from its `Last-Modified` alone.
+const MAX_HEURISTIC: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Meaningful for one connection only, so never kept.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Tells clients how their response was answered.
+const X_CACHE: &str = "x-cache";
+
+static STORE: Lazy<Store> = Lazy::new(|| Store::open(&HOSTS.load().global.cache));
+
+/// Read what is kept on disk, rather than when the first request comes in.
+pub fn init() {
+    Lazy::force(&STORE);
+}
+
+/// Answer `req` from the cache when it can be, otherwise forward it upstream and
+/// keep the response if the upstream allows it. `host` is the configured host,
+/// and `authenticated` whether Envoi checks who the client is for it, in which
+/// case responses are only kept as for requests with `Authorization`.
+pub async fn forward(
+    host: &str,
+    authenticated: bool,
+    req: Request<Incoming>,
+    timeouts: &Timeouts,
+) -> Result<Response<ProxyBody>, UpstreamError> {
+    STORE.forward(host, authenticated, req, timeouts).await
+}
+
+impl Store {
+    async fn forward(
+        &'static self,
+        host: &str,
+        authenticated: bool,
+        mut req: Request<Incoming>,
+        timeouts: &Timeouts,
+    ) -> Result<Response<ProxyBody>, UpstreamError> {
+        let asked = CacheControl::parse(req.headers());
+        let method = req.method().clone();
+        if !(method == Method::GET || method == Method::HEAD)
+            || req.headers().contains_key(RANGE)
+            || asked.no_store
+        {
+            return upstream::forward(req, timeouts).await;
+        }
+
+        let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+        let key = format!("{host}{path}");
+        let head = method == Method::HEAD;
+        let conditions = Conditions::of(req.headers());
+        let now = SystemTime::now();
+        // The response may be for this client only, even if the upstream does not
+        // say so
+        let authorized = authenticated || req.headers().contains_key(AUTHORIZATION);
+
+        let no_cache = asked.no_cache || pragma_no_cache(req.headers());
+        let fresh = |entry: &Entry, now| {
+            let age = entry.age(now);
+            !no_cache
+                && age < entry.fresh_for
+                && asked.max_age.is_none_or(|max| age.as_secs() <= max)
+        };
+
+        let Some(entry) = self.get(&key, req.headers()).await else {
+            // Nothing to keep from a HEAD request
+            if head {
+                return upstream::forward(req, timeouts).await;
+            }
+
+            // Those not taking a kept response have nothing to wait for
+            let timeout = match no_cache {
+                true => Duration::ZERO,
+                false => self.config.coalesce_timeout(),
+            };
+            let lead = match join(&key, timeout) {
+                Flight::Alone => None,
+                Flight::Lead(lead) => Some(lead),
+                Flight::Wait(mut done, timeout) => {
+                    // Ends once the response is kept, or given up on
+                    _ = tokio::time::timeout(timeout, done.changed()).await;
+                    let now = SystemTime::now();
+                    let kept = self.get(&key, req.headers()).await;
+                    if let Some(entry) = kept.filter(|entry| fresh(entry, now)) {
+                        metrics::cache(CacheResult::Coalesced);
+                        return Ok(entry.respond(&conditions, head, now, "COALESCED"));
+                    }
+                    None
+                }
+            };
+
+            let request = req.headers().clone();
+            let res = upstream::forward(req, timeouts).await?;
+            metrics::cache(CacheResult::Miss);
+            return Ok(self.keep(key, &request, res, authorized, lead));
+        };
+
+        if fresh(&entry, now) {
+            metrics::cache(CacheResult::Hit);
+            return Ok(entry.respond(&conditions, head, now, "HIT"));
+        }
+        if head {
+            return upstream::forward(req, timeouts).await;
+        }
+
+        // Only ask whether what is kept is still current, the client's own
+        // conditions are checked against it afterwards
+        conditions.replace(req.headers_mut(), &entry);
+        let request = req.headers().clone();
+        match upstream::forward(req, timeouts).await {
+            Ok(res) if res.status() == StatusCode::NOT_MODIFIED => {
+                let entry = self.refresh(&entry, res.headers(), authorized);
+                metrics::cache(CacheResult::Revalidated);
+                Ok(entry.respond(&conditions, head, SystemTime::now(), "REVALIDATED"))
+            }
+            Ok(res) => {
+                metrics::cache(CacheResult::Miss);
+                Ok(self.keep(key, &request, res, authorized, None))
+            }
+            Err(err) if !entry.must_revalidate => {
+                tracing::warn!(cause = %err, "Upstream failed, answering with stale {key}");
+                metrics::cache(CacheResult::Stale);
+                Ok(entry.respond(&conditions, head, now, "STALE"))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+enum Flight {
+    /// Coalescing is off.
+    Alone,
+    /// The first request for a key, which the others wait for.
+    Lead(Lead),
+    /// Wait for the lead request, but no longer than the timeout.
+    Wait(watch::Receiver<()>, Duration),
+}
+
+/// Held by the request fetching a key for those waiting on it, who are let go
+/// once it is dropped.
+struct Lead {
+    key: String,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for Lead {
+    fn drop(&mut self) {
+        FLIGHTS.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Keys being fetched, so that requests for the same one at the same time are
+/// sent upstream once.
+static FLIGHTS: Lazy<Mutex<HashMap<String, watch::Receiver<()>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn join(key: &str, timeout: Duration) -> Flight {
+    if timeout.is_zero() {
+        return Flight::Alone;
+    }
+
+    let mut flights = FLIGHTS.lock().unwrap();
+    match flights.get(key) {
+        Some(done) => Flight::Wait(done.clone(), timeout),
+        None => {
+            let (done, waiting) = watch::channel(());
+            flights.insert(key.to_owned(), waiting);
+            Flight::Lead(Lead {
+                key: key.to_owned(),
+                _done: done,
+            })
+        }
+    }
+}
+
+/// Drop the kept responses for `host`, or every host, whose path starts with
+/// `prefix`. Returns how many were dropped.
+pub fn purge(host: Option<&str>, prefix: &str) -> usize {
+    STORE.purge(host, prefix)
+}
+
+pub struct Stats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub disk_entries: usize,
+    pub disk_bytes: u64,
+}
+
+pub fn stats() -> Stats {
+    STORE.stats()
+}
+
+/// A response as it was kept, one per set of values of the request headers it
+/// varies on.
+#[derive(Debug)]
+struct Entry {
+    key: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    /// When it was stored or last revalidated.
+    stored: SystemTime,
+    /// How old it already was then.
+    age: Duration,
+    fresh_for: Duration,
+    must_revalidate: bool,
+}
+
+impl Entry {
+    fn size(&self) -> u64 {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+        (self.key.len() + headers + self.body.len()) as u64
+    }
+
+    fn age(&self, now: SystemTime) -> Duration {
+        self.age + now.duration_since(self.stored).unwrap_or_default()
+    }
+
+    /// Whether it is the variant for a request with these headers.
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+
+    /// Name of its file on disk.
+    fn file(&self) -> String {
+        let mut hash = Sha256::new();
+        hash.update(&self.key);
+        for (name, value) in &self.vary {
+            hash.update([0]);
+            hash.update(name);
+            hash.update(value.as_ref().map_or(&b""[..], |v| v.as_bytes()));
+        }
+        let hash: String = hash.finalize()[..16]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        hash + EXTENSION
+    }
+
+    fn respond(
+        &self,
+        conditions: &Conditions,
+        head: bool,
+        now: SystemTime,
+        result: &'static str,
+    ) -> Response<ProxyBody> {
+        let mut headers = self.headers.clone();
+        headers.insert(AGE, HeaderValue::from(self.age(now).as_secs()));
+        headers.insert(X_CACHE, HeaderValue::from_static(result));
+
+        let (status, body) = if conditions.not_modified(&self.headers) {
+            headers.remove(CONTENT_LENGTH);
+            (StatusCode::NOT_MODIFIED, Bytes::new())
+        } else {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+            let body = if head {
+                Bytes::new()
+            } else {
+                self.body.clone()
+            };
+            (self.status, body)
+        };
+
+        let mut res = Response::new(full(body));
+        *res.status_mut() = status;
+        *res.headers_mut() = headers;
+        res
+    }
+}
+
+/// How long a response may be used for, worked out when it is stored.
+#[derive(Debug, PartialEq)]
+struct Policy {
+    fresh_for: Duration,
+    age: Duration,
+    must_revalidate: bool,
+}
+
+/// `None` if the response may not be kept. `authorized` is whether the request
+/// had credentials, or the host checks who the client is.
+fn policy(
+    status: StatusCode,
+    headers: &HeaderMap,
+    authorized: bool,
+    now: SystemTime,
+) -> Option<Policy> {
+    let cc = CacheControl::parse(headers);
+    if !STORABLE.contains(&status.as_u16())
+        || cc.no_store
+        || cc.private
+        || headers.contains_key(SET_COOKIE)
+        || vary(headers).is_none()
+    {
+        return None;
+    }
+    // What one user's credentials got is only shared if the upstream says so
+    if authorized && !(cc.public || cc.s_maxage.is_some() || cc.must_revalidate) {
+        return None;
+    }
+
+    let date = http_date(headers, &DATE).unwrap_or(now);
+    let explicit = cc
+        .s_maxage
+        .or(cc.max_age)
+        .map(Duration::from_secs)
+        .or_else(|| {
+            // One which cannot be read has already expired
+            headers.get(EXPIRES).map(|_| {
+                http_date(headers, &EXPIRES)
+                    .and_then(|expires| expires.duration_since(date).ok())
+                    .unwrap_or_default()
+            })
+        });
+    // A tenth of the time since it last changed, as browsers do
+    let heuristic = || {
+        let since = date
+            .duration_since(http_date(headers, &LAST_MODIFIED)?)
+            .ok()?;
+        Some((since / 10).min(MAX_HEURISTIC))
+    };
+
+    let fresh_for = match cc.no_cache {
+        true => Duration::ZERO,
+        false => explicit.or_else(heuristic)?,
+    };
+    let validated = headers.contains_key(ETAG) || headers.contains_key(LAST_MODIFIED);
+    if fresh_for.is_zero() && !validated {
+        return None;
+    }
+
+    let age = headers
+        .get(AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let apparent = now.duration_since(date).unwrap_or_default();
+
+    Some(Policy {
+        fresh_for,
+        age: age.max(apparent),
+        must_revalidate: cc.must_revalidate || cc.no_cache,
+    })
+}
+
+/// The request headers a response varies on, `None` if it varies on anything.
+fn vary(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for name in headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        if let Ok(name) = HeaderName::try_from(name) {
+            names.push(name);
+        }
+    }
+
+    // An upstream which compresses may forget to say so
+    let accept_encoding = HeaderName::from_static("accept-encoding");
+    if headers.contains_key(CONTENT_ENCODING) && !names.contains(&accept_encoding) {
+        names.push(accept_encoding);
+    }
+    Some(names)
+}
+
+fn http_date(headers: &HeaderMap, name: &HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+fn pragma_no_cache(headers: &HeaderMap) -> bool {
+    !headers.contains_key(CACHE_CONTROL)
+        && headers
+            .get(PRAGMA)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("no-cache"))
+}
+
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = CacheControl::default();
+        let directives = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = || value.and_then(|v| v.parse().ok());
+            // With a list of fields they apply to only those, which is not
+            // worth the trouble of keeping apart
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                "max-age" => cc.max_age = seconds(),
+                "s-maxage" => cc.s_maxage = seconds(),
+                _ => {}
+            }
+        }
+        cc
+    }
+}
+
+/// The conditions of a client's request, answered with `304 Not Modified` when
+/// what is kept meets them.
+#[derive(Debug, Default)]
+struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<SystemTime>,
+}
+
+impl Conditions {
+    fn of(headers: &HeaderMap) -> Self {
+        Conditions {
+            if_none_match: headers
+                .get(IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned),
+            if_modified_since: http_date(headers, &IF_MODIFIED_SINCE),
+        }
+    }
+
+    fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(wanted) = &self.if_none_match {
+            let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+                return false;
+            };
+            // Weak comparison, as for GET
+            let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+            return wanted
+                .split(',')
+                .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag));
+        }
+
+        match (self.if_modified_since, http_date(headers, &LAST_MODIFIED)) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Swap the client's conditions for ones about `entry`.
+    fn replace(&self, headers: &mut HeaderMap, entry: &Entry) {
+        headers.remove(IF_NONE_MATCH);
+        headers.remove(IF_MODIFIED_SINCE);
+        if let Some(etag) = entry.headers.get(ETAG) {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(modified) = entry.headers.get(LAST_MODIFIED) {
+            headers.insert(IF_MODIFIED_SINCE, modified.clone());
+        }
+    }
+}
+
+struct Store {
+    config: Cache,
+    memory: Mutex<Memory>,
+    disk: Option<Arc<Mutex<Disk>>>,
+}
+
+struct Memory {
+    /// Variants by key, least recently used first out.
+    entries: LruCache<String, Vec<Arc<Entry>>>,
+    bytes: u64,
+}
+
+struct Disk {
+    dir: PathBuf,
+    size: u64,
+    bytes: u64,
+    /// The key and size of each file, least recently used first out.
+    files: LruCache<String, (String, u64)>,
+    /// The files of the variants of each key.
+    keys: HashMap<String, Vec<String>>,
+}
+
+impl Store {
+    fn open(config: &Cache) -> Self {
+        let disk = config.disk.as_ref().and_then(|disk| {
+            match Disk::open(Path::new(&disk.path), disk.size) {
+                Ok(opened) => {
+                    tracing::info!(
+                        "Cache on disk at {} holds {} response(s)",
+                        disk.path,
+                        opened.files.len()
+                    );
+                    Some(Arc::new(Mutex::new(opened)))
+                }
+                Err(e) => {
+                    tracing::error!("Could not open the cache at {}: {e}", disk.path);
+                    None
+                }
+            }
+        });
+
+        Store {
+            config: config.clone(),
+            memory: Mutex::new(Memory {
+                entries: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            disk,
+        }
+    }
+
+    async fn get(&self, key: &str, headers: &HeaderMap) -> Option<Arc<Entry>> {
+        let kept = self
+            .memory
+            .lock()
+            .unwrap()
+            .entries
+            .get(key)
+            .and_then(|variants| {
+                variants
+                    .iter()
+                    .find(|entry| entry.matches(headers))
+                    .cloned()
+            });
+        if kept.is_some() {
+            return kept;
+        }
+
+        let disk = self.disk.as_ref()?;
+        let (dir, files) = {
+            let disk = disk.lock().unwrap();
+            (disk.dir.clone(), disk.keys.get(key)?.clone())
+        };
+        for file in files {
+            let entry = match tokio::fs::read(dir.join(&file)).await {
+                Ok(data) => Disk::parse(&data),
+                Err(e) => Err(e),
+            };
+            match entry {
+                Ok(entry) if entry.matches(headers) => {
+                    disk.lock().unwrap().files.get(&file);
+                    let entry = Arc::new(entry);
+                    self.remember(entry.clone());
+                    return Some(entry);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!("Dropping unreadable cache file {file}: {e}");
+                    disk.lock().unwrap().remove(&file);
+                }
+            }
+        }
+        None
+    }
+
+    /// Pass `res` on, keeping it once it has been sent in full if it may be kept.
+    /// `lead` is held until then.
+    fn keep(
+        &'static self,
+        key: String,
+        request: &HeaderMap,
+        mut res: Response<ProxyBody>,
+        authorized: bool,
+        lead: Option<Lead>,
+    ) -> Response<ProxyBody> {
+        res.headers_mut()
+            .insert(X_CACHE, HeaderValue::from_static("MISS"));
+
+        let now = SystemTime::now();
+        let too_big = res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .is_some_and(|len| len > self.config.max_entry);
+        let Some(policy) = policy(res.status(), res.headers(), authorized, now) else {
+            return res;
+        };
+        if too_big {
+            return res;
+        }
+
+        let vary = vary(res.headers())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|name| {
+                let value = request.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+        let mut headers = res.headers().clone();
+        for name in HOP_BY_HOP.iter().chain(&[AGE.as_str(), X_CACHE]) {
+            headers.remove(*name);
+        }
+
+        let entry = Entry {
+            key,
+            status: res.status(),
+            headers,
+            body: Bytes::new(),
+            vary,
+            stored: now,
+            age: policy.age,
+            fresh_for: policy.fresh_for,
+            must_revalidate: policy.must_revalidate,
+        };
+        let limit = self.config.max_entry;
+        res.map(|body| {
+            Tee {
+                store: self,
+                inner: body,
+                buffer: BytesMut::new(),
+                limit,
+                entry: Some(entry),
+                lead,
+            }
+            .boxed_unsync()
+        })
+    }
+
+    /// Keep `entry` in memory and, if there is one, on disk.
+    fn insert(&self, entry: Entry) {
+        let entry = Arc::new(entry);
+        self.remember(entry.clone());
+
+        if let Some(disk) = &self.disk {
+            let disk = disk.clone();
+            tokio::task::spawn_blocking(move || Disk::save(&disk, &entry));
+        }
+    }
+
+    fn remember(&self, entry: Arc<Entry>) {
+        let mut memory = self.memory.lock().unwrap();
+        let Memory { entries, bytes } = &mut *memory;
+
+        let variants = entries.get_or_insert_mut(entry.key.clone(), Vec::new);
+        variants.retain(|kept| {
+            let replaced = kept.vary == entry.vary;
+            if replaced {
+                *bytes -= kept.size();
+            }
+            !replaced
+        });
+        *bytes += entry.size();
+        variants.push(entry);
+
+        while *bytes > self.config.memory {
+            let Some((_, dropped)) = entries.pop_lru() else {
+                break;
+            };
+            *bytes -= dropped.iter().map(|entry| entry.size()).sum::<u64>();
+        }
+    }
+
+    /// Update `entry` with the headers of a `304 Not Modified` for it.
+    fn refresh(&self, entry: &Entry, headers: &HeaderMap, authorized: bool) -> Arc<Entry> {
+        let now = SystemTime::now();
+        let mut merged = entry.headers.clone();
+        for name in headers.keys() {
+            if name == CONTENT_LENGTH || HOP_BY_HOP.contains(&name.as_str()) {
+                continue;
+            }
+            merged.remove(name);
+            for value in headers.get_all(name) {
+                merged.append(name, value.clone());
+            }
+        }
+
+        let policy = policy(entry.status, &merged, authorized, now);
+        let refreshed = Arc::new(Entry {
+            key: entry.key.clone(),
+            status: entry.status,
+            headers: merged,
+            body: entry.body.clone(),
+            vary: entry.vary.clone(),
+            stored: now,
+            age: policy.as_ref().map(|p| p.age).unwrap_or_default(),
+            fresh_for: policy.as_ref().map(|p| p.fresh_for).unwrap_or_default(),
+            must_revalidate: policy.as_ref().is_none_or(|p| p.must_revalidate),
+        });
+
+        match policy {
+            Some(_) => {
+                self.remember(refreshed.clone());
+                if let Some(disk) = &self.disk {
+                    let (disk, entry) = (disk.clone(), refreshed.clone());
+                    tokio::task::spawn_blocking(move || Disk::save(&disk, &entry));
+                }
+            }
+            // The upstream no longer lets it be kept
+            None => {
+                self.purge_key(&entry.key);
+            }
+        }
+        refreshed
+    }
+
+    fn purge(&self, host: Option<&str>, prefix: &str) -> usize {
+        let wanted = |key: &str| {
+            let (key_host, path) = key.split_at(key.find('/').unwrap_or(key.len()));
+            host.is_none_or(|host| host.eq_ignore_ascii_case(key_host)) && path.starts_with(prefix)
+        };
+
+        let mut keys = {
+            let memory = self.memory.lock().unwrap();
+            memory
+                .entries
+                .iter()
+                .map(|(key, _)| key.clone())
+                .filter(|key| wanted(key))
+                .collect::<Vec<_>>()
+        };
+        if let Some(disk) = &self.disk {
+            let disk = disk.lock().unwrap();
+            keys.extend(disk.keys.keys().filter(|key| wanted(key)).cloned());
+        }
+        keys.sort();
+        keys.dedup();
+
+        keys.iter().map(|key| self.purge_key(key)).sum()
+    }
+
+    /// Drop every variant of `key`, returning how many there were.
+    fn purge_key(&self, key: &str) -> usize {
+        let mut purged = 0;
+        {
+            let mut memory = self.memory.lock().unwrap();
+            if let Some(variants) = memory.entries.pop(key) {
+                memory.bytes -= variants.iter().map(|entry| entry.size()).sum::<u64>();
+                purged = variants.len();
+            }
+        }
+        if let Some(disk) = &self.disk {
+            let mut disk = disk.lock().unwrap();
+            let files = disk.keys.get(key).cloned().unwrap_or_default();
+            purged = purged.max(files.len());
+            for file in files {
+                disk.remove(&file);
+            }
+        }
+        purged
+    }
+
+    fn stats(&self) -> Stats {
+        let (entries, bytes) = {
+            let memory = self.memory.lock().unwrap();
+            let entries = memory.entries.iter().map(|(_, v)| v.len()).sum();
+            (entries, memory.bytes)
+        };
+        let (disk_entries, disk_bytes) = self.disk.as_ref().map_or((0, 0), |disk| {
+            let disk = disk.lock().unwrap();
+            (disk.files.len(), disk.bytes)
+        });
+        Stats {
+            entries,
+            bytes,
+            disk_entries,
+            disk_bytes,
+        }
+    }
+}
+
+/// Of every cache file, so nothing else in the directory is ever touched.
+const EXTENSION: &str = ".cache";
+
+/// Whether `name` is as `Entry::file` names them, followed by `suffix`.
+fn cache_file(name: &str, suffix: &str) -> bool {
+    name.strip_suffix(suffix)
+        .and_then(|name| name.strip_suffix(EXTENSION))
+        .is_some_and(|hash| hash.len() == 32 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// When `path` was last written, its size and its first line.
+fn head(path: &Path) -> io::Result<(SystemTime, u64, String)> {
+    let file = fs::File::open(path)?;
+    let metadata = file.metadata()?;
+    let mut line = String::new();
+    BufReader::new(file).read_line(&mut line)?;
+    Ok((metadata.modified()?, metadata.len(), line))
+}
+
+/// How an entry is written to disk, before its body.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    key: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    vary: Vec<(String, Option<String>)>,
+    /// Seconds since the Unix epoch.
+    stored: u64,
+    age: u64,
+    fresh_for: u64,
+    must_revalidate: bool,
+}
+
+impl Disk {
+    fn open(dir: &Path, size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut found = Vec::new();
+        for file in fs::read_dir(dir)? {
+            let file = file?;
+            if !file.file_type().is_ok_and(|t| t.is_file()) {
+                continue;
+            }
+            // Only our own files, in case `path` is a directory with others in it
+            let name = file.file_name().to_string_lossy().into_owned();
+            if cache_file(&name, ".tmp") {
+                // Left behind by a write that never finished
+                _ = fs::remove_file(file.path());
+                continue;
+            }
+            if !cache_file(&name, "") {
+                continue;
+            }
+
+            let (modified, size, line) = match head(&file.path()) {
+                Ok(head) => head,
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable cache file {name}: {e}");
+                    continue;
+                }
+            };
+            match serde_json::from_str::<Meta>(&line) {
+                Ok(meta) => found.push((modified, name, meta.key, size)),
+                Err(_) => _ = fs::remove_file(file.path()),
+            }
+        }
+
+        // Oldest first, so they are the first to go
+        found.sort();
+        let mut disk = Disk {
+            dir: dir.to_owned(),
+            size,
+            bytes: 0,
+            files: LruCache::unbounded(),
+            keys: HashMap::new(),
+        };
+        for (_, file, key, size) in found {
+            disk.add(file, key, size);
+        }
+        disk.evict();
+        Ok(disk)
+    }
+
+    fn add(&mut self, file: String, key: String, size: u64) {
+        if let Some((_, (_, old))) = self.files.push(file.clone(), (key.clone(), size)) {
+            self.bytes -= old;
+        }
+        self.bytes += size;
+        let files = self.keys.entry(key).or_default();
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+
+    fn remove(&mut self, file: &str) {
+        if let Some((key, size)) = self.files.pop(file) {
+            self.bytes -= size;
+            if let Some(files) = self.keys.get_mut(&key) {
+                files.retain(|f| f != file);
+                if files.is_empty() {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+        if let Err(e) = fs::remove_file(self.dir.join(file)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                tracing::warn!("Could not remove cache file {file}: {e}");
+            }
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.bytes > self.size {
+            let Some((file, _)) = self.files.peek_lru().map(|(f, v)| (f.clone(), v.clone())) else {
+                break;
+            };
+            self.remove(&file);
+        }
+    }
+
+    /// Write `entry` to a file, outside the lock, then add it to the index.
+    fn save(disk: &Mutex<Disk>, entry: &Entry) {
+        let Some(data) = Disk::serialize(entry) else {
+            return;
+        };
+        let file = entry.file();
+        let dir = disk.lock().unwrap().dir.clone();
+
+        let tmp = dir.join(format!("{file}.tmp"));
+        let written = fs::File::create(&tmp)
+            .and_then(|mut f| f.write_all(&data))
+            .and_then(|_| fs::rename(&tmp, dir.join(&file)));
+        if let Err(e) = written {
+            tracing::warn!("Could not write cache file {file}: {e}");
+            _ = fs::remove_file(&tmp);
+            return;
+        }
+
+        let mut disk = disk.lock().unwrap();
+        disk.add(file, entry.key.clone(), data.len() as u64);
+        disk.evict();
+    }
+
+    /// `None` if a header cannot be written as text.
+    fn serialize(entry: &Entry) -> Option<Vec<u8>> {
+        let text = |value: &HeaderValue| value.to_str().ok().map(str::to_owned);
+        let meta = Meta {
+            key: entry.key.clone(),
+            status: entry.status.as_u16(),
+            headers: entry
+                .headers
+                .iter()
+                .map(|(name, value)| Some((name.to_string(), text(value)?)))
+                .collect::<Option<_>>()?,
+            vary: entry
+                .vary
+                .iter()
+                .map(|(name, value)| match value {
+                    Some(value) => Some((name.to_string(), Some(text(value)?))),
+                    None => Some((name.to_string(), None)),
+                })
+                .collect::<Option<_>>()?,
+            stored: entry
+                .stored
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            age: entry.age.as_secs(),
+            fresh_for: entry.fresh_for.as_secs(),
+            must_revalidate: entry.must_revalidate,
+        };
+
+        let mut data = serde_json::to_vec(&meta).ok()?;
+        data.push(b'\n');
+        data.extend_from_slice(&entry.body);
+        Some(data)
+    }
+
+    fn parse(data: &[u8]) -> io::Result<Entry> {
+        let invalid =
+            |e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let end = data
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| invalid(&"no header line"))?;
+        let meta: Meta = serde_json::from_slice(&data[..end]).map_err(|e| invalid(&e))?;
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in meta.headers {
+            let name = HeaderName::try_from(name).map_err(|e| invalid(&e))?;
+            let value = HeaderValue::try_from(value).map_err(|e| invalid(&e))?;
+            headers.append(name, value);
+        }
+        let mut vary = Vec::new();
+        for (name, value) in meta.vary {
+            let name = HeaderName::try_from(name).map_err(|e| invalid(&e))?;
+            let value = value
+                .map(HeaderValue::try_from)
+                .transpose()
+                .map_err(|e| invalid(&e))?;
+            vary.push((name, value));
+        }
+
+        Ok(Entry {
+            key: meta.key,
+            status: StatusCode::from_u16(meta.status).map_err(|e| invalid(&e))?,
+            headers,
+            body: Bytes::copy_from_slice(&data[end + 1..]),
+            vary,
+            stored: UNIX_EPOCH + Duration::from_secs(meta.stored),
+            age: Duration::from_secs(meta.age),
+            fresh_for: Duration::from_secs(meta.fresh_for),
+            must_revalidate: meta.must_revalidate,
+        })
+    }
+}
+
+/// Passes a response body on while keeping a copy, which is stored once the
+/// body has been read in full. Bodies over `limit` are only passed on.
+struct Tee {
+    store: &'static Store,
+    inner: ProxyBody,
+    buffer: BytesMut,
+    limit: u64,
+    /// Taken once it is stored or given up on.
+    entry: Option<Entry>,
+    /// Let go at the same time.
+    lead: Option<Lead>,
+}
+
+impl Tee {
+    fn finish(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.body = std::mem::take(&mut self.buffer).freeze();
+            self.store.insert(entry);
+        }
+        self.lead = None;
+    }
+
+    fn give_up(&mut self) {
+        self.entry = None;
+        self.lead = None;
+        self.buffer = BytesMut::new();
+    }
+}
+
+impl Body for Tee {
+    type Data = Bytes;
+    type Error = <ProxyBody as Body>::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => match frame.data_ref() {
+                Some(data) if self.entry.is_some() => {
+                    if (self.buffer.len() + data.len()) as u64 > self.limit {
+                        self.give_up();
+                    } else {
+                        let data = data.clone();
+                        self.buffer.extend_from_slice(&data);
+                    }
+                }
+                Some(_) => {}
+                // Trailers would be lost
+                None => self.give_up(),
+            },
+            Poll::Ready(Some(Err(_))) => self.give_up(),
+            Poll::Ready(None) => self.finish(),
+            Poll::Pending => {}
+        }
+        if self.entry.is_some() && self.inner.is_end_stream() {
+            self.finish();
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::future::join_all;
+
+    use super::*;
+    use crate::testing;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::try_from(*name).unwrap(),
+                    HeaderValue::try_from(*value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn entry(key: &str, body: &str) -> Entry {
+        Entry {
+            key: key.into(),
+            status: StatusCode::OK,
+            headers: headers(&[("etag", "\"v1\""), ("content-type", "image/jpeg")]),
+            body: Bytes::copy_from_slice(body.as_bytes()),
+            vary: Vec::new(),
+            stored: SystemTime::now(),
+            age: Duration::ZERO,
+            fresh_for: Duration::from_secs(60),
+            must_revalidate: false,
+        }
+    }
+
+    #[test]
+    fn freshness() {
+        let now = SystemTime::now();
+        let date = httpdate::fmt_http_date(now - Duration::from_secs(10));
+        let ok = StatusCode::OK;
+
+        let kept = policy(
+            ok,
+            &headers(&[
+                ("cache-control", "public, max-age=3600, s-maxage=600"),
+                ("date", &date),
+            ]),
+            false,
+            now,
+        )
+        .unwrap();
+        assert_eq!(kept.fresh_for, Duration::from_secs(600));
+        assert_eq!(kept.age.as_secs(), 10);
+
+        let expires = httpdate::fmt_http_date(now + Duration::from_secs(120));
+        let kept = policy(ok, &headers(&[("expires", &expires)]), false, now).unwrap();
+        assert!((119..=120).contains(&kept.fresh_for.as_secs()));
+
+        let modified = httpdate::fmt_http_date(now - Duration::from_secs(1000));
+        let kept = policy(ok, &headers(&[("last-modified", &modified)]), false, now).unwrap();
+        assert_eq!(kept.fresh_for.as_secs(), 100);
+
+        let kept = policy(
+            ok,
+            &headers(&[("cache-control", "no-cache"), ("etag", "\"a\"")]),
+            false,
+            now,
+        )
+        .unwrap();
+        assert!(kept.fresh_for.is_zero() && kept.must_revalidate);
+
+        for refused in [
+            &[("cache-control", "no-store, max-age=60")][..],
+            &[("cache-control", "private, max-age=60")],
+            &[("cache-control", "max-age=60"), ("set-cookie", "a=b")],
+            &[("cache-control", "max-age=60"), ("vary", "*")],
+            &[("cache-control", "no-cache")],
+            &[],
+        ] {
+            assert_eq!(
+                policy(ok, &headers(refused), false, now),
+                None,
+                "{refused:?}"
+            );
+        }
+        assert_eq!(
+            policy(
+                StatusCode::PARTIAL_CONTENT,
+                &headers(&[("cache-control", "max-age=60")]),
+                false,
+                now
+            ),
+            None
+        );
+
+        // Shared only if the upstream says so
+        assert_eq!(
+            policy(ok, &headers(&[("cache-control", "max-age=60")]), true, now),
+            None
+        );
+        assert!(policy(
+            ok,
+            &headers(&[("cache-control", "public, max-age=60")]),
+            true,
+            now
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn varies_on_encoding() {
+        let names = vary(&headers(&[
+            ("vary", "Origin"),
+            ("content-encoding", "gzip"),
+        ]))
+        .unwrap();
+        assert_eq!(names, ["origin", "accept-encoding"]);
+    }
+
+    #[test]
+    fn conditions() {
+        let kept = headers(&[
+            ("etag", "\"v1\""),
+            ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]);
+
+        assert!(
+            Conditions::of(&headers(&[("if-none-match", "\"v0\", W/\"v1\"")])).not_modified(&kept)
+        );
+        assert!(!Conditions::of(&headers(&[("if-none-match", "\"v2\"")])).not_modified(&kept));
+        assert!(Conditions::of(&headers(&[(
+            "if-modified-since",
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        )]))
+        .not_modified(&kept));
+        assert!(!Conditions::of(&headers(&[(
+            "if-modified-since",
+            "Tue, 20 Oct 2015 07:28:00 GMT"
+        )]))
+        .not_modified(&kept));
+        assert!(!Conditions::default().not_modified(&kept));
+    }
+
+    #[test]
+    fn memory_bounded_and_purged() {
+        let config = Cache {
+            memory: 300,
+            ..Cache::default()
+        };
+        let store = Store::open(&config);
+
+        store.remember(Arc::new(entry("a.test/one", &"x".repeat(100))));
+        store.remember(Arc::new(entry("a.test/two", &"x".repeat(100))));
+        store.remember(Arc::new(entry("b.test/one", &"x".repeat(100))));
+        let stats = store.stats();
+        assert_eq!(stats.entries, 2);
+        assert!(stats.bytes <= 300);
+
+        // The least recently used went first
+        assert!(store
+            .memory
+            .lock()
+            .unwrap()
+            .entries
+            .peek("a.test/one")
+            .is_none());
+
+        assert_eq!(store.purge(Some("a.test"), "/"), 1);
+        assert_eq!(store.purge(None, "/one"), 1);
+        assert_eq!(store.stats().entries, 0);
+        assert_eq!(store.stats().bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn kept_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut entry = entry("a.test/poster.jpg", "jpeg");
+        entry.vary = vec![(
+            HeaderName::from_static("accept-encoding"),
+            Some(HeaderValue::from_static("gzip")),
+        )];
+
+        let disk = Mutex::new(Disk::open(dir.path(), 1024).unwrap());
+        Disk::save(&disk, &entry);
+
+        let config = Cache {
+            disk: Some(crate::config_loader::CacheDisk {
+                path: dir.path().to_str().unwrap().into(),
+                size: 1024,
+            }),
+            ..Cache::default()
+        };
+        let store = Store::open(&config);
+        assert_eq!(store.stats().disk_entries, 1);
+
+        let request = headers(&[("accept-encoding", "gzip")]);
+        let found = store.get("a.test/poster.jpg", &request).await.unwrap();
+        assert_eq!(found.body, "jpeg");
+        assert_eq!(found.headers["etag"], "\"v1\"");
+        assert!(store
+            .get("a.test/poster.jpg", &HeaderMap::new())
+            .await
+            .is_none());
+
+        assert_eq!(store.purge(Some("a.test"), "/poster"), 1);
+        assert_eq!(store.stats().disk_entries, 0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn disk_leaves_other_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let ours = format!("{}{EXTENSION}", "0".repeat(32));
+        for name in ["index.html", "notes.tmp", "ffff.cache", ours.as_str()] {
+            fs::write(dir.path().join(name), "not a cache entry\n").unwrap();
+        }
+        fs::write(dir.path().join(format!("{ours}.tmp")), "half written").unwrap();
+        fs::create_dir(dir.path().join("assets")).unwrap();
+
+        let disk = Disk::open(dir.path(), 1024).unwrap();
+        assert_eq!(disk.files.len(), 0);
+
+        let mut left = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|file| file.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["assets", "ffff.cache", "index.html", "notes.tmp"]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_coalesced() {
+        let timeout = Duration::from_secs(5);
+        assert!(matches!(join("a.test/art", Duration::ZERO), Flight::Alone));
+
+        let Flight::Lead(lead) = join("a.test/art", timeout) else {
+            panic!("first request leads");
+        };
+        let Flight::Wait(mut done, _) = join("a.test/art", timeout) else {
+            panic!("second request waits");
+        };
+        assert!(matches!(join("a.test/other", timeout), Flight::Lead(_)));
+
+        drop(lead);
+        assert!(done.changed().await.is_err());
+        assert!(matches!(join("a.test/art", timeout), Flight::Lead(_)));
+    }
+
+    /// An upstream answering each request with `respond`, and how many it had.
+    async fn upstream<F>(respond: F) -> (SocketAddr, Arc<AtomicUsize>)
+    where
+        F: Fn(&Request<Incoming>) -> Response<ProxyBody> + Clone + Send + Sync + 'static,
+    {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = testing::serve({
+            let hits = hits.clone();
+            move |req| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                let res = respond(&req);
+                async move { res }
+            }
+        })
+        .await;
+        (addr, hits)
+    }
+
+    fn store(coalesce_timeout: f64) -> &'static Store {
+        Box::leak(Box::new(Store::open(&Cache {
+            coalesce_timeout,
+            ..Cache::default()
+        })))
+    }
+
+    /// `path` on `host` through `store`, returning `X-Cache` and the body.
+    async fn fetch(
+        store: &'static Store,
+        host: &str,
+        upstream: SocketAddr,
+        path: &str,
+        authenticated: bool,
+    ) -> Result<(String, Bytes), UpstreamError> {
+        let req = Request::builder()
+            .uri(format!("http://{upstream}{path}"))
+            .body(full(""))
+            .unwrap();
+        let req = testing::incoming(req).await;
+        let res = store
+            .forward(host, authenticated, req, &Timeouts::default())
+            .await?;
+        let x_cache = res.headers()[X_CACHE].to_str().unwrap().to_owned();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        Ok((x_cache, body))
+    }
+
+    fn ok(cache_control: &'static str) -> Response<ProxyBody> {
+        Response::builder()
+            .header(CACHE_CONTROL, cache_control)
+            .header(ETAG, "\"v1\"")
+            .body(full("art"))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn authenticated_kept_only_when_public() {
+        let store = store(0.0);
+        let (private, hits) = upstream(|_| ok("max-age=60")).await;
+        for _ in 0..2 {
+            let (x_cache, _) = fetch(store, "auth.test", private, "/me", true)
+                .await
+                .unwrap();
+            assert_eq!(x_cache, "MISS");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        let (public, hits) = upstream(|_| ok("public, max-age=60")).await;
+        fetch(store, "auth.test", public, "/art", true)
+            .await
+            .unwrap();
+        let (x_cache, _) = fetch(store, "auth.test", public, "/art", true)
+            .await
+            .unwrap();
+        assert_eq!(x_cache, "HIT");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn revalidated_when_stale() {
+        let store = store(0.0);
+        let (addr, hits) = upstream(|req| match req.headers().get(IF_NONE_MATCH) {
+            Some(tag) if tag == "\"v1\"" => Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(CACHE_CONTROL, "max-age=60")
+                .body(full(""))
+                .unwrap(),
+            _ => ok("max-age=0"),
+        })
+        .await;
+
+        let fetch = || fetch(store, "revalidate.test", addr, "/art", false);
+        assert_eq!(fetch().await.unwrap().0, "MISS");
+        let (x_cache, body) = fetch().await.unwrap();
+        assert_eq!(x_cache, "REVALIDATED");
+        assert_eq!(body, "art");
+        assert_eq!(fetch().await.unwrap().0, "HIT");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_when_upstream_fails() {
+        let store = store(0.0);
+        let (addr, _) = upstream(|_| ok("max-age=0")).await;
+        fetch(store, "stale.test", addr, "/art", false)
+            .await
+            .unwrap();
+
+        let down = testing::closed().await;
+        let (x_cache, body) = fetch(store, "stale.test", down, "/art", false)
+            .await
+            .unwrap();
+        assert_eq!(x_cache, "STALE");
+        assert_eq!(body, "art");
+
+        let (addr, _) = upstream(|_| ok("max-age=0, must-revalidate")).await;
+        fetch(store, "stale.test", addr, "/strict", false)
+            .await
+            .unwrap();
+        assert!(fetch(store, "stale.test", down, "/strict", false)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn purged_when_no_longer_storable() {
+        let store = store(0.0);
+        let (addr, hits) = upstream(|req| match req.headers().contains_key(IF_NONE_MATCH) {
+            true => Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(CACHE_CONTROL, "no-store")
+                .body(full(""))
+                .unwrap(),
+            false => ok("max-age=0"),
+        })
+        .await;
+
+        let fetch = || fetch(store, "purge.test", addr, "/art", false);
+        fetch().await.unwrap();
+        assert_eq!(fetch().await.unwrap().0, "REVALIDATED");
+        assert_eq!(store.stats().entries, 0);
+        // Nothing left to revalidate, so it is asked for in full
+        assert_eq!(fetch().await.unwrap().0, "MISS");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    /// An upstream taking `delay` to answer, and how many requests it had.
+    async fn slow(delay: Duration, cache_control: &'static str) -> (SocketAddr, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = testing::serve({
+            let hits = hits.clone();
+            move |_| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    tokio::time::sleep(delay).await;
+                    ok(cache_control)
+                }
+            }
+        })
+        .await;
+        (addr, hits)
+    }
+
+    /// `X-Cache` of each of `n` requests for the same path at once.
+    async fn together(
+        store: &'static Store,
+        host: &str,
+        addr: SocketAddr,
+        n: usize,
+    ) -> Vec<String> {
+        let requests = (0..n).map(|_| fetch(store, host, addr, "/art", false));
+        join_all(requests)
+            .await
+            .into_iter()
+            .map(|result| result.unwrap().0)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_sent_once() {
+        let store = store(5.0);
+        let (addr, hits) = slow(Duration::from_millis(300), "max-age=60").await;
+
+        let answers = together(store, "coalesce.test", addr, 5).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert_eq!(answers.iter().filter(|a| *a == "MISS").count(), 1);
+        assert!(answers.iter().any(|a| a == "COALESCED"), "{answers:?}");
+    }
+
+    #[tokio::test]
+    async fn waiters_let_go_when_not_kept() {
+        let store = store(5.0);
+        let (addr, hits) = slow(Duration::from_millis(300), "no-store").await;
+
+        let started = std::time::Instant::now();
+        let answers = together(store, "unkept.test", addr, 3).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert!(answers.iter().all(|a| a == "MISS"), "{answers:?}");
+        // Not held until the timeout
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn waiters_fetch_after_timeout() {
+        let store = store(0.1);
+        let (addr, hits) = slow(Duration::from_secs(1), "max-age=60").await;
+
+        let answers = together(store, "timeout.test", addr, 3).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert!(answers.iter().all(|a| a == "MISS"), "{answers:?}");
+    }
+}
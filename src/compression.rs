@@ -0,0 +1,189 @@
+use std::convert::Infallible;
+use std::future::ready;
+use std::sync::Arc;
+
+use http_body_util::BodyExt;
+use hyper::body::Body;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ETAG, RANGE,
+};
+use hyper::{Request, Response};
+use tower::{service_fn, ServiceExt};
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+
+use crate::config_loader::{Compression, Encoding};
+use crate::proxy::ProxyBody;
+
+/// The encodings the client accepts, `None` when its response should be sent as
+/// it is. Only whole responses are compressed, as the parts of a range would not
+/// fit together again.
+pub fn accepted<B>(req: &Request<B>) -> Option<HeaderValue> {
+    if req.headers().contains_key(RANGE) {
+        return None;
+    }
+    req.headers().get(ACCEPT_ENCODING).cloned()
+}
+
+/// Compress `res` with the best of the enabled encodings the client accepts, if
+/// it is worth compressing.
+pub async fn compress(
+    config: &Compression,
+    accept_encoding: HeaderValue,
+    res: Response<ProxyBody>,
+) -> Response<ProxyBody> {
+    let encoded = res.headers().contains_key(CONTENT_ENCODING);
+    let enabled = |encoding| config.encodings.contains(&encoding);
+    let predicate =
+        SizeAbove::new(config.min_size).and(ContentTypes(config.content_types.clone().into()));
+
+    // Called once, to hand over the response already made
+    let mut res = Some(res);
+    let service = tower_http::compression::Compression::new(service_fn(move |_| {
+        ready(Ok::<_, Infallible>(
+            res.take().expect("response taken once"),
+        ))
+    }))
+    .gzip(enabled(Encoding::Gzip))
+    .br(enabled(Encoding::Br))
+    .zstd(enabled(Encoding::Zstd))
+    .no_deflate()
+    .compress_when(predicate);
+
+    let mut req = Request::new(());
+    req.headers_mut().insert(ACCEPT_ENCODING, accept_encoding);
+    let mut res = match service.oneshot(req).await {
+        Ok(res) => res.map(|body| body.boxed_unsync()),
+        Err(never) => match never {},
+    };
+
+    // The compressed bytes differ from those the upstream's tag was for
+    if !encoded && res.headers().contains_key(CONTENT_ENCODING) {
+        weaken_etag(res.headers_mut());
+    }
+    res
+}
+
+fn weaken_etag(headers: &mut HeaderMap) {
+    let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    if etag.starts_with('"') {
+        if let Ok(weak) = HeaderValue::from_str(&format!("W/{etag}")) {
+            headers.insert(ETAG, weak);
+        }
+    }
+}
+
+/// Compresses responses whose media type is in the list.
+#[derive(Clone)]
+struct ContentTypes(Arc<[String]>);
+
+impl Predicate for ContentTypes {
+    fn should_compress<B>(&self, res: &Response<B>) -> bool
+    where
+        B: Body,
+    {
+        let Some(content_type) = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return false;
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        // Events are sent as they happen, which compressing would hold back
+        media_type != "text/event-stream"
+            && self.0.iter().any(|wanted| matches(wanted, &media_type))
+    }
+}
+
+fn matches(wanted: &str, media_type: &str) -> bool {
+    match wanted.strip_suffix("/*") {
+        Some(kind) => media_type
+            .split_once('/')
+            .is_some_and(|(k, _)| k.eq_ignore_ascii_case(kind)),
+        None => wanted.eq_ignore_ascii_case(media_type),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::full;
+
+    fn config() -> Compression {
+        serde_json::from_str(r#"{ "encodings": ["gzip"] }"#).unwrap()
+    }
+
+    fn response(content_type: &str, len: usize) -> Response<ProxyBody> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .header("content-length", len)
+            .header(ETAG, "\"abc\"")
+            .body(full(vec![b'a'; len]))
+            .unwrap()
+    }
+
+    #[test]
+    fn media_types() {
+        assert!(matches("text/*", "text/html"));
+        assert!(matches("application/json", "application/json"));
+        assert!(!matches("text/*", "texts/html"));
+        assert!(!matches("application/json", "application/jsonp"));
+    }
+
+    #[tokio::test]
+    async fn compressed_when_worth_it() {
+        let accept = HeaderValue::from_static("br, gzip");
+
+        let res = compress(
+            &config(),
+            accept.clone(),
+            response("text/html; charset=utf-8", 4096),
+        )
+        .await;
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[ETAG], "W/\"abc\"");
+        assert!(!res.headers().contains_key("content-length"));
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.len() < 4096);
+
+        let small = compress(&config(), accept.clone(), response("text/html", 100)).await;
+        assert!(!small.headers().contains_key(CONTENT_ENCODING));
+
+        let image = compress(&config(), accept.clone(), response("image/png", 4096)).await;
+        assert!(!image.headers().contains_key(CONTENT_ENCODING));
+
+        let events = compress(
+            &config(),
+            accept.clone(),
+            response("text/event-stream", 4096),
+        )
+        .await;
+        assert!(!events.headers().contains_key(CONTENT_ENCODING));
+
+        let mut encoded = response("text/html", 4096);
+        encoded
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        let encoded = compress(&config(), accept, encoded).await;
+        assert_eq!(encoded.headers()[CONTENT_ENCODING], "br");
+        assert_eq!(encoded.headers()[ETAG], "\"abc\"");
+    }
+
+    #[test]
+    fn ranges_sent_as_they_are() {
+        let req = Request::builder()
+            .header(ACCEPT_ENCODING, "gzip")
+            .header(RANGE, "bytes=0-99")
+            .body(())
+            .unwrap();
+        assert!(accepted(&req).is_none());
+    }
+}
@@ -0,0 +1,523 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use hyper::http::uri::Authority;
+use hyper::{StatusCode, Uri};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::access_log::rfc3339;
+use crate::config_loader::{Action, Admin, Config, Host, CONFIG};
+use crate::{access, config_history, headers, tls, HOSTS};
+
+/// Config keys whose values never leave the admin listener.
+const SECRETS: [&str; 4] = ["secret", "client_secret", "session_secret", "token"];
+
+const REDACTED: &str = "<redacted>";
+
+/// Edits are made one at a time, from reading the file to reloading it.
+static EDITING: Mutex<()> = Mutex::const_new(());
+
+/// Who is changing the config, for the audit log.
+pub struct Editor {
+    pub user: String,
+    pub client: Option<IpAddr>,
+}
+
+pub enum Change {
+    Create(Value),
+    /// Replace the host with this name, which may rename it.
+    Update(String, Value),
+    Delete(String),
+}
+
+pub enum EditError {
+    /// The file changed since the editor read it.
+    Conflict,
+    NotFound,
+    Invalid(Vec<String>),
+    Failed(String),
+}
+
+/// What is wrong with a host, and what might be.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Read the config file and switch to it, keeping the current one if it is invalid.
+/// Requests already being handled finish with the config they started with.
+pub async fn reload() -> Result<usize, String> {
+    // Address lists are read with it, as requests do not wait for the disk
+    let config = tokio::task::spawn_blocking(|| {
+        let config = Config::read()?;
+        access::load_lists(&config);
+        Ok::<_, String>(config)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    let hosts = config.hosts.len();
+
+    HOSTS.store(Arc::new(config));
+    tls::reload();
+
+    tracing::info!("Reloaded config, {hosts} host(s)");
+    Ok(hosts)
+}
+
+/// The config file as written, and its version for `apply`.
+pub fn read() -> Result<(Value, String), String> {
+    let data = fs::read(CONFIG).map_err(|e| format!("{CONFIG}: {e}"))?;
+    let value = serde_json::from_slice(&data).map_err(|e| format!("{CONFIG}: {e}"))?;
+    Ok((value, version(&data)))
+}
+
+pub fn version(data: &[u8]) -> String {
+    Sha256::digest(data)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The hosts in the config file, which is either a list of them or has them under `hosts`.
+pub fn hosts(file: &Value) -> &[Value] {
+    match file {
+        Value::Array(hosts) => hosts,
+        _ => file
+            .get("hosts")
+            .and_then(Value::as_array)
+            .map_or(&[], Vec::as_slice),
+    }
+}
+
+fn hosts_mut(file: &mut Value) -> Option<&mut Vec<Value>> {
+    match file {
+        Value::Array(hosts) => Some(hosts),
+        Value::Object(file) => file.entry("hosts").or_insert(json!([])).as_array_mut(),
+        _ => None,
+    }
+}
+
+fn name(host: &Value) -> &str {
+    host.get("host").and_then(Value::as_str).unwrap_or_default()
+}
+
+/// Make `change` to the config file if it is still at version `expected`, then
+/// reload it. Returns the new version and any warnings.
+pub async fn apply(
+    change: Change,
+    expected: Option<&str>,
+    editor: &Editor,
+    admin: &Admin,
+) -> Result<(String, Vec<String>), EditError> {
+    let _editing = EDITING.lock().await;
+
+    let old = fs::read(CONFIG).map_err(|e| EditError::Failed(format!("{CONFIG}: {e}")))?;
+    if expected.is_some_and(|expected| expected != version(&old)) {
+        return Err(EditError::Conflict);
+    }
+    let mut file: Value =
+        serde_json::from_slice(&old).map_err(|e| EditError::Failed(format!("{CONFIG}: {e}")))?;
+
+    let hosts =
+        hosts_mut(&mut file).ok_or(EditError::Failed(format!("{CONFIG} has no list of hosts")))?;
+    let position = |hosts: &[Value], wanted: &str| hosts.iter().position(|h| name(h) == wanted);
+
+    let (action, target, before, after) = match change {
+        Change::Create(host) => {
+            if position(hosts, name(&host)).is_some() {
+                return Err(EditError::Invalid(vec![format!(
+                    "{} already exists",
+                    name(&host)
+                )]));
+            }
+            hosts.push(host.clone());
+            ("create_host", name(&host).to_owned(), None, Some(host))
+        }
+        Change::Update(target, mut host) => {
+            let i = position(hosts, &target).ok_or(EditError::NotFound)?;
+            restore_secrets(&mut host, &hosts[i]);
+            if name(&host) != target && position(hosts, name(&host)).is_some() {
+                return Err(EditError::Invalid(vec![format!(
+                    "{} already exists",
+                    name(&host)
+                )]));
+            }
+            let before = std::mem::replace(&mut hosts[i], host.clone());
+            ("update_host", target, Some(before), Some(host))
+        }
+        Change::Delete(target) => {
+            let i = position(hosts, &target).ok_or(EditError::NotFound)?;
+            let before = hosts.remove(i);
+            ("delete_host", target, Some(before), None)
+        }
+    };
+
+    let report = after.as_ref().map(validate).unwrap_or_default();
+    if !report.errors.is_empty() {
+        return Err(EditError::Invalid(report.errors));
+    }
+
+    let data =
+        serde_json::to_string_pretty(&file).map_err(|e| EditError::Failed(e.to_string()))? + "\n";
+    // Anything else in the file has to load as well
+    Config::parse(&data).map_err(|e| EditError::Invalid(vec![e.to_string()]))?;
+
+    replace(admin, &old, data.as_bytes())?;
+    audit(&admin.audit_log, editor, action, &target, before, after);
+    reload().await.map_err(EditError::Failed)?;
+
+    Ok((version(data.as_bytes()), report.warnings))
+}
+
+/// Go back to the snapshot `id` if the config file is still at version
+/// `expected`, then reload it. Returns the new version.
+pub async fn rollback(
+    id: &str,
+    expected: Option<&str>,
+    editor: &Editor,
+    admin: &Admin,
+) -> Result<String, EditError> {
+    let _editing = EDITING.lock().await;
+
+    let old = fs::read(CONFIG).map_err(|e| EditError::Failed(format!("{CONFIG}: {e}")))?;
+    if expected.is_some_and(|expected| expected != version(&old)) {
+        return Err(EditError::Conflict);
+    }
+
+    let data = config_history::read(&admin.history, id)
+        .map_err(|e| EditError::Failed(format!("reading snapshot {id}: {e}")))?
+        .ok_or(EditError::NotFound)?;
+    // What was valid then may not be now, if a setting has gone away
+    let text = String::from_utf8_lossy(&data);
+    Config::parse(&text).map_err(|e| EditError::Invalid(vec![e.to_string()]))?;
+
+    replace(admin, &old, &data)?;
+    audit(&admin.audit_log, editor, "rollback", id, None, None);
+    reload().await.map_err(EditError::Failed)?;
+
+    Ok(version(&data))
+}
+
+/// Write the config file, keeping a snapshot of what it was and what it became.
+/// The old one may never have been seen, if the file was edited by hand.
+fn replace(admin: &Admin, old: &[u8], new: &[u8]) -> Result<(), EditError> {
+    let snapshot = |data| {
+        if let Err(e) = config_history::record(&admin.history, admin.history_keep, data) {
+            tracing::error!(
+                "Could not keep a copy of {CONFIG} in {}: {e}",
+                admin.history
+            );
+        }
+    };
+
+    snapshot(old);
+    write_atomic(Path::new(CONFIG), new)
+        .map_err(|e| EditError::Failed(format!("writing {CONFIG}: {e}")))?;
+    snapshot(new);
+    Ok(())
+}
+
+/// Replaces `path` in one go, so a crash never leaves half a file behind.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+
+    let mut file = fs::File::create(&tmp)?;
+    // It may hold secrets, so keep whatever permissions it had
+    if let Ok(metadata) = fs::metadata(path) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp, path)
+}
+
+/// Record a change made through the admin listener.
+pub fn audit(
+    path: &str,
+    editor: &Editor,
+    action: &str,
+    target: &str,
+    before: Option<Value>,
+    after: Option<Value>,
+) {
+    tracing::info!(user = editor.user, client = ?editor.client, "{action} {target}");
+
+    let redacted = |value: Option<Value>| {
+        value.map(|mut value| {
+            redact(&mut value);
+            value
+        })
+    };
+    let line = json!({
+        "time": rfc3339(SystemTime::now()),
+        "user": editor.user,
+        "client": editor.client,
+        "action": action,
+        "target": target,
+        "before": redacted(before),
+        "after": redacted(after),
+    });
+
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{line}"));
+    if let Err(e) = written {
+        tracing::error!("Could not write audit log {path}: {e}");
+    }
+}
+
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRETS.contains(&key.as_str()) && !value.is_null() {
+                    *value = Value::String(REDACTED.into());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// `redact` for the whole config file, whose global settings also have the
+/// headers sent to the telemetry collector, which usually carry an API key.
+pub fn redact_config(value: &mut Value) {
+    redact(value);
+    if let Some(Value::Object(headers)) = value.pointer_mut("/telemetry/headers") {
+        for value in headers.values_mut() {
+            *value = Value::String(REDACTED.into());
+        }
+    }
+}
+
+/// Put back the secrets an editor was only shown redacted.
+fn restore_secrets(new: &mut Value, old: &Value) {
+    match (new, old) {
+        (Value::Object(new), Value::Object(old)) => {
+            for (key, value) in new.iter_mut() {
+                match old.get(key) {
+                    Some(old) if SECRETS.contains(&key.as_str()) && *value == REDACTED => {
+                        *value = old.clone();
+                    }
+                    Some(old) => restore_secrets(value, old),
+                    None => {}
+                }
+            }
+        }
+        (Value::Array(new), Value::Array(old)) => {
+            for (new, old) in new.iter_mut().zip(old) {
+                restore_secrets(new, old);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn has_redacted(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map.iter().any(|(key, value)| {
+            (SECRETS.contains(&key.as_str()) && *value == REDACTED) || has_redacted(value)
+        }),
+        Value::Array(values) => values.iter().any(has_redacted),
+        _ => false,
+    }
+}
+
+/// Check a host before it is saved. Errors stop it being saved, warnings are
+/// about things which may be fixed later, like missing TLS files.
+pub fn validate(host: &Value) -> Report {
+    let mut report = Report::default();
+
+    if has_redacted(host) {
+        report
+            .errors
+            .push("a secret was left redacted where there was none before".into());
+    }
+
+    let host: Host = match serde_json::from_value(host.clone()) {
+        Ok(host) => host,
+        Err(e) => {
+            report.errors.push(e.to_string());
+            return report;
+        }
+    };
+
+    if host.host.is_empty() || host.host.parse::<Authority>().is_err() {
+        report
+            .errors
+            .push(format!("{:?} is not a valid host name", host.host));
+    }
+
+    check_action(&host.action, "", &mut report);
+    for route in &host.routes {
+        let context = format!("route {}: ", route.path);
+        if !route.path.starts_with('/') {
+            report
+                .errors
+                .push(format!("{context}path must start with /"));
+        }
+        if let Some(action) = &route.action {
+            check_action(action, &context, &mut report);
+        }
+        for problem in route.headers.iter().flat_map(headers::check) {
+            report.errors.push(format!("{context}{problem}"));
+        }
+    }
+    report
+        .errors
+        .extend(host.headers.iter().flat_map(headers::check));
+
+    if let Some(tls) = &host.tls {
+        for path in [&tls.public, &tls.private] {
+            if !Path::new(path).is_file() {
+                report.warnings.push(format!("TLS file {path} not found"));
+            }
+        }
+    }
+    for (status, path) in &host.error_pages {
+        if !Path::new(path).is_file() {
+            report
+                .warnings
+                .push(format!("error page for {status} not found at {path}"));
+        }
+    }
+    if let Some(mtls) = &host.mtls {
+        if !Path::new(&mtls.ca).is_file() {
+            report
+                .errors
+                .push(format!("client CA bundle {} not found", mtls.ca));
+        }
+    }
+
+    report
+}
+
+fn check_action(action: &Action, context: &str, report: &mut Report) {
+    let errors = &mut report.errors;
+    match action {
+        Action::Destination(destination) => match destination.parse::<Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("http") && uri.authority().is_some() => {}
+            _ => errors.push(format!(
+                "{context}destination {destination:?} must be an http:// URL"
+            )),
+        },
+        Action::ServeDir(serve_dir) => {
+            if !Path::new(&serve_dir.root).is_dir() {
+                errors.push(format!("{context}directory {} not found", serve_dir.root));
+            }
+        }
+        Action::Respond(respond) => {
+            if StatusCode::from_u16(respond.status).is_err() {
+                errors.push(format!("{context}{} is not a status code", respond.status));
+            }
+            if let Some(file) = respond.file.as_ref().filter(|f| !Path::new(f).is_file()) {
+                errors.push(format!("{context}response file {file} not found"));
+            }
+        }
+        Action::Redirect(redirect) => {
+            if !(300..400).contains(&redirect.status) {
+                errors.push(format!(
+                    "{context}{} is not a redirect status",
+                    redirect.status
+                ));
+            }
+            if redirect.to.is_empty() || redirect.to.parse::<Uri>().is_err() {
+                errors.push(format!("{context}{:?} is not a URL", redirect.to));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_redacted_and_restored() {
+        let old = json!({
+            "host": "a.test",
+            "destination": "http://127.0.0.1:8096",
+            "auth": { "oidc": { "client_id": "envoi", "client_secret": "hunter2" } },
+            "routes": [{ "path": "/api", "auth": { "jwt": { "secret": "s3cret" } } }],
+        });
+
+        let mut shown = old.clone();
+        redact(&mut shown);
+        assert_eq!(shown["auth"]["oidc"]["client_id"], "envoi");
+        assert_eq!(shown["auth"]["oidc"]["client_secret"], REDACTED);
+        assert_eq!(shown["routes"][0]["auth"]["jwt"]["secret"], REDACTED);
+
+        let mut edited = shown.clone();
+        edited["destination"] = json!("http://127.0.0.1:8097");
+        edited["routes"][0]["auth"]["jwt"]["secret"] = json!("new");
+        restore_secrets(&mut edited, &old);
+        assert_eq!(edited["auth"]["oidc"]["client_secret"], "hunter2");
+        assert_eq!(edited["routes"][0]["auth"]["jwt"]["secret"], "new");
+        assert!(!has_redacted(&edited));
+
+        // Nothing to restore it from
+        let mut moved = shown.clone();
+        moved["routes"] = json!([]);
+        moved["auth"]["jwt"] = shown["routes"][0]["auth"]["jwt"].clone();
+        restore_secrets(&mut moved, &json!({}));
+        assert!(has_redacted(&moved));
+    }
+
+    #[test]
+    fn validation() {
+        let report = validate(&json!({ "host": "a.test", "destination": "http://127.0.0.1:8096" }));
+        assert!(report.errors.is_empty() && report.warnings.is_empty());
+
+        let report = validate(&json!({
+            "host": "a test",
+            "destination": "https://127.0.0.1:8096",
+            "tls": { "public": "missing.pem", "private": "missing.key" },
+            "routes": [
+                { "path": "api", "serve_dir": "./missing" },
+                { "path": "/old", "redirect": { "to": "https://a.test", "status": 200 } },
+            ],
+        }));
+        assert_eq!(
+            report.errors,
+            [
+                "\"a test\" is not a valid host name",
+                "destination \"https://127.0.0.1:8096\" must be an http:// URL",
+                "route api: path must start with /",
+                "route api: directory ./missing not found",
+                "route /old: 200 is not a redirect status",
+            ]
+        );
+        assert_eq!(report.warnings.len(), 2);
+
+        // Not a host at all
+        let report = validate(&json!({ "host": "a.test" }));
+        assert_eq!(report.errors.len(), 1);
+    }
+
+    #[test]
+    fn hosts_in_either_layout() {
+        let list = json!([{ "host": "a.test" }]);
+        assert_eq!(name(&hosts(&list)[0]), "a.test");
+
+        let mut file = json!({ "timeouts": { "connect": 5 } });
+        assert!(hosts(&file).is_empty());
+        hosts_mut(&mut file)
+            .unwrap()
+            .push(json!({ "host": "b.test" }));
+        assert_eq!(name(&hosts(&file)[0]), "b.test");
+        assert_eq!(file["timeouts"]["connect"], 5);
+    }
+}
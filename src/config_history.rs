@@ -0,0 +1,171 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use similar::TextDiff;
+
+use crate::access_log::rfc3339;
+
+/// A copy of the config file as it was when it was accepted.
+pub struct Snapshot {
+    /// When it was taken, which also names the file.
+    pub id: String,
+    pub version: String,
+}
+
+fn path(dir: &str, id: &str) -> PathBuf {
+    Path::new(dir).join(format!("{id}.json"))
+}
+
+/// Keep a copy of `data` unless it is the same as the newest one, then drop the
+/// oldest beyond `keep`. Returns the new snapshot's id.
+pub fn record(dir: &str, keep: usize, data: &[u8]) -> io::Result<Option<String>> {
+    record_at(dir, keep, data, SystemTime::now())
+}
+
+fn record_at(dir: &str, keep: usize, data: &[u8], now: SystemTime) -> io::Result<Option<String>> {
+    let snapshots = list(dir)?;
+    if let Some(newest) = snapshots.first() {
+        if fs::read(path(dir, &newest.id))? == data {
+            return Ok(None);
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    // Colons are not allowed in file names everywhere
+    let stamp = rfc3339(now).replace(':', "-");
+    // Those taken within the same millisecond get a count, never replacing
+    // one another
+    let mut id = stamp.clone();
+    for count in 1.. {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path(dir, &id))
+        {
+            Ok(mut file) => {
+                file.write_all(data)?;
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => id = format!("{stamp}-{count}"),
+            Err(e) => return Err(e),
+        }
+    }
+
+    for old in snapshots.iter().skip(keep.saturating_sub(1)) {
+        if let Err(e) = fs::remove_file(path(dir, &old.id)) {
+            tracing::warn!("Could not remove old config snapshot {}: {e}", old.id);
+        }
+    }
+
+    Ok(Some(id))
+}
+
+/// The snapshots in `dir`, newest first.
+pub fn list(dir: &str) -> io::Result<Vec<Snapshot>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let Some(id) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        let data = fs::read(&path)?;
+        snapshots.push(Snapshot {
+            id: id.to_owned(),
+            version: crate::config_edit::version(&data),
+        });
+    }
+
+    snapshots.sort_by(|a, b| order(&b.id).cmp(&order(&a.id)));
+    Ok(snapshots)
+}
+
+/// Timestamps of the same width sort by time, then by the count of those taken
+/// within the same millisecond.
+fn order(id: &str) -> (&str, u32) {
+    match id.split_once("Z-") {
+        Some((stamp, count)) => (stamp, count.parse().unwrap_or_default()),
+        None => (id.strip_suffix('Z').unwrap_or(id), 0),
+    }
+}
+
+/// The snapshot `id`, `None` if there is no such snapshot.
+pub fn read(dir: &str, id: &str) -> io::Result<Option<Vec<u8>>> {
+    // Only ever read what `list` found, so the id cannot name another file
+    if !list(dir)?.iter().any(|snapshot| snapshot.id == id) {
+        return Ok(None);
+    }
+    fs::read(path(dir, id)).map(Some)
+}
+
+/// A unified diff between two versions of the config.
+pub fn diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(old_name, new_name)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_deduplicated_and_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+
+        assert!(record(dir, 2, b"one").unwrap().is_some());
+        assert!(record(dir, 2, b"one").unwrap().is_none());
+        let two = record(dir, 2, b"two").unwrap().unwrap();
+        let three = record(dir, 2, b"three").unwrap().unwrap();
+
+        let snapshots = list(dir).unwrap();
+        let ids = snapshots.iter().map(|s| s.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, [three.as_str(), two.as_str()]);
+        assert_eq!(read(dir, &two).unwrap().unwrap(), b"two");
+        assert!(read(dir, "../Hosts").unwrap().is_none());
+    }
+
+    fn ids(dir: &str) -> Vec<String> {
+        list(dir).unwrap().into_iter().map(|s| s.id).collect()
+    }
+
+    #[test]
+    fn same_millisecond() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        let now = SystemTime::now();
+
+        // An edit snapshots the file before and after it
+        let before = record_at(dir, 50, b"before", now).unwrap().unwrap();
+        let after = record_at(dir, 50, b"after", now).unwrap().unwrap();
+        assert_ne!(before, after);
+        assert_eq!(read(dir, &before).unwrap().unwrap(), b"before");
+        assert_eq!(read(dir, &after).unwrap().unwrap(), b"after");
+        assert_eq!(ids(dir), [after.as_str(), before.as_str()]);
+
+        // The newest is kept, not the one it would have replaced
+        let newest = record_at(dir, 1, b"newest", now).unwrap().unwrap();
+        assert_eq!(ids(dir), [newest.as_str()]);
+        assert_eq!(read(dir, &newest).unwrap().unwrap(), b"newest");
+    }
+
+    #[test]
+    fn unified_diff() {
+        let diff = diff("a\nb\nc\n", "a\nB\nc\n", "old", "new");
+        assert!(diff.starts_with("--- old\n+++ new\n"));
+        assert!(diff.contains("-b\n+B\n"));
+    }
+}
@@ -1,47 +1,995 @@
-use serde::{Deserialize, Serialize};
+use ipnet::IpNet;
+use jsonwebtoken::jwk::Jwk;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
-use std::fs;
-use std::os::unix::process;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
 
-const CONFIG: &str = "Hosts.json";
+pub const CONFIG: &str = "Hosts.json";
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Host {
-    host: String,
-    destination: String,
-    tls: Option<Tls>,
+    pub host: String,
+    #[serde(flatten, deserialize_with = "host_action")]
+    pub action: Action,
+    pub tls: Option<Tls>,
+    /// Paths to custom error page templates, keyed by status code.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub error_pages: HashMap<u16, String>,
+    /// Overrides for the global timeouts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeouts: Option<Timeouts>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rate_limits: Vec<RateLimit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access: Option<Access>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Auth>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtls: Option<Mtls>,
+    /// Overrides the global compression settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// Keep proxied responses the upstream allows to be cached, in the global cache.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cache: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<Headers>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Host {
+    /// The route with the longest `path` matching `path`.
+    pub fn route(&self, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| {
+                path.strip_prefix(route.path.as_str()).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/') || route.path.ends_with('/')
+                })
+            })
+            .max_by_key(|route| route.path.len())
+    }
+}
+
+/// Settings for the requests of a host whose path starts with `path`,
+/// anything left out is taken from the host.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Route {
+    pub path: String,
+    #[serde(flatten, deserialize_with = "route_action")]
+    pub action: Option<Action>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limits: Option<Vec<RateLimit>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access: Option<Access>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Auth>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<Headers>,
+}
+
+/// Changes to the headers of requests sent upstream, and of the responses sent
+/// back to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Headers {
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub request: HeaderRules,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub response: HeaderRules,
+    /// Added to responses which do not have them already.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<SecurityHeaders>,
+}
+
+/// Applied in order: `remove`, `rename`, `set`, then `add`. Values may use
+/// placeholders such as `{{client_ip}}`, see `headers::fill`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HeaderRules {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    /// From the old name to the new one.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub rename: HashMap<String, String>,
+    /// Replaces any values the header already has, removing them when the
+    /// value comes out empty.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub set: HashMap<String, String>,
+    /// Goes alongside any values the header already has.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub add: HashMap<String, String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty()
+            && self.rename.is_empty()
+            && self.set.is_empty()
+            && self.add.is_empty()
+    }
+}
+
+/// The usual security headers, each of which `null` leaves out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecurityHeaders {
+    /// `Strict-Transport-Security`
+    #[serde(default = "default_hsts")]
+    pub hsts: Option<String>,
+    /// `Content-Security-Policy`, left out unless given as it depends on the site.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_security_policy: Option<String>,
+    /// `X-Frame-Options`
+    #[serde(default = "default_frame_options")]
+    pub frame_options: Option<String>,
+    /// `Referrer-Policy`
+    #[serde(default = "default_referrer_policy")]
+    pub referrer_policy: Option<String>,
+    /// `X-Content-Type-Options: nosniff`
+    #[serde(default = "default_true")]
+    pub nosniff: bool,
+    /// Remove `Server` and `X-Powered-By`, which name the software behind us.
+    #[serde(default = "default_true")]
+    pub hide_backend: bool,
+}
+
+fn default_hsts() -> Option<String> {
+    Some("max-age=31536000".into())
+}
+
+fn default_frame_options() -> Option<String> {
+    Some("SAMEORIGIN".into())
+}
+
+fn default_referrer_policy() -> Option<String> {
+    Some("strict-origin-when-cross-origin".into())
+}
+
+/// Ask clients connecting to a host for a certificate, checked during the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mtls {
+    /// PEM bundle of the CAs client certificates must be issued by.
+    pub ca: String,
+    /// Revocation lists, PEM or DER. Read at startup and on reload.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub crls: Vec<String>,
+    /// Without a certificate, requests get `403 Forbidden` rather than being let
+    /// through without one.
+    #[serde(default = "default_true")]
+    pub required: bool,
+    /// Subject common names or SANs, of which the certificate must have one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_subjects: Vec<String>,
+    /// SHA-256 fingerprints in hex, of which the certificate must be one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_fingerprints: Vec<String>,
+}
+
+/// Which client IPs may connect. Denied addresses are always refused; when
+/// there is anything to allow, everything else is refused too.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Access {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Cidr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Cidr>,
+    /// Files with one address or CIDR per line, reloaded when they change.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_files: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_files: Vec<String>,
+    /// ISO country codes, looked up in the `geoip` country database.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_countries: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_countries: Vec<String>,
+}
+
+/// An IPv4 or IPv6 network, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr(pub IpNet);
+
+impl FromStr for Cidr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<IpNet>() {
+            Ok(net) => Ok(Cidr(net)),
+            Err(_) => s.parse::<IpAddr>().map(|ip| Cidr(ip.into())),
+        }
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| D::Error::custom(format!("invalid address or CIDR {s:?}")))
+    }
+}
+
+/// How clients prove who they are before a host's requests are handled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Auth {
+    Basic(BasicAuth),
+    Forward(ForwardAuth),
+    Oidc(OidcAuth),
+    Jwt(JwtAuth),
+    /// Lets a route through without the host's auth.
+    Off,
+}
+
+/// HTTP Basic auth against an htpasswd file of bcrypt or argon2 hashes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BasicAuth {
+    /// Reloaded when it changes.
+    pub htpasswd: String,
+    #[serde(default = "default_realm")]
+    pub realm: String,
+}
+
+fn default_realm() -> String {
+    "Envoi".into()
+}
+
+/// Ask an auth service about each request; a 2xx response lets it through,
+/// anything else is sent back to the client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardAuth {
+    pub url: String,
+    /// Headers copied from the auth service's response to the upstream request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub copy_headers: Vec<String>,
+}
+
+/// Log in with an OpenID Connect provider, keeping the user in a session cookie.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OidcAuth {
+    /// Where `/.well-known/openid-configuration` is found.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Handled by Envoi on every host using this login, which all have to be
+    /// registered with the provider as `https://<host><callback_path>`.
+    #[serde(default = "default_callback_path")]
+    pub callback_path: String,
+    /// Lets hosts under one domain share a login, e.g. `citrusfire.co.uk`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cookie_domain: Option<String>,
+    /// Encrypts the session cookie. Without one, sessions end when Envoi restarts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_secret: Option<String>,
+    /// Seconds before the user has to log in again.
+    #[serde(default = "default_session_lifetime")]
+    pub session_lifetime: u64,
+    /// Upstream request headers set from the ID token's claims, header to claim.
+    #[serde(default = "default_claim_headers")]
+    pub claim_headers: HashMap<String, String>,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".into(), "email".into(), "profile".into()]
+}
+
+fn default_callback_path() -> String {
+    "/_envoi/oidc/callback".into()
+}
+
+fn default_session_lifetime() -> u64 {
+    12 * 60 * 60
+}
+
+fn default_claim_headers() -> HashMap<String, String> {
+    HashMap::from([
+        ("X-Auth-User".into(), "sub".into()),
+        ("X-Auth-Email".into(), "email".into()),
+        ("X-Auth-Name".into(), "preferred_username".into()),
+    ])
+}
+
+/// Require an `Authorization: Bearer` JWT, signed with one of the keys given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JwtAuth {
+    /// For HS256, HS384 and HS512 tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// Public keys as JWKs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<Jwk>,
+    /// Re-read when it changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// Claims the token must have. `null` accepts any value; otherwise the claim
+    /// must equal the value, or contain it if the claim is a list or a `scope`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub required_claims: HashMap<String, serde_json::Value>,
+    /// Upstream request headers set from the token's claims, header to claim.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub forward_claims: HashMap<String, String>,
+    #[serde(default = "default_realm")]
+    pub realm: String,
+}
+
+/// Allow `limit` requests every `per` seconds for each key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub key: RateLimitKey,
+    pub limit: u32,
+    #[serde(deserialize_with = "period")]
+    pub per: f64,
+    /// Requests allowed in a burst by the token bucket, defaults to `limit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub algorithm: Algorithm,
+}
+
+/// The longest `per` a rate limit can have, a year.
+const MAX_PERIOD: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+/// A rate limit's period in seconds, small enough that counters cannot overflow.
+fn period<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let per = f64::deserialize(deserializer)?;
+    if !(0.001..=MAX_PERIOD).contains(&per) {
+        return Err(D::Error::custom(format!(
+            "per must be between 0.001 and {MAX_PERIOD} seconds, not {per}"
+        )));
+    }
+    Ok(per)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// Each client IP has its own limit.
+    #[default]
+    Ip,
+    /// Each value of this header has its own limit, e.g. an API key.
+    Header(String),
+    /// All clients share one limit.
+    Route,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    #[default]
+    TokenBucket,
+    SlidingWindow,
+}
+
+/// What a host does with its requests, set by exactly one of these keys.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Proxy to this upstream, e.g. `http://192.168.68.100:8096`.
+    Destination(String),
+    /// Serve static files, either just the root directory or the full options.
+    ServeDir(ServeDir),
+    /// Answer with a fixed response.
+    Respond(Respond),
+    /// Redirect somewhere else.
+    Redirect(Redirect),
+}
+
+/// The keys which choose an `Action`.
+const ACTIONS: [&str; 4] = ["destination", "serve_dir", "respond", "redirect"];
+
+/// Whatever keys a host or route has besides its settings, which may only be
+/// one action. Flattening does not catch misspelled or repeated ones itself.
+fn route_action<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Action>, D::Error> {
+    let rest = serde_json::Map::deserialize(deserializer)?;
+    if let Some(key) = rest.keys().find(|key| !ACTIONS.contains(&key.as_str())) {
+        return Err(D::Error::custom(format!("unknown key {key:?}")));
+    }
+    if rest.len() > 1 {
+        return Err(D::Error::custom(format!(
+            "only one of {} can be set",
+            ACTIONS.join(", ")
+        )));
+    }
+    if rest.is_empty() {
+        return Ok(None);
+    }
+
+    Action::deserialize(serde_json::Value::Object(rest))
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+fn host_action<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Action, D::Error> {
+    route_action(deserializer)?
+        .ok_or_else(|| D::Error::custom(format!("one of {} must be set", ACTIONS.join(", "))))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "ServeDirOptions")]
+pub struct ServeDir {
+    pub root: String,
+    /// Files to look for when a directory is requested, in order.
+    pub index: Vec<String>,
+    /// List the contents of directories without an index file.
+    pub listing: bool,
+    /// Answer requests for missing files with the root index, for single page apps.
+    pub spa: bool,
+    /// Serve `.br` / `.gz` files next to the requested one if the client accepts them.
+    pub precompressed: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServeDirOptions {
+    Root(String),
+    Full {
+        root: String,
+        #[serde(default = "default_index")]
+        index: Vec<String>,
+        #[serde(default)]
+        listing: bool,
+        #[serde(default)]
+        spa: bool,
+        #[serde(default = "default_true")]
+        precompressed: bool,
+    },
+}
+
+impl From<ServeDirOptions> for ServeDir {
+    fn from(options: ServeDirOptions) -> Self {
+        match options {
+            ServeDirOptions::Root(root) => ServeDir {
+                root,
+                index: default_index(),
+                listing: false,
+                spa: false,
+                precompressed: true,
+            },
+            ServeDirOptions::Full {
+                root,
+                index,
+                listing,
+                spa,
+                precompressed,
+            } => ServeDir {
+                root,
+                index,
+                listing,
+                spa,
+                precompressed,
+            },
+        }
+    }
+}
+
+fn default_index() -> Vec<String> {
+    vec!["index.html".into()]
+}
+
+/// What to do with requests for hosts which are not configured.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownHost {
+    /// Close the connection without answering, and refuse TLS handshakes for
+    /// server names which are not configured.
+    Drop,
+    #[serde(untagged)]
+    Action(Action),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Respond {
+    #[serde(default = "default_respond_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub body: String,
+    /// Template to use instead of `body`, see the error pages for placeholders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+fn default_respond_status() -> u16 {
+    200
+}
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Redirect {
+    /// Where to, e.g. `https://citrusfire.co.uk`.
+    pub to: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    /// Append the request's path and query to `to`.
+    #[serde(default = "default_true")]
+    pub keep_path: bool,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tls {
-    public: String,
-    private: String,
+    pub public: String,
+    pub private: String,
+}
+
+/// Settings which apply to every host.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Global {
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Unix socket a newer Envoi connects to, to take over our listening sockets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade_socket: Option<String>,
+    /// Defaults to a 404 page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_host: Option<UnknownHost>,
+    /// Connections from one IP beyond this are closed before the TLS handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections_per_ip: Option<u32>,
+    /// Checked when connections are accepted, before the TLS handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access: Option<Access>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geoip: Option<GeoIp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_log: Option<AccessLog>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin: Option<Admin>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telemetry: Option<Telemetry>,
+    #[serde(default)]
+    pub request_id: RequestId,
+    /// Applies to hosts without their own compression settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// Where the hosts with `cache` set keep responses.
+    #[serde(default)]
+    pub cache: Cache,
+}
+
+/// How requests are given an ID, which is passed upstream, returned to the client
+/// and shown in logs and error pages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RequestId {
+    #[serde(default = "default_request_id_header")]
+    pub header: String,
+    /// Clients whose own ID is kept, such as a load balancer in front of Envoi.
+    /// Everyone else's is replaced.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted: Vec<Cidr>,
+    #[serde(default)]
+    pub format: RequestIdFormat,
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        RequestId {
+            header: default_request_id_header(),
+            trusted: Vec::new(),
+            format: RequestIdFormat::default(),
+        }
+    }
+}
+
+fn default_request_id_header() -> String {
+    "X-Request-Id".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestIdFormat {
+    #[default]
+    Uuid7,
+    Ulid,
+}
+
+/// Exports a span for every request over OTLP/HTTP, JSON encoded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Telemetry {
+    /// The collector's traces endpoint, usually `http://<collector>:4318/v1/traces`.
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Sent along with every export, for collectors which want an API key.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+}
+
+fn default_service_name() -> String {
+    "envoi".into()
+}
+
+/// A separate plain HTTP listener for metrics and the admin API, bound to
+/// localhost or a private network.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Admin {
+    pub listen: SocketAddr,
+    /// Required as a bearer token by the API, though not for `/metrics`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Users who can sign in to the API and the config portal with Basic auth.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub htpasswd: Option<String>,
+    /// Changes made to the config through the admin listener, one JSON line each.
+    #[serde(default = "default_audit_log")]
+    pub audit_log: String,
+    /// Where a copy of each accepted version of the config file is kept.
+    #[serde(default = "default_history")]
+    pub history: String,
+    /// How many versions to keep.
+    #[serde(default = "default_history_keep")]
+    pub history_keep: usize,
+}
+
+fn default_audit_log() -> String {
+    "audit.log".into()
+}
+
+fn default_history() -> String {
+    "history".into()
+}
+
+fn default_history_keep() -> usize {
+    50
+}
+
+/// One line per request, written separately from the diagnostic log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessLog {
+    pub path: String,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// Start a new file once the current one reaches this many bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Start a new file every hour or day (UTC).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotate: Option<Rotate>,
+    /// How many rotated files to keep.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    #[default]
+    Combined,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotate {
+    Hourly,
+    Daily,
+}
+
+/// Compressing responses for clients which accept it. Responses which are
+/// already encoded or are part of a range are sent as they are.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Compression {
+    /// Off for a host which would otherwise have the global settings.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_encodings")]
+    pub encodings: Vec<Encoding>,
+    /// Responses smaller than this many bytes are not worth compressing.
+    #[serde(default = "default_min_size")]
+    pub min_size: u16,
+    /// Media types to compress, `text/*` matching any text type.
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+fn default_encodings() -> Vec<Encoding> {
+    vec![Encoding::Zstd, Encoding::Br, Encoding::Gzip]
+}
+
+fn default_min_size() -> u16 {
+    1024
+}
+
+fn default_content_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "application/wasm",
+        "image/svg+xml",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// Sizes are in bytes. Only read at startup.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cache {
+    /// Least recently used responses are dropped beyond this.
+    #[serde(default = "default_cache_memory")]
+    pub memory: u64,
+    /// Larger responses are not kept.
+    #[serde(default = "default_cache_max_entry")]
+    pub max_entry: u64,
+    /// Also keeps responses on disk, where they survive a restart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk: Option<CacheDisk>,
+    /// Seconds that requests for a response already being fetched wait for it,
+    /// before fetching it themselves. 0 fetches it for each of them.
+    #[serde(default = "default_coalesce_timeout")]
+    pub coalesce_timeout: f64,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            memory: default_cache_memory(),
+            max_entry: default_cache_max_entry(),
+            disk: None,
+            coalesce_timeout: default_coalesce_timeout(),
+        }
+    }
+}
+
+impl Cache {
+    pub fn coalesce_timeout(&self) -> Duration {
+        secs(Some(self.coalesce_timeout)).unwrap_or_default()
+    }
+}
+
+fn default_coalesce_timeout() -> f64 {
+    5.0
+}
+
+fn default_cache_memory() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_cache_max_entry() -> u64 {
+    8 * 1024 * 1024
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheDisk {
+    pub path: String,
+    #[serde(default = "default_cache_disk_size")]
+    pub size: u64,
+}
+
+fn default_cache_disk_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+/// MaxMind format (mmdb) databases used to tag clients with their country and ASN.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeoIp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country_db: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn_db: Option<String>,
+    /// Sent upstream with the client's country code, replacing any from the client.
+    #[serde(default = "default_country_header")]
+    pub country_header: String,
+}
+
+fn default_country_header() -> String {
+    "X-Client-Country".into()
+}
+
+/// Timeouts in seconds, `0` disables a timeout.
+///
+/// `header_read` and `client_idle` apply to client connections before the host is
+/// known, and `drain` to the whole process, so they are only read from the global
+/// settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Timeouts {
+    /// Receiving the request headers from the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_read: Option<f64>,
+    /// Keeping an idle client connection open between requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_idle: Option<f64>,
+    /// Waiting for the next chunk of the request body from the client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<f64>,
+    /// Connecting to the upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect: Option<f64>,
+    /// Waiting for the upstream's response headers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_response: Option<f64>,
+    /// The whole request, up to the last byte of the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<f64>,
+    /// Keeping an idle pooled upstream connection open.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_idle: Option<f64>,
+    /// Letting in-flight requests finish when shutting down.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drain: Option<f64>,
+}
+
+impl Timeouts {
+    fn defaults() -> Self {
+        Timeouts {
+            header_read: Some(30.0),
+            client_idle: Some(75.0),
+            request_body: Some(60.0),
+            connect: Some(10.0),
+            upstream_response: Some(60.0),
+            request: None,
+            upstream_idle: Some(90.0),
+            drain: Some(30.0),
+        }
+    }
+
+    /// Fill in anything not set here from `fallback`.
+    pub fn or(&self, fallback: &Timeouts) -> Timeouts {
+        Timeouts {
+            header_read: self.header_read.or(fallback.header_read),
+            client_idle: self.client_idle.or(fallback.client_idle),
+            request_body: self.request_body.or(fallback.request_body),
+            connect: self.connect.or(fallback.connect),
+            upstream_response: self.upstream_response.or(fallback.upstream_response),
+            request: self.request.or(fallback.request),
+            upstream_idle: self.upstream_idle.or(fallback.upstream_idle),
+            drain: self.drain.or(fallback.drain),
+        }
+    }
+
+    pub fn header_read(&self) -> Option<Duration> {
+        secs(self.header_read)
+    }
+
+    pub fn client_idle(&self) -> Option<Duration> {
+        secs(self.client_idle)
+    }
+
+    pub fn request_body(&self) -> Option<Duration> {
+        secs(self.request_body)
+    }
+
+    pub fn connect(&self) -> Option<Duration> {
+        secs(self.connect)
+    }
+
+    pub fn upstream_response(&self) -> Option<Duration> {
+        secs(self.upstream_response)
+    }
+
+    pub fn request(&self) -> Option<Duration> {
+        secs(self.request)
+    }
+
+    pub fn upstream_idle(&self) -> Option<Duration> {
+        secs(self.upstream_idle)
+    }
+
+    pub fn drain(&self) -> Option<Duration> {
+        secs(self.drain)
+    }
+}
+
+fn secs(value: Option<f64>) -> Option<Duration> {
+    value
+        .filter(|s| *s > 0.0)
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+}
+
+/// On disk the config is either a plain list of hosts, or the global settings
+/// alongside a `hosts` list.
+#[derive(Serialize, Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    global: Global,
+    hosts: Vec<Host>,
 }
 
 pub struct Config {
-    pub dest_map: HashMap<String, String>,
-    pub tls_map: HashMap<String, Option<Tls>>,
+    pub global: Global,
+    pub hosts: HashMap<String, Host>,
 }
 
 impl Config {
     pub fn load() -> Self {
-        let data = fs::read_to_string(CONFIG).unwrap();
+        let data = match fs::read_to_string(CONFIG) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::create(),
+            Err(e) => panic!("Could not read {CONFIG}: {e}"),
+        };
 
-        let hosts: Vec<Host> = serde_json::from_str(&data).unwrap(); //TODO: Unwrap()
+        Self::parse(&data).unwrap() //TODO: Unwrap()
+    }
 
-        Self::to_map(hosts)
+    /// Read the config file again, for a reload.
+    pub fn read() -> Result<Self, String> {
+        let data = fs::read_to_string(CONFIG).map_err(|e| format!("{CONFIG}: {e}"))?;
+        Self::parse(&data).map_err(|e| format!("{CONFIG}: {e}"))
     }
 
-    pub fn to_map(hosts: Vec<Host>) -> Self {
-        let mut dest_map: HashMap<String, String> = HashMap::new();
-        let mut tls_map: HashMap<String, Option<Tls>> = HashMap::new();
+    pub fn parse(data: &str) -> Result<Self, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_str(data)?;
 
-        for host in hosts {
-            dest_map.insert(host.host.clone(), host.destination);
-            tls_map.insert(host.host, host.tls);
-        }
+        let file = if value.is_array() {
+            ConfigFile {
+                global: Global::default(),
+                hosts: serde_json::from_value(value)?,
+            }
+        } else {
+            serde_json::from_value(value)?
+        };
+
+        Ok(Self::to_map(file.global, file.hosts))
+    }
+
+    pub fn to_map(mut global: Global, hosts: Vec<Host>) -> Self {
+        global.timeouts = global.timeouts.or(&Timeouts::defaults());
 
-        Config { dest_map, tls_map }
+        // Looked up by the lowercased `Host` header and server name
+        let hosts = hosts
+            .into_iter()
+            .map(|host| (host.host.to_ascii_lowercase(), host))
+            .collect();
+
+        Config { global, hosts }
+    }
+
+    /// The compression settings for `host`, or the global ones, `None` when it
+    /// is off.
+    pub fn compression<'a>(&'a self, host: Option<&'a Host>) -> Option<&'a Compression> {
+        host.and_then(|h| h.compression.as_ref())
+            .or(self.global.compression.as_ref())
+            .filter(|c| c.enabled)
+    }
+
+    /// The timeouts for `host`, or the global ones for unknown hosts.
+    pub fn timeouts(&self, host: Option<&Host>) -> Timeouts {
+        match host.and_then(|h| h.timeouts.as_ref()) {
+            Some(timeouts) => timeouts.or(&self.global.timeouts),
+            None => self.global.timeouts.clone(),
+        }
     }
 
     pub fn create() -> Self {
@@ -52,13 +1000,28 @@ impl Config {
 
         let host = Host {
             host: "emby.citrusfire.co.uk".into(),
-            destination: "192.168.68.100:8096".into(),
+            action: Action::Destination("http://192.168.68.100:8096".into()),
             tls: Some(tls),
+            error_pages: HashMap::new(),
+            timeouts: None,
+            rate_limits: Vec::new(),
+            access: None,
+            auth: None,
+            mtls: None,
+            compression: None,
+            cache: false,
+            headers: None,
+            routes: Vec::new(),
         };
 
-        let serialized = serde_json::to_string(&host).unwrap();
+        let serialized = serde_json::to_string_pretty(&[&host]).unwrap();
 
-        let write_handle = fs::write(CONFIG, serialized);
+        // Never replace a file that turned up in the meantime
+        let write_handle = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(CONFIG)
+            .and_then(|mut file| file.write_all(serialized.as_bytes()));
 
         match write_handle {
             Ok(_) => {
@@ -68,6 +1031,86 @@ impl Config {
                 tracing::error!("Could NOT create {CONFIG}. \n{e}");
             }
         }
-        Self::to_map(vec![host])
+        Self::to_map(Global::default(), vec![host])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(value: serde_json::Value) -> Result<Host, String> {
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn hosts_have_one_action() {
+        let host = parse(json!({ "host": "a.test", "redirect": { "to": "https://b.test" } }));
+        assert!(matches!(host.unwrap().action, Action::Redirect(r) if r.status == 301));
+
+        let none = parse(json!({ "host": "a.test" })).unwrap_err();
+        assert!(none.contains("must be set"), "{none}");
+
+        let both = parse(json!({
+            "host": "a.test",
+            "destination": "http://127.0.0.1:8096",
+            "redirect": { "to": "https://b.test" },
+        }))
+        .unwrap_err();
+        assert!(both.contains("only one of"), "{both}");
+
+        let misspelled = parse(json!({ "host": "a.test", "destinaton": "http://127.0.0.1" }));
+        let misspelled = misspelled.unwrap_err();
+        assert!(
+            misspelled.contains("unknown key \"destinaton\""),
+            "{misspelled}"
+        );
+    }
+
+    #[test]
+    fn routes_have_at_most_one_action() {
+        let route = |route| parse(json!({ "host": "a.test", "respond": {}, "routes": [route] }));
+
+        let host = route(json!({ "path": "/api", "auth": "off" })).unwrap();
+        assert!(host.routes[0].action.is_none());
+
+        let host = route(json!({ "path": "/old", "redirect": { "to": "/new" } })).unwrap();
+        assert!(matches!(&host.routes[0].action, Some(Action::Redirect(r)) if r.to == "/new"));
+
+        let misspelled = route(json!({ "path": "/old", "redirct": { "to": "/new" } }));
+        assert!(misspelled.unwrap_err().contains("unknown key \"redirct\""));
+
+        let both = route(json!({
+            "path": "/old",
+            "respond": {},
+            "redirect": { "to": "/new" },
+        }));
+        assert!(both.unwrap_err().contains("only one of"));
+
+        // Not quietly dropped when it does not parse
+        let broken = route(json!({ "path": "/old", "redirect": { "status": 302 } }));
+        assert!(broken.unwrap_err().contains("to"));
+    }
+
+    #[test]
+    fn actions_written_as_read() {
+        let value = json!({
+            "host": "a.test",
+            "serve_dir": "./res/dirs/www",
+            "routes": [{ "path": "/api", "destination": "http://127.0.0.1:8096" }],
+        });
+        let written = serde_json::to_value(parse(value).unwrap()).unwrap();
+
+        assert_eq!(written["serve_dir"]["root"], "./res/dirs/www");
+        assert_eq!(written["routes"][0]["destination"], "http://127.0.0.1:8096");
+        assert!(parse(written).is_ok());
+    }
+
+    #[test]
+    fn example_config_loads() {
+        let data = fs::read_to_string(CONFIG).unwrap();
+        assert!(!Config::parse(&data).unwrap().hosts.is_empty());
     }
 }
@@ -0,0 +1,162 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Response, StatusCode};
+use tower_http::timeout::TimeoutError;
+
+use crate::config_loader::Host;
+use crate::proxy::{full, ProxyBody};
+use crate::static_files::escape_html;
+
+const DEFAULT_PAGE: &str = "<h1>{{status}} {{reason}}</h1>\n<p>Request ID: {{request_id}}</p>\n";
+
+/// Why a request could not be answered by its upstream.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// The upstream could not be reached (refused, reset, DNS failure...).
+    Connect(hyper_util::client::legacy::Error),
+    /// The upstream was reached but failed before sending a response.
+    Request(hyper_util::client::legacy::Error),
+    /// The upstream did not answer in time.
+    Timeout(String),
+    /// The client was too slow sending the request body.
+    ClientTimeout,
+    /// There is no usable upstream for this request.
+    Unavailable(String),
+}
+
+impl UpstreamError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Connect(_) | Self::Request(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::ClientTimeout => StatusCode::REQUEST_TIMEOUT,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Short name for logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Connect(_) => "connect",
+            Self::Request(_) => "request",
+            Self::Timeout(_) => "timeout",
+            Self::ClientTimeout => "client_timeout",
+            Self::Unavailable(_) => "unavailable",
+        }
+    }
+}
+
+impl From<hyper_util::client::legacy::Error> for UpstreamError {
+    fn from(err: hyper_util::client::legacy::Error) -> Self {
+        if caused_by::<TimeoutError>(&err) {
+            Self::ClientTimeout
+        } else if is_timeout(&err) {
+            Self::Timeout(err.to_string())
+        } else if err.is_connect() {
+            Self::Connect(err)
+        } else {
+            Self::Request(err)
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // The client error's own message is just "client error (Connect)",
+            // the interesting part is further down the chain.
+            Self::Connect(err) | Self::Request(err) => {
+                write!(f, "{err}")?;
+                let mut source = err.source();
+                while let Some(err) = source {
+                    write!(f, ": {err}")?;
+                    source = err.source();
+                }
+                Ok(())
+            }
+            Self::Timeout(msg) | Self::Unavailable(msg) => f.write_str(msg),
+            Self::ClientTimeout => f.write_str("timed out reading the request body"),
+        }
+    }
+}
+
+impl StdError for UpstreamError {}
+
+fn caused_by<E: StdError + 'static>(err: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<E>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+fn is_timeout(err: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<io::Error>() {
+            if io.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Build the error page for `status`, using the host's template when it has one.
+///
+/// Templates may use `{{status}}`, `{{reason}}` and `{{request_id}}`.
+pub async fn render(
+    host: Option<&Host>,
+    status: StatusCode,
+    request_id: &str,
+) -> Response<ProxyBody> {
+    let template = match host.and_then(|h| h.error_pages.get(&status.as_u16())) {
+        Some(path) => match tokio::fs::read_to_string(path).await {
+            Ok(template) => template,
+            Err(e) => {
+                tracing::error!("Could not read error page {path}: {e}");
+                DEFAULT_PAGE.to_owned()
+            }
+        },
+        None => DEFAULT_PAGE.to_owned(),
+    };
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(CACHE_CONTROL, "no-store")
+        .body(full(fill(&template, status, request_id)))
+        .unwrap()
+}
+
+/// Substitute the placeholders in a page template.
+pub fn fill(template: &str, status: StatusCode, request_id: &str) -> String {
+    template
+        .replace("{{status}}", status.as_str())
+        .replace("{{reason}}", status.canonical_reason().unwrap_or_default())
+        .replace("{{request_id}}", &escape_html(request_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_placeholders() {
+        let page = fill(DEFAULT_PAGE, StatusCode::BAD_GATEWAY, "0192a5b4-c3d2-7fff");
+        assert_eq!(
+            page,
+            "<h1>502 Bad Gateway</h1>\n<p>Request ID: 0192a5b4-c3d2-7fff</p>\n"
+        );
+
+        let page = fill("{{request_id}}", StatusCode::OK, "<script>\"x\"");
+        assert_eq!(page, "&lt;script&gt;&quot;x&quot;");
+    }
+}
@@ -0,0 +1,181 @@
+use std::net::IpAddr;
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use once_cell::sync::Lazy;
+
+use crate::HOSTS;
+
+/// What the databases know about a client address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code, e.g. `GB`.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+}
+
+struct Databases {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+/// Opened on first use; a database which cannot be opened is left out.
+static DATABASES: Lazy<Databases> = Lazy::new(|| {
+    let config = HOSTS.load();
+    let geoip = config.global.geoip.as_ref();
+    Databases {
+        country: geoip.and_then(|g| g.country_db.as_deref()).and_then(open),
+        asn: geoip.and_then(|g| g.asn_db.as_deref()).and_then(open),
+    }
+});
+
+fn open(path: &str) -> Option<Reader<Vec<u8>>> {
+    match Reader::open_readfile(path) {
+        Ok(reader) => {
+            tracing::info!(
+                "Loaded GeoIP database {path} ({})",
+                reader.metadata.database_type
+            );
+            Some(reader)
+        }
+        Err(e) => {
+            tracing::error!("Could not load GeoIP database {path}: {e}");
+            None
+        }
+    }
+}
+
+/// Look up `ip`, leaving out anything not configured or not found.
+pub fn lookup(ip: IpAddr) -> Location {
+    DATABASES.lookup(ip)
+}
+
+impl Databases {
+    fn lookup(&self, ip: IpAddr) -> Location {
+        // Clients connecting over IPv4 to a dual stack socket show up as `::ffff:a.b.c.d`
+        let ip = ip.to_canonical();
+        let mut location = Location::default();
+
+        if let Some(record) = self
+            .country
+            .as_ref()
+            .and_then(|db| found(db.lookup::<geoip2::Country>(ip)))
+        {
+            location.country = record
+                .country
+                .or(record.registered_country)
+                .and_then(|c| c.iso_code)
+                .map(str::to_owned);
+        }
+
+        if let Some(record) = self
+            .asn
+            .as_ref()
+            .and_then(|db| found(db.lookup::<geoip2::Asn>(ip)))
+        {
+            location.asn = record.autonomous_system_number;
+            location.as_org = record.autonomous_system_organization.map(str::to_owned);
+        }
+
+        location
+    }
+}
+
+fn found<T>(result: Result<T, MaxMindDBError>) -> Option<T> {
+    match result {
+        Ok(record) => Some(record),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+        Err(e) => {
+            tracing::debug!("GeoIP lookup failed: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access;
+    use crate::config_loader::Access;
+
+    /// Holds country and ASN records for:
+    ///   81.2.69.0/24    GB, AS20712
+    ///   2.125.160.0/19  GB, no ASN
+    ///   5.8.16.0/21     RU, AS34665
+    ///   89.160.20.0/24  SE, AS29518
+    const FIXTURE: &str = "res/test/geoip.mmdb";
+
+    fn fixture() -> Databases {
+        Databases {
+            country: open(FIXTURE),
+            asn: open(FIXTURE),
+        }
+    }
+
+    #[test]
+    fn finds_country_and_asn() {
+        let location = fixture().lookup("81.2.69.160".parse().unwrap());
+        assert_eq!(location.country.as_deref(), Some("GB"));
+        assert_eq!(location.asn, Some(20712));
+        assert_eq!(location.as_org.as_deref(), Some("Andrews & Arnold Ltd"));
+    }
+
+    #[test]
+    fn country_without_asn() {
+        let location = fixture().lookup("2.125.170.1".parse().unwrap());
+        assert_eq!(location.country.as_deref(), Some("GB"));
+        assert_eq!(location.asn, None);
+    }
+
+    #[test]
+    fn mapped_ipv4() {
+        let location = fixture().lookup("::ffff:89.160.20.112".parse().unwrap());
+        assert_eq!(location.country.as_deref(), Some("SE"));
+    }
+
+    #[test]
+    fn unknown_address() {
+        assert_eq!(
+            fixture().lookup("192.0.2.1".parse().unwrap()),
+            Location::default()
+        );
+    }
+
+    #[test]
+    fn missing_database() {
+        let databases = Databases {
+            country: open("res/test/missing.mmdb"),
+            asn: None,
+        };
+        assert_eq!(
+            databases.lookup("81.2.69.160".parse().unwrap()),
+            Location::default()
+        );
+    }
+
+    #[test]
+    fn country_rules() {
+        let databases = fixture();
+        let check = |access: &Access, ip: &str| {
+            let ip = ip.parse().unwrap();
+            access::allowed(access, ip, databases.lookup(ip).country.as_deref())
+        };
+
+        let deny = Access {
+            deny_countries: vec!["ru".into()],
+            ..Default::default()
+        };
+        assert!(!check(&deny, "5.8.16.1"));
+        assert!(check(&deny, "81.2.69.160"));
+        assert!(check(&deny, "192.0.2.1"));
+
+        // Addresses with no known country are refused by an allow list
+        let allow = Access {
+            allow_countries: vec!["GB".into()],
+            ..Default::default()
+        };
+        assert!(check(&allow, "81.2.69.160"));
+        assert!(!check(&allow, "89.160.20.112"));
+        assert!(!check(&allow, "192.0.2.1"));
+    }
+}
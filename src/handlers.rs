@@ -0,0 +1,125 @@
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Response, StatusCode, Uri};
+
+use crate::config_loader::{Redirect, Respond};
+use crate::error_pages;
+use crate::proxy::{full, ProxyBody};
+
+pub fn not_found() -> Response<ProxyBody> {
+    tracing::info!("404 hit");
+
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(full(
+            "<h1>You've hit 404, this host and/or address leads to nowhere...</h1>",
+        ))
+        .unwrap()
+}
+
+pub async fn respond(respond: &Respond, request_id: &str) -> Response<ProxyBody> {
+    let status = StatusCode::from_u16(respond.status).unwrap_or(StatusCode::OK);
+
+    let body = match &respond.file {
+        Some(path) => match tokio::fs::read_to_string(path).await {
+            Ok(template) => error_pages::fill(&template, status, request_id),
+            Err(e) => {
+                tracing::error!("Could not read {path}: {e}");
+                respond.body.clone()
+            }
+        },
+        None => respond.body.clone(),
+    };
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, &respond.content_type)
+        .body(full(body))
+        .unwrap()
+}
+
+pub fn redirect(redirect: &Redirect, uri: &Uri) -> Response<ProxyBody> {
+    let location = match uri.path_and_query() {
+        Some(path) if redirect.keep_path => {
+            format!("{}{path}", redirect.to.trim_end_matches('/'))
+        }
+        _ => redirect.to.clone(),
+    };
+
+    Response::builder()
+        .status(StatusCode::from_u16(redirect.status).unwrap_or(StatusCode::MOVED_PERMANENTLY))
+        .header(LOCATION, location)
+        .body(full(""))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    async fn body(res: Response<ProxyBody>) -> String {
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn config<T: serde::de::DeserializeOwned>(value: serde_json::Value) -> T {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn responds_with_body() {
+        let res = respond(&config(serde_json::json!({ "body": "ok" })), "id").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(body(res).await, "ok");
+
+        let gone = config(serde_json::json!({ "status": 410, "content_type": "text/plain" }));
+        let res = respond(&gone, "id").await;
+        assert_eq!(res.status(), StatusCode::GONE);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+    }
+
+    #[tokio::test]
+    async fn responds_with_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("maintenance.html");
+        std::fs::write(&file, "{{status}} {{request_id}}").unwrap();
+
+        let respond_config = config(serde_json::json!({
+            "status": 503,
+            "body": "fallback",
+            "file": file,
+        }));
+        let res = respond(&respond_config, "abc").await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(res).await, "503 abc");
+
+        // The body when the file cannot be read
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(
+            body(respond(&respond_config, "abc").await).await,
+            "fallback"
+        );
+    }
+
+    #[test]
+    fn redirects_keeping_path() {
+        let uri = "/some/page?q=1".parse().unwrap();
+
+        let keep = config(serde_json::json!({ "to": "https://b.test/" }));
+        let res = redirect(&keep, &uri);
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()[LOCATION], "https://b.test/some/page?q=1");
+
+        let fixed = config(serde_json::json!({
+            "to": "https://b.test/",
+            "status": 302,
+            "keep_path": false,
+        }));
+        let res = redirect(&fixed, &uri);
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()[LOCATION], "https://b.test/");
+    }
+}
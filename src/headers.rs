@@ -0,0 +1,310 @@
+use hyper::header::{
+    Entry, HeaderMap, HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, SERVER,
+    STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+
+use crate::config_loader::{HeaderRules, Headers, SecurityHeaders};
+use crate::proxy::ClientInfo;
+
+const X_POWERED_BY: &str = "x-powered-by";
+
+const PLACEHOLDERS: &[&str] = &[
+    "client_ip",
+    "host",
+    "request_id",
+    "tls_version",
+    "country",
+    "client_cert_subject",
+    "client_cert_fingerprint",
+];
+
+/// What the placeholders in header values are filled with.
+pub struct Context<'a> {
+    pub client: Option<&'a ClientInfo>,
+    /// The configured host name.
+    pub host: &'a str,
+    pub request_id: &'a str,
+}
+
+/// Change the headers of a request about to be sent upstream.
+pub fn request(config: &Headers, headers: &mut HeaderMap, context: &Context) {
+    rewrite(&config.request, headers, context);
+}
+
+/// Change the headers of a response about to be sent to the client. The rules
+/// come after the security headers, so they can change those too.
+pub fn response(config: &Headers, headers: &mut HeaderMap, context: &Context) {
+    if let Some(security) = &config.security {
+        secure(security, headers);
+    }
+    rewrite(&config.response, headers, context);
+}
+
+/// Problems with the header names and placeholders in `config`.
+pub fn check(config: &Headers) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (side, rules) in [("request", &config.request), ("response", &config.response)] {
+        let names = rules
+            .remove
+            .iter()
+            .chain(rules.rename.keys())
+            .chain(rules.rename.values())
+            .chain(rules.set.keys())
+            .chain(rules.add.keys());
+        for name in names {
+            if HeaderName::try_from(name.as_str()).is_err() {
+                problems.push(format!("{side} header {name:?} is not a valid name"));
+            }
+        }
+
+        for template in rules.set.values().chain(rules.add.values()) {
+            for placeholder in placeholders(template) {
+                if !PLACEHOLDERS.contains(&placeholder) {
+                    problems.push(format!(
+                        "{side} header value {template:?} has unknown placeholder {{{{{placeholder}}}}}"
+                    ));
+                }
+            }
+        }
+    }
+    problems
+}
+
+fn rewrite(rules: &HeaderRules, headers: &mut HeaderMap, context: &Context) {
+    for name in &rules.remove {
+        if let Some(name) = header_name(name) {
+            headers.remove(name);
+        }
+    }
+
+    for (from, to) in &rules.rename {
+        let (Some(from), Some(to)) = (header_name(from), header_name(to)) else {
+            continue;
+        };
+        let values = match headers.entry(from) {
+            Entry::Occupied(entry) => entry.remove_entry_mult().1.collect(),
+            Entry::Vacant(_) => Vec::new(),
+        };
+        for value in values {
+            headers.append(&to, value);
+        }
+    }
+
+    for (name, template) in &rules.set {
+        // Whatever the client sent is gone even when there is nothing to put in
+        // its place, so it cannot stand in for a value we would have set
+        if let Some(name) = header_name(name) {
+            headers.remove(name);
+        }
+        if let Some((name, value)) = header(name, template, context) {
+            headers.insert(name, value);
+        }
+    }
+
+    for (name, template) in &rules.add {
+        if let Some((name, value)) = header(name, template, context) {
+            headers.append(name, value);
+        }
+    }
+}
+
+fn secure(security: &SecurityHeaders, headers: &mut HeaderMap) {
+    let wanted = [
+        (STRICT_TRANSPORT_SECURITY, security.hsts.as_deref()),
+        (
+            CONTENT_SECURITY_POLICY,
+            security.content_security_policy.as_deref(),
+        ),
+        (X_FRAME_OPTIONS, security.frame_options.as_deref()),
+        (REFERRER_POLICY, security.referrer_policy.as_deref()),
+        (
+            X_CONTENT_TYPE_OPTIONS,
+            security.nosniff.then_some("nosniff"),
+        ),
+    ];
+    for (name, value) in wanted {
+        let Some(value) = value.and_then(|v| HeaderValue::from_str(v).ok()) else {
+            continue;
+        };
+        // What the upstream chose for itself is kept
+        headers.entry(name).or_insert(value);
+    }
+
+    if security.hide_backend {
+        headers.remove(SERVER);
+        headers.remove(X_POWERED_BY);
+    }
+}
+
+fn header_name(name: &str) -> Option<HeaderName> {
+    let parsed = HeaderName::try_from(name).ok();
+    if parsed.is_none() {
+        tracing::warn!("Invalid header name {name:?}");
+    }
+    parsed
+}
+
+/// The header to add, `None` when the value came out empty, such as a
+/// certificate's subject when the client sent none.
+fn header(name: &str, template: &str, context: &Context) -> Option<(HeaderName, HeaderValue)> {
+    let name = header_name(name)?;
+    let value = fill(template, context);
+    if value.is_empty() {
+        return None;
+    }
+    match HeaderValue::from_str(&value) {
+        Ok(value) => Some((name, value)),
+        Err(_) => {
+            tracing::warn!("Invalid value {value:?} for header {name}");
+            None
+        }
+    }
+}
+
+/// Fill in `{{client_ip}}`, `{{host}}`, `{{request_id}}`, `{{tls_version}}`,
+/// `{{country}}`, `{{client_cert_subject}}` and `{{client_cert_fingerprint}}`.
+/// Those not known for this request are left empty.
+fn fill(template: &str, context: &Context) -> String {
+    if !template.contains("{{") {
+        return template.to_owned();
+    }
+
+    let client = context.client;
+    // Only a certificate verified for this host is passed on, as with the
+    // certificate headers
+    let cert = client
+        .and_then(|c| c.cert.as_deref())
+        .filter(|cert| cert.verified_for(context.host));
+
+    template
+        .replace(
+            "{{client_ip}}",
+            &client.map(|c| c.addr.ip().to_string()).unwrap_or_default(),
+        )
+        .replace("{{host}}", context.host)
+        .replace("{{request_id}}", context.request_id)
+        .replace(
+            "{{tls_version}}",
+            client.and_then(|c| c.tls_version).unwrap_or_default(),
+        )
+        .replace(
+            "{{country}}",
+            client
+                .and_then(|c| c.location.country.as_deref())
+                .unwrap_or_default(),
+        )
+        .replace(
+            "{{client_cert_subject}}",
+            cert.map_or("", |c| c.subject.as_str()),
+        )
+        .replace(
+            "{{client_cert_fingerprint}}",
+            cert.map_or("", |c| c.fingerprint.as_str()),
+        )
+}
+
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split("{{")
+        .skip(1)
+        .filter_map(|rest| rest.split_once("}}").map(|(name, _)| name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geoip::Location;
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            addr: "203.0.113.7:50000".parse().unwrap(),
+            location: Location {
+                country: Some("GB".into()),
+                ..Default::default()
+            },
+            tls_version: Some("TLSv1.3"),
+            cert: None,
+        }
+    }
+
+    #[test]
+    fn rules_applied_in_order() {
+        let config: Headers = serde_json::from_str(
+            r#"{
+                "request": {
+                    "remove": ["x-debug"],
+                    "rename": { "x-old": "x-new" },
+                    "set": { "x-real-ip": "{{client_ip}}", "x-cert": "{{client_cert_subject}}" },
+                    "add": { "x-new": "{{country}} via {{host}} ({{request_id}})" }
+                }
+            }"#,
+        )
+        .unwrap();
+        let client = client();
+        let context = Context {
+            client: Some(&client),
+            host: "a.test",
+            request_id: "abc",
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-debug", HeaderValue::from_static("1"));
+        headers.insert("x-old", HeaderValue::from_static("kept"));
+        headers.insert("x-real-ip", HeaderValue::from_static("spoofed"));
+        headers.insert("x-cert", HeaderValue::from_static("CN=admin"));
+        request(&config, &mut headers, &context);
+
+        assert!(!headers.contains_key("x-debug"));
+        assert!(!headers.contains_key("x-old"));
+        let new = headers.get_all("x-new").iter().collect::<Vec<_>>();
+        assert_eq!(new, ["kept", "GB via a.test (abc)"]);
+        assert_eq!(headers["x-real-ip"], "203.0.113.7");
+        // No certificate, so nothing to set, nor is the client's own kept
+        assert!(!headers.contains_key("x-cert"));
+    }
+
+    #[test]
+    fn security_headers() {
+        let config: Headers = serde_json::from_str(
+            r#"{
+                "security": { "frame_options": null },
+                "response": { "set": { "referrer-policy": "no-referrer" } }
+            }"#,
+        )
+        .unwrap();
+        let context = Context {
+            client: None,
+            host: "a.test",
+            request_id: "abc",
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(SERVER, HeaderValue::from_static("Kestrel"));
+        headers.insert(X_POWERED_BY, HeaderValue::from_static("ASP.NET"));
+        headers.insert(
+            STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_static("max-age=60"),
+        );
+        response(&config, &mut headers, &context);
+
+        assert!(!headers.contains_key(SERVER));
+        assert!(!headers.contains_key(X_POWERED_BY));
+        assert_eq!(headers[STRICT_TRANSPORT_SECURITY], "max-age=60");
+        assert!(!headers.contains_key(X_FRAME_OPTIONS));
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[REFERRER_POLICY], "no-referrer");
+    }
+
+    #[test]
+    fn problems_found() {
+        let config: Headers = serde_json::from_str(
+            r#"{ "response": { "set": { "bad name": "x", "x-ok": "{{client_ip}} {{nope}}" } } }"#,
+        )
+        .unwrap();
+        let problems = check(&config);
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems.iter().any(|p| p.contains("\"bad name\"")));
+        assert!(problems.iter().any(|p| p.contains("{{nope}}")));
+    }
+}
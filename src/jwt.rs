@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use hyper::{HeaderMap, Method, Request};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Header, Validation};
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::auth::Denied;
+use crate::config_loader::JwtAuth;
+use crate::proxy::full;
+use crate::upstream;
+
+/// Key sets from URLs are fetched again after this long.
+const URL_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Key sets from files are read again after this long.
+const FILE_MAX_AGE: Duration = Duration::from_secs(10);
+
+/// A token signed with a key we do not have only causes a fetch this often,
+/// to pick up rotated keys without letting clients hammer the issuer.
+const MIN_REFETCH: Duration = Duration::from_secs(30);
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Url(&'a str),
+    File(&'a str),
+}
+
+impl Source<'_> {
+    fn location(&self) -> &str {
+        match self {
+            Source::Url(location) | Source::File(location) => location,
+        }
+    }
+
+    fn max_age(&self) -> Duration {
+        match self {
+            Source::Url(_) => URL_MAX_AGE,
+            Source::File(_) => FILE_MAX_AGE,
+        }
+    }
+}
+
+struct Fetched {
+    keys: JwkSet,
+    at: Instant,
+}
+
+/// Keyed by URL or path.
+static KEY_SETS: Lazy<DashMap<String, Arc<Fetched>>> = Lazy::new(DashMap::new);
+
+/// What a token has to match besides its signature.
+pub struct Expected<'a> {
+    pub issuer: Option<&'a str>,
+    pub audience: Option<&'a str>,
+}
+
+/// Let through requests with a valid bearer token, setting headers from its claims.
+pub async fn authenticate<B>(config: &JwtAuth, req: &mut Request<B>) -> Result<(), Denied> {
+    let challenge = |error: Option<&str>| {
+        let realm = config.realm.replace(['"', '\\'], "");
+        let challenge = match error {
+            Some(error) => format!("Bearer realm=\"{realm}\", error=\"{error}\""),
+            None => format!("Bearer realm=\"{realm}\""),
+        };
+        HeaderValue::from_str(&challenge).unwrap_or_else(|_| HeaderValue::from_static("Bearer"))
+    };
+
+    let Some(token) = bearer(req.headers()) else {
+        return Err(Denied::Unauthorized(challenge(None)));
+    };
+
+    let claims = match validate_bearer(config, token).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            tracing::info!("Refused bearer token: {e}");
+            return Err(Denied::Unauthorized(challenge(Some("invalid_token"))));
+        }
+    };
+
+    if let Some((missing, _)) = config
+        .required_claims
+        .iter()
+        .find(|(name, wanted)| !has_claim(&claims, name, wanted))
+    {
+        tracing::info!("Refused bearer token without claim {missing:?}");
+        return Err(Denied::Unauthorized(challenge(Some("insufficient_scope"))));
+    }
+
+    set_claim_headers(req.headers_mut(), &config.forward_claims, |claim| {
+        claims.get(claim).and_then(claim_value)
+    });
+    Ok(())
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let header = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = header.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+async fn validate_bearer(config: &JwtAuth, token: &str) -> Result<HashMap<String, Value>, String> {
+    let header = decode_header(token).map_err(|e| format!("invalid token: {e}"))?;
+    let expected = Expected {
+        issuer: config.issuer.as_deref(),
+        audience: config.audience.as_deref(),
+    };
+
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        let secret = config.secret.as_ref().ok_or("no secret for HMAC tokens")?;
+        let key = DecodingKey::from_secret(secret.as_bytes());
+        return decode(token, &key, &validation(header.alg, &expected))
+            .map(|data| data.claims)
+            .map_err(|e| format!("invalid token: {e}"));
+    }
+
+    if let Some(jwk) = find(&config.keys, header.kid.as_deref()) {
+        return validate(token, jwk, &expected);
+    }
+
+    let sources = [
+        config.jwks_file.as_deref().map(Source::File),
+        config.jwks_url.as_deref().map(Source::Url),
+    ];
+    for source in sources.into_iter().flatten() {
+        if let Some(claims) = with_key_set(token, &header, source, &expected).await? {
+            return Ok(claims);
+        }
+    }
+
+    Err(format!("no key {:?}", header.kid))
+}
+
+/// Whether `claims` has `name` with the `wanted` value, see `JwtAuth::required_claims`.
+fn has_claim(claims: &HashMap<String, Value>, name: &str, wanted: &Value) -> bool {
+    let Some(value) = claims.get(name) else {
+        return false;
+    };
+
+    match (wanted, value) {
+        (Value::Null, _) => true,
+        // OAuth scopes are one space separated string
+        (Value::String(wanted), Value::String(value)) if name == "scope" || name == "scp" => {
+            value.split(' ').any(|scope| scope == wanted)
+        }
+        (wanted, Value::Array(values)) => values.contains(wanted),
+        (wanted, value) => wanted == value,
+    }
+}
+
+/// Validate `token` with a key from the JWKS at `url`, returning its claims.
+pub async fn validate_with_jwks<T: DeserializeOwned>(
+    token: &str,
+    url: &str,
+    expected: &Expected<'_>,
+) -> Result<T, String> {
+    let header = decode_header(token).map_err(|e| format!("invalid token: {e}"))?;
+
+    with_key_set(token, &header, Source::Url(url), expected)
+        .await?
+        .ok_or_else(|| format!("no key {:?} in {url}", header.kid))
+}
+
+/// `None` if the set has no key for the token.
+async fn with_key_set<T: DeserializeOwned>(
+    token: &str,
+    header: &Header,
+    source: Source<'_>,
+    expected: &Expected<'_>,
+) -> Result<Option<T>, String> {
+    let mut keys = key_set(source, false).await?;
+    let mut jwk = find(&keys.keys.keys, header.kid.as_deref());
+
+    if jwk.is_none() && keys.at.elapsed() >= MIN_REFETCH {
+        keys = key_set(source, true).await?;
+        jwk = find(&keys.keys.keys, header.kid.as_deref());
+    }
+
+    jwk.map(|jwk| validate(token, jwk, expected)).transpose()
+}
+
+/// Without a key ID the token has to be for the only key there is.
+fn find<'a>(keys: &'a [Jwk], kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => keys
+            .iter()
+            .find(|jwk| jwk.common.key_id.as_deref() == Some(kid)),
+        None if keys.len() == 1 => keys.first(),
+        None => None,
+    }
+}
+
+pub fn validate<T: DeserializeOwned>(
+    token: &str,
+    jwk: &Jwk,
+    expected: &Expected<'_>,
+) -> Result<T, String> {
+    let header = decode_header(token).map_err(|e| format!("invalid token: {e}"))?;
+
+    // A key which names its algorithm can only be used with that one
+    if let Some(alg) = jwk.common.key_algorithm {
+        if format!("{alg:?}") != format!("{:?}", header.alg) {
+            return Err(format!("key is for {alg:?}, token uses {:?}", header.alg));
+        }
+    }
+
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("unusable key: {e}"))?;
+    decode(token, &key, &validation(header.alg, expected))
+        .map(|data| data.claims)
+        .map_err(|e| match e.kind() {
+            ErrorKind::InvalidAlgorithm => format!("key cannot be used with {:?}", header.alg),
+            _ => format!("invalid token: {e}"),
+        })
+}
+
+/// `decode` refuses keys of a different family to `alg`, so a token cannot
+/// pick an algorithm which turns a public key into an HMAC secret.
+pub fn validation(alg: Algorithm, expected: &Expected<'_>) -> Validation {
+    let mut validation = Validation::new(alg);
+    validation.leeway = 30;
+    validation.validate_nbf = true;
+    validation.set_required_spec_claims(&["exp"]);
+    match expected.audience {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => validation.validate_aud = false,
+    }
+    if let Some(issuer) = expected.issuer {
+        validation.set_issuer(&[issuer]);
+    }
+    validation
+}
+
+async fn key_set(source: Source<'_>, refresh: bool) -> Result<Arc<Fetched>, String> {
+    let location = source.location();
+
+    if let Some(keys) = KEY_SETS.get(location) {
+        if !refresh && keys.at.elapsed() < source.max_age() {
+            return Ok(keys.clone());
+        }
+    }
+
+    let keys = match source {
+        Source::Url(url) => fetch_json::<JwkSet>(url).await,
+        Source::File(path) => match tokio::fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| format!("invalid JWKS: {e}")),
+            Err(e) => Err(e.to_string()),
+        },
+    };
+
+    let fetched = match keys {
+        Ok(keys) => Arc::new(Fetched {
+            keys,
+            at: Instant::now(),
+        }),
+        // Better to keep using old keys than to refuse everyone
+        Err(e) => match KEY_SETS.get(location) {
+            Some(keys) => {
+                tracing::warn!("Could not refresh keys from {location}: {e}");
+                return Ok(keys.clone());
+            }
+            None => return Err(format!("could not load keys from {location}: {e}")),
+        },
+    };
+
+    if let Source::Url(_) = source {
+        tracing::info!("Fetched {} key(s) from {location}", fetched.keys.keys.len());
+    }
+    KEY_SETS.insert(location.to_owned(), fetched.clone());
+    Ok(fetched)
+}
+
+pub async fn fetch_json<T: DeserializeOwned>(url: &str) -> Result<T, String> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(url)
+        .header("accept", "application/json")
+        .body(full(""))
+        .map_err(|e| format!("invalid url: {e}"))?;
+
+    let (status, body) = upstream::fetch(req, FETCH_TIMEOUT)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("status {status}"));
+    }
+
+    serde_json::from_slice(&body).map_err(|e| format!("invalid response: {e}"))
+}
+
+/// Set headers from claims, header to claim, replacing any sent by the client.
+pub fn set_claim_headers(
+    headers: &mut HeaderMap,
+    mapping: &HashMap<String, String>,
+    claim: impl Fn(&str) -> Option<String>,
+) {
+    for (header, name) in mapping {
+        let Ok(header) = HeaderName::try_from(header.as_str()) else {
+            tracing::warn!("Invalid header name {header:?} for claim {name:?}");
+            continue;
+        };
+
+        headers.remove(&header);
+        if let Some(value) = claim(name).and_then(|v| HeaderValue::from_str(&v).ok()) {
+            headers.insert(header, value);
+        }
+    }
+}
+
+/// A claim as a header value, lists joined with commas.
+pub fn claim_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        Value::Array(values) => Some(
+            values
+                .iter()
+                .filter_map(claim_value)
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        Value::Null | Value::Object(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use jsonwebtoken::{encode, EncodingKey};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const JWKS: &str = "res/test/jwt/jwks.json";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(alg: Algorithm, claims: Value) -> String {
+        let mut header = Header::new(alg);
+        let key = match alg {
+            Algorithm::HS256 => EncodingKey::from_secret(b"hunter2"),
+            Algorithm::RS256 => {
+                EncodingKey::from_rsa_pem(include_bytes!("../res/test/jwt/rs256.pem")).unwrap()
+            }
+            Algorithm::ES256 => {
+                EncodingKey::from_ec_pem(include_bytes!("../res/test/jwt/es256.pem")).unwrap()
+            }
+            Algorithm::EdDSA => {
+                EncodingKey::from_ed_pem(include_bytes!("../res/test/jwt/ed25519.pem")).unwrap()
+            }
+            _ => unreachable!(),
+        };
+        if alg != Algorithm::HS256 {
+            header.kid = Some(
+                format!("{alg:?}")
+                    .to_lowercase()
+                    .replace("eddsa", "ed25519"),
+            );
+        }
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": "https://auth.test",
+            "aud": "api",
+            "sub": "alice",
+            "scope": "read:media write:media",
+            "roles": ["admin", "user"],
+            "exp": now() + 300,
+        })
+    }
+
+    fn config() -> JwtAuth {
+        JwtAuth {
+            secret: Some("hunter2".into()),
+            keys: Vec::new(),
+            jwks_file: Some(JWKS.into()),
+            jwks_url: None,
+            issuer: Some("https://auth.test".into()),
+            audience: Some("api".into()),
+            required_claims: HashMap::new(),
+            forward_claims: HashMap::from([
+                ("X-User".into(), "sub".into()),
+                ("X-Roles".into(), "roles".into()),
+            ]),
+            realm: "api".into(),
+        }
+    }
+
+    fn request(token: &str) -> Request<()> {
+        Request::builder()
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .header("x-user", "mallory")
+            .body(())
+            .unwrap()
+    }
+
+    /// The `error` in the challenge, `None` if there is a challenge without one.
+    async fn refused(config: &JwtAuth, req: &mut Request<()>) -> Option<String> {
+        match authenticate(config, req).await {
+            Err(Denied::Unauthorized(challenge)) => {
+                let challenge = challenge.to_str().unwrap();
+                assert!(challenge.starts_with("Bearer realm=\"api\""));
+                challenge
+                    .split_once("error=")
+                    .map(|(_, error)| error.trim_matches('"').to_owned())
+            }
+            Ok(()) => panic!("token was accepted"),
+            Err(_) => panic!("expected a challenge"),
+        }
+    }
+
+    #[tokio::test]
+    async fn algorithms() {
+        let config = config();
+        for alg in [
+            Algorithm::HS256,
+            Algorithm::RS256,
+            Algorithm::ES256,
+            Algorithm::EdDSA,
+        ] {
+            let mut req = request(&sign(alg, claims()));
+            assert!(authenticate(&config, &mut req).await.is_ok(), "{alg:?}");
+            assert_eq!(req.headers()["x-user"], "alice");
+            assert_eq!(req.headers()["x-roles"], "admin,user");
+        }
+    }
+
+    #[tokio::test]
+    async fn inline_keys() {
+        let jwks: JwkSet = serde_json::from_str(&std::fs::read_to_string(JWKS).unwrap()).unwrap();
+        let config = JwtAuth {
+            secret: None,
+            keys: jwks.keys,
+            jwks_file: None,
+            ..config()
+        };
+
+        let mut req = request(&sign(Algorithm::ES256, claims()));
+        assert!(authenticate(&config, &mut req).await.is_ok());
+
+        // No secret for HMAC
+        let mut req = request(&sign(Algorithm::HS256, claims()));
+        assert_eq!(
+            refused(&config, &mut req).await.as_deref(),
+            Some("invalid_token")
+        );
+    }
+
+    #[tokio::test]
+    async fn jwks_url() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/jwks", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(full(std::fs::read(JWKS).unwrap())))
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        let config = JwtAuth {
+            jwks_file: None,
+            jwks_url: Some(url),
+            ..config()
+        };
+        let mut req = request(&sign(Algorithm::RS256, claims()));
+        assert!(authenticate(&config, &mut req).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn missing_or_malformed() {
+        let config = config();
+
+        let mut req = Request::new(());
+        assert_eq!(refused(&config, &mut req).await, None);
+
+        let mut req = request("not.a.jwt");
+        assert_eq!(
+            refused(&config, &mut req).await.as_deref(),
+            Some("invalid_token")
+        );
+
+        // Signed with the wrong key
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = None;
+        let forged = encode(&header, &claims(), &EncodingKey::from_secret(b"guess")).unwrap();
+        let mut req = request(&forged);
+        assert_eq!(
+            refused(&config, &mut req).await.as_deref(),
+            Some("invalid_token")
+        );
+        assert_eq!(req.headers()["x-user"], "mallory");
+    }
+
+    #[tokio::test]
+    async fn checks_standard_claims() {
+        let config = config();
+        let with = |changes: Value| {
+            let mut claims = claims();
+            for (name, value) in changes.as_object().unwrap() {
+                match value {
+                    Value::Null => claims.as_object_mut().unwrap().remove(name),
+                    value => claims
+                        .as_object_mut()
+                        .unwrap()
+                        .insert(name.clone(), value.clone()),
+                };
+            }
+            request(&sign(Algorithm::EdDSA, claims))
+        };
+
+        for changes in [
+            json!({ "exp": now() - 300 }),
+            json!({ "exp": null }),
+            json!({ "nbf": now() + 300 }),
+            json!({ "aud": "other" }),
+            json!({ "iss": "https://evil.test" }),
+        ] {
+            let mut req = with(changes.clone());
+            assert_eq!(
+                refused(&config, &mut req).await.as_deref(),
+                Some("invalid_token"),
+                "{changes}"
+            );
+        }
+
+        let mut req = with(json!({ "nbf": now() - 10 }));
+        assert!(authenticate(&config, &mut req).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn required_claims() {
+        let mut config = config();
+        config.required_claims = HashMap::from([
+            ("sub".into(), Value::Null),
+            ("scope".into(), json!("write:media")),
+            ("roles".into(), json!("admin")),
+        ]);
+
+        let mut req = request(&sign(Algorithm::RS256, claims()));
+        assert!(authenticate(&config, &mut req).await.is_ok());
+
+        config
+            .required_claims
+            .insert("scope".into(), json!("write"));
+        let mut req = request(&sign(Algorithm::RS256, claims()));
+        assert_eq!(
+            refused(&config, &mut req).await.as_deref(),
+            Some("insufficient_scope")
+        );
+
+        config.required_claims = HashMap::from([("tenant".into(), Value::Null)]);
+        let mut req = request(&sign(Algorithm::RS256, claims()));
+        assert_eq!(
+            refused(&config, &mut req).await.as_deref(),
+            Some("insufficient_scope")
+        );
+    }
+
+    #[tokio::test]
+    async fn key_confusion() {
+        // An HMAC token "signed" with the RSA public key must not pass
+        let jwks: JwkSet = serde_json::from_str(&std::fs::read_to_string(JWKS).unwrap()).unwrap();
+        let Some(jwk) = jwks.find("rs256") else {
+            panic!("fixture has an RS256 key");
+        };
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("rs256".into());
+        let public = serde_json::to_vec(jwk).unwrap();
+        let token = encode(&header, &claims(), &EncodingKey::from_secret(&public)).unwrap();
+
+        let expected = Expected {
+            issuer: None,
+            audience: None,
+        };
+        assert!(validate::<Value>(&token, jwk, &expected).is_err());
+
+        // And a key only takes tokens for its own algorithm
+        let token = sign(Algorithm::ES256, claims());
+        assert!(validate::<Value>(&token, jwk, &expected).is_err());
+    }
+}
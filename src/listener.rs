@@ -0,0 +1,476 @@
+use std::env;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use nix::sys::socket::{
+    getsockopt, recvmsg, sendmsg, sockopt, ControlMessage, ControlMessageOwned, MsgFlags, SockType,
+};
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+
+use crate::config_loader::Config;
+use crate::rate_limit::{self, ConnectionPermit};
+use crate::{access, geoip, shutdown, HOSTS};
+
+/// First file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+const MAX_HANDOFF_FDS: usize = 32;
+
+/// Listening sockets passed to us at startup, waiting for `bind` to claim them.
+static INHERITED: Lazy<Mutex<Vec<std::net::TcpListener>>> = Lazy::new(Default::default);
+
+/// Everything we are listening on, so it can be handed to a newer process.
+static BOUND: Lazy<Mutex<Vec<OwnedFd>>> = Lazy::new(Default::default);
+
+/// Pick up the listening sockets `systemd` passed, or failing that those of a
+/// running Envoi on `upgrade_socket`. Must run before anything is bound.
+pub async fn inherit(systemd: Vec<OwnedFd>, upgrade_socket: Option<&str>) {
+    let mut listeners = systemd_listeners(systemd);
+
+    if listeners.is_empty() {
+        if let Some(path) = upgrade_socket {
+            match from_running(path).await {
+                Ok(received) => {
+                    tracing::info!(
+                        "Took over {} listener(s) from the running process",
+                        received.len()
+                    );
+                    listeners = received;
+                }
+                // Nothing running, this is a normal start
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                    ) => {}
+                Err(e) => tracing::error!("Could not take over listeners via {path}: {e}"),
+            }
+        }
+    }
+
+    INHERITED.lock().unwrap().extend(listeners);
+}
+
+/// The descriptors passed by systemd socket activation (`LISTEN_FDS`). Changes
+/// the environment, so has to run before the runtime starts any threads.
+pub fn from_systemd() -> Vec<OwnedFd> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+
+    if !for_us {
+        return Vec::new();
+    }
+
+    // So that anything we spawn does not try to use them as well
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        // SAFETY: systemd passes us ownership of these descriptors
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect()
+}
+
+fn systemd_listeners(fds: Vec<OwnedFd>) -> Vec<std::net::TcpListener> {
+    fds.into_iter()
+        .filter_map(|fd| {
+            let raw = fd.as_raw_fd();
+            match tcp_listener(fd) {
+                Ok((listener, addr)) => {
+                    tracing::info!("Received {addr} from systemd");
+                    Some(listener)
+                }
+                Err(e) => {
+                    tracing::warn!("Ignoring socket {raw} from systemd: {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// `fd` as a TCP listener, if that is what it is. Whatever else a unit passes
+/// along, such as a datagram or Unix socket, is refused.
+fn tcp_listener(fd: OwnedFd) -> io::Result<(std::net::TcpListener, SocketAddr)> {
+    if getsockopt(&fd, sockopt::SockType)? != SockType::Stream
+        || !getsockopt(&fd, sockopt::AcceptConn)?
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a listening stream socket",
+        ));
+    }
+
+    let listener = std::net::TcpListener::from(fd);
+    // Unix sockets have no address of this kind
+    let addr = listener.local_addr()?;
+    Ok((listener, addr))
+}
+
+async fn from_running(path: &str) -> io::Result<Vec<std::net::TcpListener>> {
+    let stream = tokio::net::UnixStream::connect(path).await?.into_std()?;
+    stream.set_nonblocking(false)?;
+
+    tokio::task::spawn_blocking(move || receive_listeners(stream))
+        .await
+        .map_err(io::Error::other)?
+}
+
+fn receive_listeners(mut stream: UnixStream) -> io::Result<Vec<std::net::TcpListener>> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let mut buf = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg = nix::cmsg_space!([RawFd; MAX_HANDOFF_FDS]);
+
+    let msg = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+
+    let mut listeners = Vec::new();
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            for fd in fds {
+                // SAFETY: the descriptors were just created for us by the kernel
+                let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+                // Dropping the others closes them
+                match tcp_listener(owned) {
+                    Ok((listener, _)) => listeners.push(listener),
+                    Err(e) => tracing::warn!("Ignoring socket {fd} from the running process: {e}"),
+                }
+            }
+        }
+    }
+
+    // Tell the old process it can stop accepting
+    stream.write_all(b"ok")?;
+
+    Ok(listeners)
+}
+
+/// Bind `addr`, reusing an inherited socket for it if there is one.
+pub async fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let inherited = {
+        let mut inherited = INHERITED.lock().unwrap();
+        inherited
+            .iter()
+            .position(|l| l.local_addr().is_ok_and(|a| a == addr))
+            .map(|i| inherited.swap_remove(i))
+    };
+
+    let listener = match inherited {
+        Some(listener) => {
+            tracing::info!("Using inherited listener for {addr}");
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => TcpListener::bind(addr).await?,
+    };
+
+    BOUND
+        .lock()
+        .unwrap()
+        .push(listener.as_fd().try_clone_to_owned()?);
+
+    Ok(listener)
+}
+
+/// Wait for a newer Envoi to connect on `path`, hand it our listening sockets
+/// and then start draining.
+pub async fn serve_handoff(path: String) {
+    // Either stale, or left by the process we took over from
+    _ = std::fs::remove_file(&path);
+
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Could not listen for upgrades on {path}: {e}");
+            return;
+        }
+    };
+
+    tracing::info!("Listening for upgrades on {path}");
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::error!("Upgrade socket accept failed: {e}");
+                    continue;
+                }
+            },
+            _ = shutdown::triggered() => return,
+        };
+
+        let result = match stream.into_std() {
+            Ok(stream) => tokio::task::spawn_blocking(move || send_listeners(stream))
+                .await
+                .map_err(io::Error::other)
+                .and_then(|r| r),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => {
+                tracing::info!("Handed listeners to the new process");
+                shutdown::trigger();
+                return;
+            }
+            Err(e) => tracing::error!("Listener handoff failed: {e}"),
+        }
+    }
+}
+
+fn send_listeners(mut stream: UnixStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let fds: Vec<RawFd> = BOUND
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|fd| fd.as_raw_fd())
+        .collect();
+
+    sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(b"1")],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+
+    let mut ack = [0u8; 2];
+    stream.read_exact(&mut ack)?;
+
+    if &ack != b"ok" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected reply from the new process",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Accepts TCP connections, closing those we do not want before any TLS work
+/// is spent on them.
+pub struct GuardedListener(pub TcpListener);
+
+/// A TCP connection which counts towards its IP's connection limit while open.
+pub struct GuardedStream {
+    inner: TcpStream,
+    _permit: ConnectionPermit,
+}
+
+impl tls_listener::AsyncAccept for GuardedListener {
+    type Connection = GuardedStream;
+    type Address = SocketAddr;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(GuardedStream, SocketAddr), io::Error>> {
+        loop {
+            let (inner, addr) = ready!(self.0.poll_accept(cx))?;
+
+            if let Some(permit) = admit(&HOSTS.load(), addr.ip()) {
+                return Poll::Ready(Ok((
+                    GuardedStream {
+                        inner,
+                        _permit: permit,
+                    },
+                    addr,
+                )));
+            }
+        }
+    }
+}
+
+/// Whether `config` lets `ip` connect, counting the connection if so.
+fn admit(config: &Config, ip: IpAddr) -> Option<ConnectionPermit> {
+    if let Some(access) = &config.global.access {
+        let country = geoip::lookup(ip).country;
+        if !access::allowed(access, ip, country.as_deref()) {
+            tracing::debug!("Refused connection from {ip}");
+            return None;
+        }
+    }
+
+    let permit = rate_limit::connection_permit(ip, config.global.max_connections_per_ip);
+    if permit.is_none() {
+        tracing::debug!("Too many connections from {ip}");
+    }
+    permit
+}
+
+impl AsyncRead for GuardedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for GuardedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use serde_json::json;
+    use tls_listener::AsyncAccept;
+
+    use super::*;
+
+    #[test]
+    fn only_tcp_listeners_inherited() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        assert_eq!(tcp_listener(tcp.into()).unwrap().1, addr);
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(tcp_listener(udp.into()).is_err());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connected = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(tcp_listener(connected.into()).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let unix = std::os::unix::net::UnixListener::bind(dir.path().join("sock")).unwrap();
+        assert!(tcp_listener(unix.into()).is_err());
+    }
+
+    #[tokio::test]
+    async fn listeners_handed_over() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (old, new) = UnixStream::pair().unwrap();
+        let sent = std::thread::spawn(move || send_listeners(old));
+        let received = receive_listeners(new).unwrap();
+        sent.join().unwrap().unwrap();
+
+        // The same socket, so connections to it can be accepted by either process
+        let taken = received
+            .into_iter()
+            .find(|l| l.local_addr().is_ok_and(|a| a == addr))
+            .unwrap();
+        let client = std::net::TcpStream::connect(addr).unwrap();
+        let (_, peer) = taken.accept().unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn only_tcp_listeners_taken_over() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let (old, new) = UnixStream::pair().unwrap();
+        sendmsg::<()>(
+            old.as_raw_fd(),
+            &[IoSlice::new(b"1")],
+            &[ControlMessage::ScmRights(&[
+                tcp.as_raw_fd(),
+                udp.as_raw_fd(),
+            ])],
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+
+        let received = receive_listeners(new).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn guarded_connections_counted() {
+        let mut listener = GuardedListener(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let addr = listener.0.local_addr().unwrap();
+        let open = |ip| {
+            rate_limit::connections()
+                .into_iter()
+                .find(|(open, _)| *open == ip)
+                .map_or(0, |(_, count)| count)
+        };
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, peer) = poll_fn(|cx| Pin::new(&mut listener).poll_accept(cx))
+            .await
+            .unwrap();
+        assert_eq!(open(peer.ip()), 1);
+        drop(stream);
+        assert_eq!(open(peer.ip()), 0);
+    }
+
+    #[test]
+    fn connections_admitted() {
+        let config = |global: serde_json::Value| {
+            let mut config = global;
+            config["hosts"] = json!([]);
+            Config::parse(&config.to_string()).unwrap()
+        };
+        let ip = "127.0.0.5".parse().unwrap();
+
+        let denied = config(json!({ "access": { "deny": ["127.0.0.5"] } }));
+        let not_allowed = config(json!({ "access": { "allow": ["10.0.0.0/8"] } }));
+        let no_connections = config(json!({ "max_connections_per_ip": 0 }));
+
+        assert!(admit(&config(json!({})), ip).is_some());
+        assert!(admit(&denied, ip).is_none());
+        assert!(admit(&not_allowed, ip).is_none());
+        assert!(admit(&no_connections, ip).is_none());
+    }
+}
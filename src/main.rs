@@ -1,43 +1,68 @@
+mod access;
+mod access_log;
+mod admin;
+mod auth;
+mod body;
+mod cache;
+mod compression;
+mod config_edit;
+mod config_history;
 mod config_loader;
+mod error_pages;
+mod geoip;
+mod handlers;
+mod headers;
+mod jwt;
+mod listener;
+mod metrics;
+mod oidc;
+mod proxy;
+mod rate_limit;
+mod request_id;
+mod shutdown;
+mod static_files;
+mod telemetry;
+#[cfg(test)]
+mod testing;
 mod tls;
+mod upstream;
 
-use axum::response::Html;
-use axum::routing::any;
-use axum::Router;
 use hyper::service::service_fn;
-use hyper::{Request, StatusCode};
 
 use futures_util::stream::StreamExt;
-use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::client::legacy::Client;
-use hyper_util::rt::TokioExecutor;
-use tower_http::services::ServeDir;
+use futures_util::FutureExt;
 use std::net::SocketAddr;
-use std::{future::ready, sync::Mutex};
+use std::os::fd::OwnedFd;
+use std::process::ExitCode;
+use std::future::ready;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{sleep_until, Instant};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use hyper::server::conn::http1;
-use hyper_util::rt::tokio::TokioIo;
+use hyper_util::rt::{TokioIo, TokioTimer};
 
-use tls::tls_acceptor_impl;
+use listener::GuardedListener;
+use metrics::Handshake;
+use proxy::ClientInfo;
+use tls::{tls_acceptor_impl, Session};
 use tls_listener::TlsListener;
 
+use arc_swap::ArcSwap;
 use once_cell::sync::Lazy;
-use tokio::net::TcpListener;
 
 use config_loader::Config;
 
 /*
 TODO: 
 Short Term
-- for servedir, tokio spawn an axum server and then the proxy service can route to it?!
-- Create servedir, 404 from config
 - dockerize
 
 Long Term
-- Configurable rate limiting, geo blocking, ip blocking etc...
 - tls per host
-- web portal config tool
 
 
 try and update to toml with format, these should be Option<T>
@@ -49,63 +74,24 @@ destination = "http://192.168.68.100:8096"
 serve_dir = "./www"
 
 */
-// Load config from file / create new file
-static HOSTS: Lazy<Config> = Lazy::new(Config::load);
-
-static REQS: Lazy<Mutex<RequestsHandled>> = Lazy::new(|| Mutex::new(RequestsHandled::new()));
-
-static CLIENT: Lazy<hyper_util::client::legacy::Client<HttpConnector, hyper::body::Incoming>> =
-    Lazy::new(|| Client::builder(TokioExecutor::new()).build_http());
-
-static HOST404: Lazy<String> = Lazy::new(|| "http://127.0.0.1:41050/".to_owned());
+// Load config from file / create new file, swapped out when reloaded
+pub static HOSTS: Lazy<ArcSwap<Config>> = Lazy::new(|| ArcSwap::from_pointee(Config::load()));
 
 const CERT: &[u8] = include_bytes!("../res/tls/cloudflare-origin/public.der");
 const PKEY: &[u8] = include_bytes!("../res/tls/cloudflare-origin/private.der");
 
-struct RequestsHandled(u64);
-impl RequestsHandled {
-    fn increment(&mut self) {
-        self.0 += 1;
-    }
-    fn print(&self) {
-        tracing::info!("Requests Handled: {}\n", self.0);
-    }
-    fn new() -> Self {
-        RequestsHandled(0)
-    }
-}
+fn main() -> ExitCode {
+    // Taken before any other thread could be reading the environment
+    let systemd = listener::from_systemd();
 
-async fn handle(
-    mut req: Request<hyper::body::Incoming>,
-) -> Result<hyper::Response<hyper::body::Incoming>, hyper_util::client::legacy::Error> {
-    let host_header = &req
-        .headers()
-        .get("host")
-        .unwrap()
-        .to_str()
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
         .unwrap()
-        .to_owned();
-
-    let host = HOSTS.dest_map.get(host_header).unwrap_or(&HOST404);
-
-    tracing::info!("{host_header} => {host}");
-
-    let uri = format!("{host}{}", req.uri());
-
-    *req.uri_mut() = uri.parse().unwrap();
-
-    {
-        let mut lock = REQS.lock().unwrap();
-        lock.increment();
-        lock.print();
-        drop(lock)
-    }
-
-    CLIENT.request(req).await
+        .block_on(run(systemd))
 }
 
-#[tokio::main]
-async fn main() {
+async fn run(systemd: Vec<OwnedFd>) -> ExitCode {
 
     // Create and start logger
     tracing_subscriber::registry()
@@ -116,28 +102,66 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let service_404_handle = tokio::spawn(async { 
-        create_404_service().await 
-    });
-    let service_dir_handle = tokio::spawn(async { 
-        create_servedir_service().await 
-    });
+    // Address lists are ready before the first connection is checked
+    tokio::task::spawn_blocking(|| access::load_lists(&HOSTS.load()))
+        .await
+        .unwrap();
+
+    // These are only read at startup, a reload does not change them
+    let config = HOSTS.load_full();
+
+    // Must happen before any of the services bind
+    listener::inherit(systemd, config.global.upgrade_socket.as_deref()).await;
+
+    if let Some(path) = config.global.upgrade_socket.clone() {
+        tokio::spawn(listener::serve_handoff(path));
+    }
+
+    tokio::spawn(rate_limit::sweep_expired());
+    tokio::spawn(access::reload_changed());
+    tokio::spawn(auth::reload_changed());
+    cache::init();
+
+    if let Some(admin) = config.global.admin.clone() {
+        tokio::spawn(admin::serve(admin));
+    }
+
+    if let Some(telemetry) = config.global.telemetry.clone() {
+        tokio::spawn(telemetry::export(telemetry));
+    }
+
     let service_main_handle = tokio::spawn(async { 
         create_proxy_server().await 
     });
     
     tokio::select!(
-        _ = service_404_handle
-        => {
-            tracing::error!("404 service failed")
-        }
-        _ = service_dir_handle => {
-            tracing::error!("Serve dir service failed")
-        }
+        // Services stop by themselves once shutdown is triggered, that is not a failure
+        biased;
+        _ = shutdown::signal_received() => {}
+        _ = shutdown::triggered() => {}
         _ = service_main_handle => {
-            tracing::error!("Main proxy service failed")
+            tracing::error!("Main proxy service failed");
+            return ExitCode::FAILURE;
         }
-    )
+    );
+
+    // Stop accepting, and ask open connections to close after their current request
+    shutdown::trigger();
+
+    let drained = shutdown::drain(config.global.timeouts.drain()).await;
+
+    // Spans of the last requests would otherwise be lost
+    if let Some(telemetry) = &config.global.telemetry {
+        telemetry::flush(telemetry).await;
+    }
+
+    if drained {
+        tracing::info!("All connections drained");
+        ExitCode::SUCCESS
+    } else {
+        tracing::warn!("Drain deadline passed with connections still open");
+        ExitCode::FAILURE
+    }
 }
 
 
@@ -149,72 +173,120 @@ async fn create_proxy_server() {
     // This uses a filter to handle errors with connecting
     TlsListener::new(
         tls_acceptor_impl(PKEY, CERT),
-        TcpListener::bind(addr).await.unwrap(),
+        GuardedListener(listener::bind(addr).await.unwrap()),
     )
-    .connections()
     .filter_map(|conn| {
         ready(match conn {
-            Err(err) => {
+            // Failed handshakes are mostly scanners and bots, not worth more than debug
+            Err(tls_listener::Error::ListenerError(err)) => {
                 tracing::error!("{err}");
                 None
             }
-            Ok(c) => Some(TokioIo::new(c)),
+            Err(err) => {
+                metrics::handshake(match err {
+                    tls_listener::Error::HandshakeTimeout { .. } => Handshake::Timeout,
+                    _ => Handshake::Failure,
+                });
+                tracing::debug!("{err}");
+                None
+            }
+            Ok((c, addr)) => {
+                metrics::handshake(Handshake::Success);
+                let session = tls::session(&c);
+                Some((TokioIo::new(c), addr, session))
+            }
         })
     })
-    .for_each_concurrent(None, |conn| async {
-        if let Err(err) = http1::Builder::new()
-            .serve_connection(conn, service_fn(handle))
-            .await
-        {
-            eprintln!("Error serving connection: {:?}", err);
-        }
-    }).await
+    .take_until(shutdown::triggered())
+    .for_each_concurrent(None, |(conn, addr, session)| serve_connection(conn, addr, session)).await
 }
 
-// TODO: 404 service should take in a port, optionally redirect? etc...
-async fn create_404_service() {
-
-    let service_handle = tokio::spawn(async {
-        // build our application with a route
-        let app = Router::new()
-            .route("/", any(handler_404))
-            .route("/*0", any(handler_404));
-
-        // run it
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:41050")
-            .await
-            .unwrap();
-        tracing::info!("Starting 404 service");
-        axum::serve(listener, app).await.unwrap();
-    });
-
-    _ = service_handle.await;
+/// Tracks requests on a connection so it can be closed once idle.
+struct ConnActivity {
+    in_flight: AtomicUsize,
+    last_active: Mutex<Instant>,
 }
 
-async fn handler_404() -> (StatusCode, Html<&'static str>) {
-    tracing::info!("404 hit");
-    (
-        StatusCode::NOT_FOUND,
-        Html("<h1>You've hit 404, this host and/or address leads to nowhere...</h1>"),
-    )
+impl ConnActivity {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_deadline(&self, idle: Duration) -> Instant {
+        *self.last_active.lock().unwrap() + idle
+    }
+
+    fn is_idle(&self, idle: Duration) -> bool {
+        self.in_flight.load(Ordering::Acquire) == 0 && self.idle_deadline(idle) <= Instant::now()
+    }
 }
 
-// TODO: serve dir route
-async fn create_servedir_service() {
-
-    let service_handle = tokio::spawn(async {
-        // build our application with a route
-        let app = Router::new()
-        .nest_service("", 
-            ServeDir::new("../res/dirs/www"));
-
-        // run it
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:41051")
-            .await
-            .unwrap();
-        tracing::info!("Starting serve dir service");
-        axum::serve(listener, app).await.unwrap();
+async fn serve_connection<I>(conn: TokioIo<I>, addr: SocketAddr, session: Session)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let config = HOSTS.load_full();
+    let timeouts = &config.global.timeouts;
+    let mut shutdown = shutdown::subscribe();
+    let _connection = metrics::connection();
+
+    let activity = Arc::new(ConnActivity {
+        in_flight: AtomicUsize::new(0),
+        last_active: Mutex::new(Instant::now()),
     });
 
-    _ = service_handle.await;
-}
\ No newline at end of file
+    let client = ClientInfo {
+        addr,
+        location: geoip::lookup(addr.ip()),
+        tls_version: session.version,
+        cert: session.cert.map(Arc::new),
+    };
+
+    let service = {
+        let activity = activity.clone();
+        service_fn(move |mut req| {
+            let activity = activity.clone();
+            req.extensions_mut().insert(client.clone());
+            async move {
+                activity.in_flight.fetch_add(1, Ordering::AcqRel);
+                let res = proxy::handle(req).await;
+                activity.in_flight.fetch_sub(1, Ordering::AcqRel);
+                activity.touch();
+                res
+            }
+        })
+    };
+
+    let mut builder = http1::Builder::new();
+    builder
+        .timer(TokioTimer::new())
+        .header_read_timeout(timeouts.header_read());
+
+    let conn = builder.serve_connection(conn, service);
+    tokio::pin!(conn);
+
+    let idle = timeouts.client_idle();
+
+    let result = loop {
+        let idle_deadline = idle.map(|idle| activity.idle_deadline(idle));
+
+        tokio::select! {
+            res = conn.as_mut() => break res,
+            _ = shutdown.wait_for(|stopping| *stopping).map(drop) => {
+                // Lets the current request finish, then responds with `Connection: close`
+                conn.as_mut().graceful_shutdown();
+                break conn.as_mut().await;
+            }
+            _ = sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                if idle.is_some_and(|idle| activity.is_idle(idle)) {
+                    conn.as_mut().graceful_shutdown();
+                    break conn.as_mut().await;
+                }
+            }
+        }
+    };
+
+    if let Err(err) = result {
+        tracing::debug!("Error serving connection: {err}");
+    }
+}
@@ -0,0 +1,452 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use hyper::StatusCode;
+use once_cell::sync::Lazy;
+
+use crate::body::BytesIn;
+use crate::cache;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Sharded maps of atomics, so requests only contend when they update the exact
+/// same series at the same moment.
+static REQUESTS: Lazy<DashMap<RequestKey, Arc<RequestStats>>> = Lazy::new(DashMap::new);
+static BYTES: Lazy<DashMap<String, Arc<Transferred>>> = Lazy::new(DashMap::new);
+static UPSTREAM_ERRORS: Lazy<DashMap<(String, &'static str), AtomicU64>> = Lazy::new(DashMap::new);
+
+static IN_FLIGHT: AtomicI64 = AtomicI64::new(0);
+static CONNECTIONS: AtomicI64 = AtomicI64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKES: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+static CACHE: [AtomicU64; 5] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct RequestKey {
+    host: String,
+    route: String,
+    status: u16,
+}
+
+#[derive(Default)]
+struct RequestStats {
+    count: AtomicU64,
+    /// Not cumulative, they are summed up when rendered.
+    buckets: [AtomicU64; BUCKETS.len()],
+    sum_micros: AtomicU64,
+}
+
+#[derive(Default)]
+struct Transferred {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Handshake {
+    Success = 0,
+    Failure = 1,
+    Timeout = 2,
+}
+
+pub fn handshake(outcome: Handshake) {
+    HANDSHAKES[outcome as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How a request to a host with a cache was answered.
+#[derive(Clone, Copy, Debug)]
+pub enum CacheResult {
+    Hit = 0,
+    Miss = 1,
+    Revalidated = 2,
+    Stale = 3,
+    /// Answered with the response fetched for another request at the same time.
+    Coalesced = 4,
+}
+
+pub fn cache(result: CacheResult) {
+    CACHE[result as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counted as an active connection until dropped.
+pub struct Connection(());
+
+pub fn connection() -> Connection {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    Connection(())
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Open client connections and requests being handled.
+pub fn active() -> (i64, i64) {
+    (
+        CONNECTIONS.load(Ordering::Relaxed),
+        IN_FLIGHT.load(Ordering::Relaxed),
+    )
+}
+
+pub fn upstream_error(host: &str, kind: &'static str) {
+    UPSTREAM_ERRORS
+        .entry((host.to_owned(), kind))
+        .or_default()
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// A request in flight, until it is finished or dropped.
+pub struct InFlight {
+    host: String,
+    route: String,
+    started: Instant,
+    bytes_in: BytesIn,
+}
+
+/// `host` and `route` are the configured host name and route path, empty when
+/// there are none.
+pub fn start(host: &str, route: &str, bytes_in: &BytesIn) -> InFlight {
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+
+    InFlight {
+        host: host.to_owned(),
+        route: route.to_owned(),
+        started: Instant::now(),
+        bytes_in: bytes_in.clone(),
+    }
+}
+
+impl InFlight {
+    /// Called once the response body has been sent, or dropped.
+    pub fn finish(self, status: StatusCode, bytes_out: u64) {
+        let key = RequestKey {
+            host: self.host.clone(),
+            route: self.route.clone(),
+            status: status.as_u16(),
+        };
+        let stats = match REQUESTS.get(&key) {
+            Some(stats) => stats.clone(),
+            None => REQUESTS.entry(key).or_default().clone(),
+        };
+        stats.observe(self.started.elapsed());
+
+        let transferred = match BYTES.get(&self.host) {
+            Some(transferred) => transferred.clone(),
+            None => BYTES.entry(self.host.clone()).or_default().clone(),
+        };
+        transferred
+            .received
+            .fetch_add(self.bytes_in.get(), Ordering::Relaxed);
+        transferred.sent.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    /// The connection was closed instead of responding.
+    pub fn dropped(self) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RequestStats {
+    fn observe(&self, elapsed: Duration) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Everything in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let mut requests = REQUESTS
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect::<Vec<_>>();
+    requests.sort_by(|a, b| a.0.cmp(&b.0));
+
+    header(
+        &mut out,
+        "envoi_requests_total",
+        "counter",
+        "Requests answered, by host, route and status.",
+    );
+    for (key, stats) in &requests {
+        let count = stats.count.load(Ordering::Relaxed);
+        _ = writeln!(out, "envoi_requests_total{{{}}} {count}", key.labels());
+    }
+
+    header(
+        &mut out,
+        "envoi_request_duration_seconds",
+        "histogram",
+        "Time until the response was sent, by host, route and status.",
+    );
+    for (key, stats) in &requests {
+        let labels = key.labels();
+        let mut cumulative = 0;
+        for (le, bucket) in BUCKETS.iter().zip(&stats.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            _ = writeln!(
+                out,
+                "envoi_request_duration_seconds_bucket{{{labels},le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let count = stats.count.load(Ordering::Relaxed);
+        let sum = stats.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        _ = writeln!(
+            out,
+            "envoi_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {count}"
+        );
+        _ = writeln!(out, "envoi_request_duration_seconds_sum{{{labels}}} {sum}");
+        _ = writeln!(
+            out,
+            "envoi_request_duration_seconds_count{{{labels}}} {count}"
+        );
+    }
+
+    header(
+        &mut out,
+        "envoi_requests_in_flight",
+        "gauge",
+        "Requests being handled.",
+    );
+    _ = writeln!(
+        out,
+        "envoi_requests_in_flight {}",
+        IN_FLIGHT.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut out,
+        "envoi_requests_dropped_total",
+        "counter",
+        "Requests whose connection was closed without a response.",
+    );
+    _ = writeln!(
+        out,
+        "envoi_requests_dropped_total {}",
+        DROPPED.load(Ordering::Relaxed)
+    );
+
+    let mut errors = UPSTREAM_ERRORS
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+        .collect::<Vec<_>>();
+    errors.sort();
+    header(
+        &mut out,
+        "envoi_upstream_errors_total",
+        "counter",
+        "Failed upstream requests, by host and kind of failure.",
+    );
+    for ((host, kind), count) in errors {
+        _ = writeln!(
+            out,
+            "envoi_upstream_errors_total{{host=\"{}\",kind=\"{kind}\"}} {count}",
+            escape(&host)
+        );
+    }
+
+    let mut bytes = BYTES
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect::<Vec<_>>();
+    bytes.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, help, direction) in [
+        (
+            "envoi_received_bytes_total",
+            "Request body bytes received from clients, by host.",
+            true,
+        ),
+        (
+            "envoi_sent_bytes_total",
+            "Response body bytes sent to clients, by host.",
+            false,
+        ),
+    ] {
+        header(&mut out, name, "counter", help);
+        for (host, transferred) in &bytes {
+            let count = match direction {
+                true => transferred.received.load(Ordering::Relaxed),
+                false => transferred.sent.load(Ordering::Relaxed),
+            };
+            _ = writeln!(out, "{name}{{host=\"{}\"}} {count}", escape(host));
+        }
+    }
+
+    header(
+        &mut out,
+        "envoi_tls_handshakes_total",
+        "counter",
+        "TLS handshakes with clients, by result.",
+    );
+    for (result, count) in ["success", "failure", "timeout"].iter().zip(&HANDSHAKES) {
+        _ = writeln!(
+            out,
+            "envoi_tls_handshakes_total{{result=\"{result}\"}} {}",
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut out,
+        "envoi_cache_requests_total",
+        "counter",
+        "Requests to hosts with a cache, by how they were answered.",
+    );
+    for (result, count) in ["hit", "miss", "revalidated", "stale", "coalesced"]
+        .iter()
+        .zip(&CACHE)
+    {
+        _ = writeln!(
+            out,
+            "envoi_cache_requests_total{{result=\"{result}\"}} {}",
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    let stats = cache::stats();
+    header(
+        &mut out,
+        "envoi_cache_bytes",
+        "gauge",
+        "Size of the responses kept, in memory and on disk.",
+    );
+    _ = writeln!(out, "envoi_cache_bytes{{tier=\"memory\"}} {}", stats.bytes);
+    _ = writeln!(
+        out,
+        "envoi_cache_bytes{{tier=\"disk\"}} {}",
+        stats.disk_bytes
+    );
+
+    header(
+        &mut out,
+        "envoi_connections_active",
+        "gauge",
+        "Open client connections.",
+    );
+    _ = writeln!(
+        out,
+        "envoi_connections_active {}",
+        CONNECTIONS.load(Ordering::Relaxed)
+    );
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+impl RequestKey {
+    fn labels(&self) -> String {
+        format!(
+            "host=\"{}\",route=\"{}\",status=\"{}\"",
+            escape(&self.host),
+            escape(&self.route),
+            self.status
+        )
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn in_flight(host: &str, route: &str) -> InFlight {
+        start(host, route, &BytesIn::default())
+    }
+
+    #[test]
+    fn requests_and_latency() {
+        in_flight("metrics.test", "/api").finish(StatusCode::OK, 100);
+        in_flight("metrics.test", "/api").finish(StatusCode::OK, 50);
+        in_flight("metrics.test", "").finish(StatusCode::NOT_FOUND, 10);
+
+        let out = render();
+        assert!(out.contains(
+            "envoi_requests_total{host=\"metrics.test\",route=\"/api\",status=\"200\"} 2\n"
+        ));
+        assert!(out
+            .contains("envoi_requests_total{host=\"metrics.test\",route=\"\",status=\"404\"} 1\n"));
+        // Both well under the smallest bucket, which every larger one includes
+        assert!(out.contains("envoi_request_duration_seconds_bucket{host=\"metrics.test\",route=\"/api\",status=\"200\",le=\"0.005\"} 2\n"));
+        assert!(out.contains("envoi_request_duration_seconds_bucket{host=\"metrics.test\",route=\"/api\",status=\"200\",le=\"10\"} 2\n"));
+        assert!(out.contains("envoi_request_duration_seconds_bucket{host=\"metrics.test\",route=\"/api\",status=\"200\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("envoi_request_duration_seconds_count{host=\"metrics.test\",route=\"/api\",status=\"200\"} 2\n"));
+        assert!(out.contains("envoi_sent_bytes_total{host=\"metrics.test\"} 160\n"));
+        assert!(out.contains("# TYPE envoi_request_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let stats = RequestStats::default();
+        stats.observe(Duration::from_millis(30));
+        stats.observe(Duration::from_millis(700));
+        stats.observe(Duration::from_secs(60));
+
+        let buckets = stats
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        assert_eq!(buckets, [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(stats.count.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.sum_micros.load(Ordering::Relaxed), 60_730_000);
+    }
+
+    #[test]
+    fn upstream_errors_and_escaping() {
+        upstream_error("quote\"d.test", "timeout");
+        upstream_error("quote\"d.test", "timeout");
+
+        let out = render();
+        assert!(out.contains(
+            "envoi_upstream_errors_total{host=\"quote\\\"d.test\",kind=\"timeout\"} 2\n"
+        ));
+    }
+
+    #[test]
+    fn handshakes_and_connections() {
+        handshake(Handshake::Timeout);
+        let _connection = connection();
+
+        let out = render();
+        assert!(out.contains("envoi_tls_handshakes_total{result=\"timeout\"} "));
+        assert!(!out.contains("envoi_tls_handshakes_total{result=\"timeout\"} 0\n"));
+        assert!(!out.contains("envoi_connections_active 0\n"));
+    }
+}
@@ -0,0 +1,731 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use dashmap::DashMap;
+use hyper::header::{
+    HeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_TYPE, COOKIE, HOST, LOCATION, SET_COOKIE,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::auth::Denied;
+use crate::config_loader::OidcAuth;
+use crate::error_pages::UpstreamError;
+use crate::jwt::{self, Expected};
+use crate::proxy::{full, ProxyBody};
+use crate::upstream;
+
+const SESSION_COOKIE: &str = "envoi_session";
+const LOGIN_COOKIE: &str = "envoi_login";
+
+/// Seconds the user has to finish logging in with the provider.
+const LOGIN_LIFETIME: u64 = 600;
+
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of the provider's discovery document we use.
+#[derive(Deserialize)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// Discovered on first use, keyed by configured issuer.
+static PROVIDERS: Lazy<DashMap<String, Arc<Provider>>> = Lazy::new(DashMap::new);
+
+/// Encrypts cookies when there is no `session_secret`.
+static RANDOM_KEY: Lazy<Key> = Lazy::new(|| ChaCha20Poly1305::generate_key(&mut OsRng));
+
+/// Kept in the session cookie once the user has logged in.
+#[derive(Serialize, Deserialize)]
+struct Session {
+    /// Hosts sharing a cookie domain do not have to share a login.
+    iss: String,
+    aud: String,
+    exp: u64,
+    /// Only the claims sent upstream.
+    claims: HashMap<String, String>,
+}
+
+/// Kept in a cookie while the user is away at the provider.
+#[derive(Serialize, Deserialize)]
+struct Login {
+    state: String,
+    nonce: String,
+    return_to: String,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct Tokens {
+    id_token: String,
+}
+
+/// Let requests with a session through with the user's identity in headers,
+/// send everyone else to log in.
+pub async fn authenticate<B>(config: &OidcAuth, req: &mut Request<B>) -> Result<(), Denied> {
+    let key = key(config);
+
+    if req.uri().path() == config.callback_path {
+        return Err(callback(config, &key, req).await);
+    }
+
+    let session = cookie(req.headers(), SESSION_COOKIE)
+        .and_then(|sealed| open::<Session>(&key, SESSION_COOKIE, sealed))
+        .filter(|s| s.exp > now() && s.iss == config.issuer && s.aud == config.client_id);
+
+    match session {
+        Some(session) => {
+            pass_identity(config, &session, req.headers_mut());
+            Ok(())
+        }
+        None if req.method() == Method::GET || req.method() == Method::HEAD => {
+            Err(start_login(config, &key, req).await)
+        }
+        // There is nothing sensible to come back to after logging in
+        None => Err(Denied::Status(
+            StatusCode::UNAUTHORIZED,
+            "no session".into(),
+        )),
+    }
+}
+
+async fn start_login<B>(config: &OidcAuth, key: &Key, req: &Request<B>) -> Denied {
+    let provider = match provider(&config.issuer).await {
+        Ok(provider) => provider,
+        Err(e) => return Denied::Failed(e),
+    };
+    let Some(redirect_uri) = redirect_uri(config, req) else {
+        return Denied::Status(StatusCode::BAD_REQUEST, "no host header".into());
+    };
+
+    let login = Login {
+        state: random(),
+        nonce: random(),
+        return_to: local_path(req.uri().path_and_query().map_or("/", |p| p.as_str())).to_owned(),
+        exp: now() + LOGIN_LIFETIME,
+    };
+
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair("scope", &config.scopes.join(" "))
+        .append_pair("state", &login.state)
+        .append_pair("nonce", &login.nonce)
+        .finish();
+    let separator = match provider.authorization_endpoint.contains('?') {
+        true => '&',
+        false => '?',
+    };
+
+    let cookie = set_cookie(
+        config,
+        LOGIN_COOKIE,
+        &seal(key, LOGIN_COOKIE, &login),
+        LOGIN_LIFETIME,
+    );
+    Denied::Response(redirect(
+        &format!("{}{separator}{query}", provider.authorization_endpoint),
+        [cookie],
+    ))
+}
+
+/// The provider sends the user back here with a code for their ID token.
+async fn callback<B>(config: &OidcAuth, key: &Key, req: &Request<B>) -> Denied {
+    let invalid = |reason: &str| Denied::Status(StatusCode::BAD_REQUEST, reason.to_owned());
+
+    let query: HashMap<String, String> =
+        form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+
+    let Some(login) = cookie(req.headers(), LOGIN_COOKIE)
+        .and_then(|sealed| open::<Login>(key, LOGIN_COOKIE, sealed))
+        .filter(|login| login.exp > now())
+    else {
+        return invalid("no login in progress");
+    };
+    if query.get("state") != Some(&login.state) {
+        return invalid("state does not match");
+    }
+    if let Some(error) = query.get("error") {
+        return invalid(&format!("provider refused the login: {error}"));
+    }
+    let Some(code) = query.get("code") else {
+        return invalid("no code");
+    };
+
+    let provider = match provider(&config.issuer).await {
+        Ok(provider) => provider,
+        Err(e) => return Denied::Failed(e),
+    };
+    let Some(redirect_uri) = redirect_uri(config, req) else {
+        return invalid("no host header");
+    };
+
+    let id_token = match exchange(config, &provider, code, &redirect_uri).await {
+        Ok(id_token) => id_token,
+        Err(denied) => return denied,
+    };
+
+    let expected = Expected {
+        issuer: Some(&provider.issuer),
+        audience: Some(&config.client_id),
+    };
+    let claims: HashMap<String, Value> =
+        match jwt::validate_with_jwks(&id_token, &provider.jwks_uri, &expected).await {
+            Ok(claims) => claims,
+            Err(e) => return Denied::Status(StatusCode::UNAUTHORIZED, e),
+        };
+    if claims.get("nonce").and_then(Value::as_str) != Some(&login.nonce) {
+        return Denied::Status(StatusCode::UNAUTHORIZED, "nonce does not match".into());
+    }
+
+    let session = Session {
+        iss: config.issuer.clone(),
+        aud: config.client_id.clone(),
+        exp: now() + config.session_lifetime,
+        claims: config
+            .claim_headers
+            .values()
+            .filter_map(|claim| Some((claim.clone(), jwt::claim_value(claims.get(claim)?)?)))
+            .collect(),
+    };
+
+    let sub = claims
+        .get("sub")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    tracing::info!("Logged in {sub:?}");
+
+    Denied::Response(redirect(
+        &login.return_to,
+        [
+            set_cookie(
+                config,
+                SESSION_COOKIE,
+                &seal(key, SESSION_COOKIE, &session),
+                config.session_lifetime,
+            ),
+            set_cookie(config, LOGIN_COOKIE, "", 0),
+        ],
+    ))
+}
+
+/// `path` if it stays on this site, otherwise `/`. Browsers take `//host` and
+/// `/\host` as another site.
+fn local_path(path: &str) -> &str {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some('/'), None) => path,
+        (Some('/'), Some(next)) if next != '/' && next != '\\' => path,
+        _ => "/",
+    }
+}
+
+/// Swap the code for the user's ID token.
+async fn exchange(
+    config: &OidcAuth,
+    provider: &Provider,
+    code: &str,
+    redirect_uri: &str,
+) -> Result<String, Denied> {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .append_pair("grant_type", "authorization_code")
+        .append_pair("code", code)
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("client_id", &config.client_id)
+        .append_pair("client_secret", &config.client_secret)
+        .finish();
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(provider.token_endpoint.as_str())
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(ACCEPT, "application/json")
+        .body(full(body))
+        .map_err(|e| {
+            Denied::Failed(UpstreamError::Unavailable(format!(
+                "invalid token endpoint: {e}"
+            )))
+        })?;
+
+    let (status, body) = upstream::fetch(req, TOKEN_TIMEOUT)
+        .await
+        .map_err(Denied::Failed)?;
+    if !status.is_success() {
+        return Err(Denied::Status(
+            StatusCode::BAD_GATEWAY,
+            format!(
+                "token endpoint returned {status}: {}",
+                String::from_utf8_lossy(&body)
+            ),
+        ));
+    }
+
+    serde_json::from_slice::<Tokens>(&body)
+        .map(|tokens| tokens.id_token)
+        .map_err(|e| {
+            Denied::Status(
+                StatusCode::BAD_GATEWAY,
+                format!("invalid token response: {e}"),
+            )
+        })
+}
+
+async fn provider(issuer: &str) -> Result<Arc<Provider>, UpstreamError> {
+    if let Some(provider) = PROVIDERS.get(issuer) {
+        return Ok(provider.clone());
+    }
+
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let provider = jwt::fetch_json::<Provider>(&url)
+        .await
+        .map(Arc::new)
+        .map_err(|e| UpstreamError::Unavailable(format!("discovering {issuer}: {e}")))?;
+
+    PROVIDERS.insert(issuer.to_owned(), provider.clone());
+    Ok(provider)
+}
+
+fn redirect_uri<B>(config: &OidcAuth, req: &Request<B>) -> Option<String> {
+    let host = req.headers().get(HOST)?.to_str().ok()?;
+    Some(format!("https://{host}{}", config.callback_path))
+}
+
+/// Set the configured headers from the session, replacing any sent by the client,
+/// and keep our cookies from the upstream.
+fn pass_identity(config: &OidcAuth, session: &Session, headers: &mut HeaderMap) {
+    strip_cookies(headers);
+
+    jwt::set_claim_headers(headers, &config.claim_headers, |claim| {
+        session.claims.get(claim).cloned()
+    });
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+fn strip_cookies(headers: &mut HeaderMap) {
+    let ours = |c: &str| {
+        let name = c.split('=').next().unwrap_or_default();
+        name == SESSION_COOKIE || name == LOGIN_COOKIE
+    };
+
+    let kept = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .map(str::trim)
+        .filter(|c| !c.is_empty() && !ours(c))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    headers.remove(COOKIE);
+    if let Ok(kept) = HeaderValue::from_str(&kept) {
+        if !kept.is_empty() {
+            headers.insert(COOKIE, kept);
+        }
+    }
+}
+
+fn set_cookie(config: &OidcAuth, name: &str, value: &str, max_age: u64) -> HeaderValue {
+    let mut cookie =
+        format!("{name}={value}; Path=/; Max-Age={max_age}; Secure; HttpOnly; SameSite=Lax");
+    if let Some(domain) = &config.cookie_domain {
+        cookie.push_str(&format!("; Domain={domain}"));
+    }
+    HeaderValue::from_str(&cookie).expect("cookie values are base64")
+}
+
+fn redirect<const N: usize>(location: &str, cookies: [HeaderValue; N]) -> Response<ProxyBody> {
+    let mut res = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(CACHE_CONTROL, "no-store")
+        .body(full(""))
+        .unwrap();
+
+    let headers = res.headers_mut();
+    if let Ok(location) = HeaderValue::from_str(location) {
+        headers.insert(LOCATION, location);
+    }
+    for cookie in cookies {
+        headers.append(SET_COOKIE, cookie);
+    }
+    res
+}
+
+fn key(config: &OidcAuth) -> Key {
+    match &config.session_secret {
+        Some(secret) => Sha256::digest(secret.as_bytes()),
+        None => *RANDOM_KEY,
+    }
+}
+
+/// Encrypt `value` for a cookie; the name is authenticated too, so one of our
+/// cookies cannot be passed off as another.
+fn seal<T: Serialize>(key: &Key, name: &str, value: &T) -> String {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: &serde_json::to_vec(value).expect("cookie contents serialize"),
+        aad: name.as_bytes(),
+    };
+    let ciphertext = ChaCha20Poly1305::new(key)
+        .encrypt(&nonce, payload)
+        .expect("encrypting to memory");
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    BASE64URL.encode(sealed)
+}
+
+fn open<T: DeserializeOwned>(key: &Key, name: &str, sealed: &str) -> Option<T> {
+    let sealed = BASE64URL.decode(sealed).ok()?;
+    if sealed.len() < 12 {
+        return None;
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: name.as_bytes(),
+    };
+    let plaintext = ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .ok()?;
+    serde_json::from_slice(&plaintext).ok()
+}
+
+fn random() -> String {
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64URL.encode(bytes)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Stands in for a provider. Its token endpoint takes the nonce as the
+    /// code, so the tests can choose which nonce ends up in the ID token.
+    async fn issuer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+
+        let service = {
+            let base = base.clone();
+            service_fn(move |req: Request<Incoming>| {
+                let base = base.clone();
+                async move {
+                    let body = match req.uri().path() {
+                        "/.well-known/openid-configuration" => json!({
+                            "issuer": base,
+                            "authorization_endpoint": format!("{base}/authorize"),
+                            "token_endpoint": format!("{base}/token"),
+                            "jwks_uri": format!("{base}/jwks"),
+                        })
+                        .to_string(),
+                        "/jwks" => include_str!("../res/test/jwt/jwks.json").to_owned(),
+                        "/token" => {
+                            let form = req.into_body().collect().await.unwrap().to_bytes();
+                            let form: HashMap<String, String> =
+                                form_urlencoded::parse(&form).into_owned().collect();
+                            if form["client_secret"] != "secret" {
+                                return Ok::<_, Infallible>(
+                                    Response::builder().status(401).body(full("")).unwrap(),
+                                );
+                            }
+                            json!({ "id_token": id_token(&base, &form["code"]) }).to_string()
+                        }
+                        _ => String::new(),
+                    };
+                    Ok(Response::new(full(body)))
+                }
+            })
+        };
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service.clone();
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        base
+    }
+
+    fn id_token(issuer: &str, nonce: &str) -> String {
+        let mut header = Header::new(jsonwebtoken::Algorithm::EdDSA);
+        header.kid = Some("ed25519".into());
+        let key = EncodingKey::from_ed_pem(include_bytes!("../res/test/jwt/ed25519.pem")).unwrap();
+        let claims = json!({
+            "iss": issuer,
+            "aud": "envoi",
+            "sub": "alice",
+            "email": "alice@example.com",
+            "groups": ["admins", "users"],
+            "exp": now() + 300,
+            "nonce": nonce,
+        });
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    fn config(issuer: String) -> OidcAuth {
+        OidcAuth {
+            issuer,
+            client_id: "envoi".into(),
+            client_secret: "secret".into(),
+            scopes: vec!["openid".into()],
+            callback_path: "/_envoi/oidc/callback".into(),
+            cookie_domain: None,
+            session_secret: Some("test secret".into()),
+            session_lifetime: 3600,
+            claim_headers: HashMap::from([
+                ("X-Auth-User".into(), "sub".into()),
+                ("X-Auth-Email".into(), "email".into()),
+                ("X-Auth-Groups".into(), "groups".into()),
+            ]),
+        }
+    }
+
+    fn request(path: &str, cookie: Option<&str>) -> Request<()> {
+        let mut req = Request::builder().uri(path).header(HOST, "app.test");
+        if let Some(cookie) = cookie {
+            req = req.header(COOKIE, cookie);
+        }
+        req.body(()).unwrap()
+    }
+
+    fn redirected(denied: Result<(), Denied>) -> Response<ProxyBody> {
+        match denied {
+            Err(Denied::Response(res)) => {
+                assert_eq!(res.status(), StatusCode::FOUND);
+                res
+            }
+            _ => panic!("expected a redirect"),
+        }
+    }
+
+    /// `name=value` from each `Set-Cookie`.
+    fn cookies(res: &Response<ProxyBody>) -> Vec<String> {
+        res.headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap().split(';').next().unwrap().to_owned())
+            .collect()
+    }
+
+    /// Start logging in, returning the login cookie, state and nonce.
+    async fn start(config: &OidcAuth) -> (String, String, String) {
+        let mut req = request("/library?sort=name", None);
+        let res = redirected(authenticate(config, &mut req).await);
+
+        let location = res.headers()[LOCATION].to_str().unwrap();
+        let (endpoint, query) = location.split_once('?').unwrap();
+        assert_eq!(endpoint, format!("{}/authorize", config.issuer));
+
+        let query: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        assert_eq!(query["client_id"], "envoi");
+        assert_eq!(query["response_type"], "code");
+        assert_eq!(
+            query["redirect_uri"],
+            "https://app.test/_envoi/oidc/callback"
+        );
+
+        let cookie = cookies(&res).remove(0);
+        (cookie, query["state"].clone(), query["nonce"].clone())
+    }
+
+    #[tokio::test]
+    async fn login() {
+        let config = config(issuer().await);
+        let (login_cookie, state, nonce) = start(&config).await;
+
+        let mut req = request(
+            &format!("/_envoi/oidc/callback?code={nonce}&state={state}"),
+            Some(&login_cookie),
+        );
+        let res = redirected(authenticate(&config, &mut req).await);
+        assert_eq!(res.headers()[LOCATION], "/library?sort=name");
+
+        let cookies = cookies(&res);
+        assert!(cookies[0].starts_with("envoi_session="));
+        assert_eq!(cookies[1], "envoi_login=");
+
+        let mut req = request("/library", Some(&format!("theme=dark; {}", cookies[0])));
+        req.headers_mut()
+            .insert("x-auth-user", HeaderValue::from_static("mallory"));
+        assert!(authenticate(&config, &mut req).await.is_ok());
+
+        let headers = req.headers();
+        assert_eq!(headers["x-auth-user"], "alice");
+        assert_eq!(headers["x-auth-email"], "alice@example.com");
+        assert_eq!(headers["x-auth-groups"], "admins,users");
+        assert_eq!(headers[COOKIE], "theme=dark");
+    }
+
+    #[tokio::test]
+    async fn callback_checks_state_and_nonce() {
+        let config = config(issuer().await);
+        let (login_cookie, state, nonce) = start(&config).await;
+
+        let mut req = request(
+            &format!("/_envoi/oidc/callback?code={nonce}&state=forged"),
+            Some(&login_cookie),
+        );
+        let denied = authenticate(&config, &mut req).await;
+        assert!(matches!(
+            denied,
+            Err(Denied::Status(StatusCode::BAD_REQUEST, _))
+        ));
+
+        let mut req = request(
+            &format!("/_envoi/oidc/callback?code={nonce}&state={state}"),
+            None,
+        );
+        let denied = authenticate(&config, &mut req).await;
+        assert!(matches!(
+            denied,
+            Err(Denied::Status(StatusCode::BAD_REQUEST, _))
+        ));
+
+        // An ID token issued for some other login
+        let mut req = request(
+            &format!("/_envoi/oidc/callback?code=replayed&state={state}"),
+            Some(&login_cookie),
+        );
+        let denied = authenticate(&config, &mut req).await;
+        assert!(matches!(
+            denied,
+            Err(Denied::Status(StatusCode::UNAUTHORIZED, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_client_secret() {
+        let mut config = config(issuer().await);
+        let (login_cookie, state, nonce) = start(&config).await;
+
+        config.client_secret = "wrong".into();
+        let mut req = request(
+            &format!("/_envoi/oidc/callback?code={nonce}&state={state}"),
+            Some(&login_cookie),
+        );
+        let denied = authenticate(&config, &mut req).await;
+        assert!(matches!(
+            denied,
+            Err(Denied::Status(StatusCode::BAD_GATEWAY, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_per_client() {
+        let config = config(issuer().await);
+        let key = key(&config);
+
+        let session = Session {
+            iss: config.issuer.clone(),
+            aud: "another-app".into(),
+            exp: now() + 60,
+            claims: HashMap::new(),
+        };
+        let cookie = format!("{SESSION_COOKIE}={}", seal(&key, SESSION_COOKIE, &session));
+
+        let mut req = request("/", Some(&cookie));
+        redirected(authenticate(&config, &mut req).await);
+    }
+
+    #[tokio::test]
+    async fn posts_without_a_session() {
+        let config = config(issuer().await);
+        let mut req = request("/", None);
+        *req.method_mut() = Method::POST;
+
+        let denied = authenticate(&config, &mut req).await;
+        assert!(matches!(
+            denied,
+            Err(Denied::Status(StatusCode::UNAUTHORIZED, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn returns_only_to_this_site() {
+        let config = config(issuer().await);
+        let mut req = request("//evil.example/", None);
+        let res = redirected(authenticate(&config, &mut req).await);
+        let cookie = cookies(&res).remove(0);
+        let (_, sealed) = cookie.split_once('=').unwrap();
+        let login: Login = open(&key(&config), LOGIN_COOKIE, sealed).unwrap();
+        assert_eq!(login.return_to, "/");
+
+        assert_eq!(local_path("/library?sort=name"), "/library?sort=name");
+        assert_eq!(local_path("/"), "/");
+        assert_eq!(local_path("//evil"), "/");
+        assert_eq!(local_path("/\\evil"), "/");
+        assert_eq!(local_path("https://evil"), "/");
+    }
+
+    #[test]
+    fn sealed_cookies() {
+        let key = Sha256::digest(b"test secret");
+        let login = Login {
+            state: "s".into(),
+            nonce: "n".into(),
+            return_to: "/".into(),
+            exp: 1,
+        };
+
+        let sealed = seal(&key, LOGIN_COOKIE, &login);
+        assert!(open::<Login>(&key, LOGIN_COOKIE, &sealed).is_some());
+        assert!(open::<Login>(&key, SESSION_COOKIE, &sealed).is_none());
+        assert!(open::<Login>(&Sha256::digest(b"other"), LOGIN_COOKIE, &sealed).is_none());
+
+        let mut tampered = BASE64URL.decode(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open::<Login>(&key, LOGIN_COOKIE, &BASE64URL.encode(tampered)).is_none());
+    }
+}
@@ -0,0 +1,466 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full};
+use hyper::header::{HeaderName, HeaderValue, HOST, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::http::uri::Authority;
+use hyper::{HeaderMap, Request, Response, StatusCode};
+use tracing::Instrument;
+
+use crate::auth::{self, Denied};
+use crate::body::{self, BytesIn};
+use crate::config_loader::{Action, Config, Host, Route, UnknownHost};
+use crate::error_pages::{self, UpstreamError};
+use crate::geoip::Location;
+use crate::tls::{self, ClientCert};
+use crate::{
+    access, access_log, cache, compression, handlers, headers, metrics, rate_limit, request_id,
+    static_files, telemetry, upstream, HOSTS,
+};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body of every response Envoi sends, whether proxied or generated locally.
+pub type ProxyBody = UnsyncBoxBody<Bytes, BoxError>;
+
+const CERT_SUBJECT: &str = "x-client-cert-subject";
+const CERT_SAN: &str = "x-client-cert-san";
+const CERT_FINGERPRINT: &str = "x-client-cert-fingerprint";
+
+pub fn full(body: impl Into<Bytes>) -> ProxyBody {
+    Full::new(body.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Details of the client's connection, added to the extensions of its requests.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    pub location: Location,
+    pub tls_version: Option<&'static str>,
+    pub cert: Option<Arc<ClientCert>>,
+}
+
+/// Returned instead of a response to have the connection closed without one.
+#[derive(Debug)]
+pub struct DropConnection;
+
+impl fmt::Display for DropConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connection dropped")
+    }
+}
+
+impl std::error::Error for DropConnection {}
+
+pub async fn handle(
+    mut req: Request<hyper::body::Incoming>,
+) -> Result<Response<ProxyBody>, DropConnection> {
+    // Held for the whole request, so a reload does not change it halfway
+    let config = HOSTS.load_full();
+    let request_id = request_id::assign(&config.global.request_id, &mut req);
+    let key = host_key(req.headers());
+    let (host, route) = labels(&config, &req);
+    let accept_encoding = compression::accepted(&req);
+    let bytes_in = BytesIn::default();
+    req.extensions_mut().insert(bytes_in.clone());
+    let in_flight = metrics::start(&host, &route, &bytes_in);
+    let pending = access_log::start(&req, &request_id, &bytes_in);
+    let trace = telemetry::start(&mut req, &route, &request_id);
+
+    // Everything logged while handling the request is tagged with where it came from
+    let client_info = req.extensions().get::<ClientInfo>().cloned();
+    let client = client_info.as_ref();
+    let span = tracing::info_span!(
+        "request",
+        id = %request_id,
+        client = client.map(|c| tracing::field::display(c.addr.ip())),
+        country = client.and_then(|c| c.location.country.as_deref()),
+        asn = client.and_then(|c| c.location.asn),
+        cert = client
+            .and_then(|c| c.cert.as_ref())
+            .and_then(|c| c.common_name.as_deref()),
+        trace_id = %trace.context.trace_id(),
+    );
+
+    let mut res = match dispatch(req, &config, request_id.clone())
+        .instrument(span)
+        .await
+    {
+        Ok(res) => res,
+        Err(drop) => {
+            in_flight.dropped();
+            trace.finish(None, None);
+            return Err(drop);
+        }
+    };
+
+    let host_config = config.hosts.get(&key);
+    if let Some(accept_encoding) = accept_encoding {
+        if let Some(compression) = config.compression(host_config) {
+            res = compression::compress(compression, accept_encoding, res).await;
+        }
+    }
+
+    // Every response for the host, including those made here such as error pages
+    if let Some(host_config) = host_config {
+        let route = host_config.routes.iter().find(|r| r.path == route);
+        let rules = route
+            .and_then(|r| r.headers.as_ref())
+            .or(host_config.headers.as_ref());
+        if let Some(rules) = rules {
+            let context = headers::Context {
+                client,
+                host: &host,
+                request_id: &request_id,
+            };
+            headers::response(rules, res.headers_mut(), &context);
+        }
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(&*request_id::HEADER, value);
+    }
+
+    let status = res.status();
+    let upstream = res.extensions_mut().remove::<access_log::Upstream>();
+    Ok(res.map(|body| {
+        body::on_sent(body, move |bytes_out| {
+            in_flight.finish(status, bytes_out);
+            trace.finish(Some(status), upstream.as_ref());
+            if let Some(pending) = pending {
+                pending.write(status, upstream.as_ref(), bytes_out);
+            }
+        })
+    }))
+}
+
+/// The configured host name and route path a request is for, empty when there
+/// are none.
+fn labels<B>(config: &Config, req: &Request<B>) -> (String, String) {
+    let host = config.hosts.get(&host_key(req.headers()));
+    let route = host.and_then(|h| h.route(req.uri().path()));
+
+    (
+        host.map(|h| h.host.clone()).unwrap_or_default(),
+        route.map(|r| r.path.clone()).unwrap_or_default(),
+    )
+}
+
+/// The `Host` header the way hosts are keyed, lowercase and without a port.
+fn host_key(headers: &HeaderMap) -> String {
+    let host = headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    let name = match host.parse::<Authority>() {
+        Ok(authority) => authority.host().to_owned(),
+        Err(_) => host.to_owned(),
+    };
+    name.to_ascii_lowercase()
+}
+
+async fn dispatch(
+    mut req: Request<hyper::body::Incoming>,
+    config: &Config,
+    request_id: String,
+) -> Result<Response<ProxyBody>, DropConnection> {
+    let started = Instant::now();
+
+    let host_header = host_key(req.headers());
+    let host = config.hosts.get(&host_header);
+    let route = host.and_then(|h| h.route(req.uri().path()));
+
+    let action = match (host, &config.global.unknown_host) {
+        (Some(host), _) => route
+            .and_then(|r| r.action.as_ref())
+            .unwrap_or(&host.action),
+        (None, Some(UnknownHost::Action(action))) => action,
+        (None, Some(UnknownHost::Drop)) => {
+            tracing::info!("{host_header} => dropped");
+            return Err(DropConnection);
+        }
+        (None, None) => {
+            tracing::info!("{host_header} => 404");
+            return Ok(handlers::not_found());
+        }
+    };
+
+    if let (Some(host), Some(client)) = (host, req.extensions().get::<ClientInfo>()) {
+        let access = route
+            .and_then(|r| r.access.as_ref())
+            .or(host.access.as_ref());
+        let country = client.location.country.as_deref();
+        if access.is_some_and(|access| !access::allowed(access, client.addr.ip(), country)) {
+            tracing::info!("{host_header} => forbidden {}", client.addr.ip());
+            return Ok(error_pages::render(Some(host), StatusCode::FORBIDDEN, &request_id).await);
+        }
+
+        if let Some(mtls) = &host.mtls {
+            if !tls::client_cert_allowed(mtls, &host.host, client.cert.as_deref()) {
+                tracing::info!("{host_header} => client certificate refused");
+                return Ok(error_pages::render(Some(host), StatusCode::FORBIDDEN, &request_id).await);
+            }
+        }
+
+        let limits = route
+            .and_then(|r| r.rate_limits.as_deref())
+            .unwrap_or(&host.rate_limits);
+        let route_path = route.map_or("", |r| r.path.as_str());
+
+        if let Err(wait) = rate_limit::check(
+            &host.host,
+            route_path,
+            limits,
+            client.addr.ip(),
+            req.headers(),
+        ) {
+            tracing::info!("{host_header} => rate limited {}", client.addr.ip());
+            return Ok(too_many_requests(host, wait, &request_id).await);
+        }
+    }
+
+    if let Some(host) = host {
+        let auth = route.and_then(|r| r.auth.as_ref()).or(host.auth.as_ref());
+        if let Some(auth) = auth {
+            let client = req.extensions().get::<ClientInfo>().map(|c| c.addr.ip());
+            let timeouts = config.timeouts(Some(host));
+            if let Err(denied) = auth::authenticate(auth, &mut req, client, &timeouts).await {
+                tracing::info!("{host_header} => unauthorized");
+                return Ok(unauthorized(host, denied, &request_id).await);
+            }
+        }
+    }
+
+    Ok(match action {
+        Action::Destination(destination) => {
+            tracing::info!("{host_header} => {destination}");
+            proxy(req, config, host, route, destination, &request_id, started).await
+        }
+        Action::ServeDir(serve_dir) => {
+            tracing::info!("{host_header} => {}", serve_dir.root);
+            static_files::serve(serve_dir, req).await
+        }
+        Action::Respond(respond) => handlers::respond(respond, &request_id).await,
+        Action::Redirect(redirect) => handlers::redirect(redirect, req.uri()),
+    })
+}
+
+async fn unauthorized(host: &Host, denied: Denied, request_id: &str) -> Response<ProxyBody> {
+    match denied {
+        Denied::Unauthorized(challenge) => {
+            let mut res =
+                error_pages::render(Some(host), StatusCode::UNAUTHORIZED, request_id).await;
+            res.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+            res
+        }
+        Denied::Response(res) => res,
+        Denied::Failed(err) => {
+            tracing::warn!(
+                request_id,
+                kind = err.kind(),
+                cause = %err,
+                "auth request failed"
+            );
+            error_pages::render(Some(host), err.status(), request_id).await
+        }
+        Denied::Status(status, reason) => {
+            tracing::info!(request_id, reason, "authentication failed");
+            error_pages::render(Some(host), status, request_id).await
+        }
+    }
+}
+
+async fn too_many_requests(host: &Host, wait: Duration, request_id: &str) -> Response<ProxyBody> {
+    let mut res = error_pages::render(Some(host), StatusCode::TOO_MANY_REQUESTS, request_id).await;
+    // Whole seconds, rounded up so clients do not come back too early
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    res.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(secs.max(1)));
+    res
+}
+
+async fn proxy(
+    mut req: Request<hyper::body::Incoming>,
+    config: &Config,
+    host: Option<&Host>,
+    route: Option<&Route>,
+    destination: &str,
+    request_id: &str,
+    started: Instant,
+) -> Response<ProxyBody> {
+    if let Some(geoip) = &config.global.geoip {
+        tag_country(&mut req, &geoip.country_header);
+    }
+    tag_client_cert(&mut req, host);
+    let rules = route
+        .and_then(|r| r.headers.as_ref())
+        .or(host.and_then(|h| h.headers.as_ref()));
+    if let (Some(host), Some(rules)) = (host, rules) {
+        let client = req.extensions().get::<ClientInfo>().cloned();
+        let context = headers::Context {
+            client: client.as_ref(),
+            host: &host.host,
+            request_id,
+        };
+        headers::request(rules, req.headers_mut(), &context);
+    }
+
+    let sent = Instant::now();
+    let result = match format!("{destination}{}", req.uri()).parse() {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            match host.filter(|h| h.cache) {
+                Some(host) => {
+                    let authenticated = route.and_then(|r| r.auth.as_ref()).or(host.auth.as_ref());
+                    let timeouts = config.timeouts(Some(host));
+                    cache::forward(&host.host, authenticated.is_some(), req, &timeouts).await
+                }
+                None => upstream::forward(req, &config.timeouts(host)).await,
+            }
+        }
+        Err(err) => Err(UpstreamError::Unavailable(format!(
+            "invalid destination {destination:?}: {err}"
+        ))),
+    };
+
+    let upstream = access_log::Upstream {
+        destination: destination.to_owned(),
+        latency: sent.elapsed(),
+    };
+
+    let mut res = match result {
+        Ok(res) => res,
+        Err(err) => {
+            metrics::upstream_error(host.map_or("", |h| h.host.as_str()), err.kind());
+            tracing::warn!(
+                request_id,
+                upstream = destination,
+                kind = err.kind(),
+                cause = %err,
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "upstream request failed"
+            );
+            error_pages::render(host, err.status(), request_id).await
+        }
+    };
+    res.extensions_mut().insert(upstream);
+    res
+}
+
+/// Pass on the client certificate verified for this host, replacing any
+/// certificate headers from the client.
+fn tag_client_cert<B>(req: &mut Request<B>, host: Option<&Host>) {
+    let cert = req
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|c| c.cert.clone())
+        .filter(|cert| host.is_some_and(|h| cert.verified_for(&h.host)));
+
+    let headers = req.headers_mut();
+    for name in [CERT_SUBJECT, CERT_SAN, CERT_FINGERPRINT] {
+        headers.remove(name);
+    }
+
+    let Some(cert) = cert else {
+        return;
+    };
+    let values = [
+        (CERT_SUBJECT, cert.subject.clone()),
+        (CERT_SAN, cert.sans.join(",")),
+        (CERT_FINGERPRINT, cert.fingerprint.clone()),
+    ];
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Replace any country header from the client with the one we looked up.
+fn tag_country<B>(req: &mut Request<B>, header: &str) {
+    let Ok(name) = HeaderName::try_from(header) else {
+        tracing::warn!("Invalid country header name {header:?}");
+        return;
+    };
+
+    let country = req
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|c| c.location.country.as_deref())
+        .and_then(|c| HeaderValue::from_str(c).ok());
+
+    let headers = req.headers_mut();
+    headers.remove(&name);
+    if let Some(country) = country {
+        headers.insert(name, country);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::LOCATION;
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing;
+
+    fn config(unknown_host: serde_json::Value) -> Config {
+        let config = json!({
+            "unknown_host": unknown_host,
+            "hosts": [{ "host": "known.test", "respond": { "status": 204 } }],
+        });
+        Config::parse(&config.to_string()).unwrap()
+    }
+
+    async fn get(config: &Config, host: &str) -> Result<Response<ProxyBody>, DropConnection> {
+        let req = Request::get("/some/page")
+            .header("host", host)
+            .body(full(""))
+            .unwrap();
+        dispatch(testing::incoming(req).await, config, "id".into()).await
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_not_found() {
+        let res = get(&config(serde_json::Value::Null), "other.test").await;
+        assert_eq!(res.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_dropped() {
+        let config = config(json!("drop"));
+        assert!(get(&config, "other.test").await.is_err());
+
+        let res = get(&config, "known.test").await;
+        assert_eq!(res.unwrap().status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn hosts_matched_ignoring_case_and_port() {
+        let config = json!({
+            "unknown_host": "drop",
+            "hosts": [{ "host": "Known.Test", "respond": { "status": 204 } }],
+        });
+        let config = Config::parse(&config.to_string()).unwrap();
+
+        for host in ["known.test", "KNOWN.test", "known.test:8443"] {
+            let res = get(&config, host).await;
+            assert_eq!(res.unwrap().status(), StatusCode::NO_CONTENT, "{host}");
+        }
+        assert!(get(&config, "other.test:8443").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_given_action() {
+        let config = config(json!({ "redirect": { "to": "https://known.test" } }));
+
+        let res = get(&config, "other.test").await.unwrap();
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()[LOCATION], "https://known.test/some/page");
+    }
+}
@@ -0,0 +1,367 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use hyper::HeaderMap;
+use once_cell::sync::Lazy;
+use tokio::time::Instant;
+
+use crate::config_loader::{Algorithm, RateLimit, RateLimitKey};
+
+/// How often expired counters are cleared out.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Hash, PartialEq, Eq, Clone)]
+struct Key {
+    host: String,
+    route: String,
+    /// Position of the limit in its list.
+    limit: usize,
+    client: String,
+}
+
+enum Counter {
+    TokenBucket {
+        tokens: f64,
+        updated: Instant,
+    },
+    /// Approximates a sliding window by weighting the previous fixed window.
+    SlidingWindow {
+        start: Instant,
+        previous: u32,
+        current: u32,
+    },
+}
+
+struct Slot {
+    counter: Counter,
+    /// Once passed, the counter is back to its initial state and can be dropped.
+    expires: Instant,
+}
+
+static COUNTERS: Lazy<DashMap<Key, Slot>> = Lazy::new(DashMap::new);
+
+static CONNECTIONS: Lazy<DashMap<IpAddr, u32>> = Lazy::new(DashMap::new);
+
+/// Check `limits` for one request, returning how long to wait if any is exceeded.
+pub fn check(
+    host: &str,
+    route: &str,
+    limits: &[RateLimit],
+    client: IpAddr,
+    headers: &HeaderMap,
+) -> Result<(), Duration> {
+    let now = Instant::now();
+    let mut keys = Vec::with_capacity(limits.len());
+
+    for (i, limit) in limits.iter().enumerate() {
+        let client = match &limit.key {
+            RateLimitKey::Ip => client.to_string(),
+            RateLimitKey::Route => String::new(),
+            // Requests without the header share the limit of their IP
+            RateLimitKey::Header(name) => match headers.get(name).and_then(|v| v.to_str().ok()) {
+                Some(value) => format!("header:{value}"),
+                None => client.to_string(),
+            },
+        };
+
+        keys.push(Key {
+            host: host.to_owned(),
+            route: route.to_owned(),
+            limit: i,
+            client,
+        });
+    }
+
+    let retry_after = limits
+        .iter()
+        .zip(&keys)
+        .filter_map(|(limit, key)| take(limit, key.clone(), now, false).err())
+        .max();
+    if let Some(wait) = retry_after {
+        return Err(wait);
+    }
+
+    // Only charged once all of them allow it, so a client over its own limit
+    // does not use up one it shares with everybody else
+    for (limit, key) in limits.iter().zip(keys) {
+        _ = take(limit, key, now, true);
+    }
+    Ok(())
+}
+
+/// Whether `limit` allows a request now, using up its share if `charge`.
+fn take(limit: &RateLimit, key: Key, now: Instant, charge: bool) -> Result<(), Duration> {
+    let per = Duration::from_secs_f64(limit.per.max(0.001));
+    let limit_count = limit.limit.max(1);
+
+    let mut entry = COUNTERS.entry(key).or_insert_with(|| Slot {
+        counter: match limit.algorithm {
+            Algorithm::TokenBucket => Counter::TokenBucket {
+                tokens: limit.burst.unwrap_or(limit_count) as f64,
+                updated: now,
+            },
+            Algorithm::SlidingWindow => Counter::SlidingWindow {
+                start: now,
+                previous: 0,
+                current: 0,
+            },
+        },
+        expires: now,
+    });
+    let entry = entry.value_mut();
+
+    match &mut entry.counter {
+        Counter::TokenBucket { tokens, updated } => {
+            let capacity = limit.burst.unwrap_or(limit_count) as f64;
+            let rate = limit_count as f64 / per.as_secs_f64();
+
+            *tokens = (*tokens + (now - *updated).as_secs_f64() * rate).min(capacity);
+            *updated = now;
+
+            let result = if *tokens >= 1.0 {
+                if charge {
+                    *tokens -= 1.0;
+                }
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64((1.0 - *tokens) / rate))
+            };
+
+            entry.expires = now + Duration::from_secs_f64((capacity - *tokens) / rate);
+            result
+        }
+        Counter::SlidingWindow {
+            start,
+            previous,
+            current,
+        } => {
+            let elapsed = now - *start;
+            if elapsed >= per * 2 {
+                *start = now;
+                *previous = 0;
+                *current = 0;
+            } else if elapsed >= per {
+                *start += per;
+                *previous = *current;
+                *current = 0;
+            }
+
+            let into_window = (now - *start).as_secs_f64() / per.as_secs_f64();
+            let estimate = *previous as f64 * (1.0 - into_window) + *current as f64;
+
+            entry.expires = *start + per * 2;
+
+            if estimate + 1.0 <= limit_count as f64 {
+                if charge {
+                    *current += 1;
+                }
+                return Ok(());
+            }
+
+            // Wait for the previous window's share to fall far enough, or for the
+            // next window if this one alone is full.
+            let wait = if *current + 1 > limit_count || *previous == 0 {
+                *start + per - now
+            } else {
+                let needed = 1.0 - (limit_count - *current - 1) as f64 / *previous as f64;
+                Duration::from_secs_f64((needed - into_window).max(0.0) * per.as_secs_f64())
+            };
+            Err(wait)
+        }
+    }
+}
+
+/// Drop counters which are back to their initial state, forever.
+pub async fn sweep_expired() {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        COUNTERS.retain(|_, entry| entry.expires > now);
+    }
+}
+
+/// Held for as long as a connection from `ip` is open.
+pub struct ConnectionPermit(IpAddr);
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Entry::Occupied(mut open) = CONNECTIONS.entry(self.0) {
+            *open.get_mut() -= 1;
+            if *open.get() == 0 {
+                open.remove();
+            }
+        }
+    }
+}
+
+/// Count a new connection from `ip`, unless it already has too many open.
+pub fn connection_permit(ip: IpAddr, max: Option<u32>) -> Option<ConnectionPermit> {
+    // Checked before the entry is made, so refused IPs are not left behind
+    match CONNECTIONS.entry(ip) {
+        Entry::Occupied(mut open) if max.is_none_or(|max| *open.get() < max) => {
+            *open.get_mut() += 1;
+        }
+        Entry::Vacant(vacant) if max.is_none_or(|max| max > 0) => {
+            vacant.insert(1);
+        }
+        _ => return None,
+    }
+
+    Some(ConnectionPermit(ip))
+}
+
+/// Open connections by client IP.
+pub fn connections() -> Vec<(IpAddr, u32)> {
+    CONNECTIONS
+        .iter()
+        .map(|open| (*open.key(), *open.value()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(limit: u32, per: f64, algorithm: Algorithm) -> RateLimit {
+        RateLimit {
+            key: RateLimitKey::Ip,
+            limit,
+            per,
+            burst: None,
+            algorithm,
+        }
+    }
+
+    fn key(host: &str) -> Key {
+        Key {
+            host: host.to_owned(),
+            route: String::new(),
+            limit: 0,
+            client: "127.0.0.1".into(),
+        }
+    }
+
+    #[test]
+    fn token_bucket_refills() {
+        let limit = limit(2, 10.0, Algorithm::TokenBucket);
+        let now = Instant::now();
+        let take = |after| {
+            take(
+                &limit,
+                key("bucket.test"),
+                now + Duration::from_secs(after),
+                true,
+            )
+        };
+
+        assert!(take(0).is_ok());
+        assert!(take(0).is_ok());
+        // One token comes back every 5s
+        assert_eq!(take(0), Err(Duration::from_secs(5)));
+        assert_eq!(take(4), Err(Duration::from_secs(1)));
+        assert!(take(5).is_ok());
+        assert!(take(5).is_err());
+        // Never more than the burst
+        assert!(take(100).is_ok());
+        assert!(take(100).is_ok());
+        assert!(take(100).is_err());
+    }
+
+    #[test]
+    fn sliding_window_weights_previous() {
+        let limit = limit(2, 10.0, Algorithm::SlidingWindow);
+        let now = Instant::now();
+        let take = |after| {
+            take(
+                &limit,
+                key("window.test"),
+                now + Duration::from_secs(after),
+                true,
+            )
+        };
+
+        assert!(take(0).is_ok());
+        assert!(take(0).is_ok());
+        // This window is full until the next one starts
+        assert_eq!(take(4), Err(Duration::from_secs(6)));
+        // Half way into the next, the previous still counts for one
+        assert!(take(15).is_ok());
+        assert_eq!(take(15), Err(Duration::from_secs(5)));
+        // After two windows nothing is left of the first
+        assert!(take(30).is_ok());
+        assert!(take(30).is_ok());
+    }
+
+    #[test]
+    fn longest_wait_returned() {
+        let limits = [
+            limit(1, 10.0, Algorithm::TokenBucket),
+            limit(1, 60.0, Algorithm::TokenBucket),
+        ];
+        let ip = "127.0.0.2".parse().unwrap();
+        let headers = HeaderMap::new();
+
+        assert!(check("retry.test", "", &limits, ip, &headers).is_ok());
+        let wait = check("retry.test", "", &limits, ip, &headers).unwrap_err();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn rejected_requests_not_charged() {
+        let limits = [
+            limit(1, 60.0, Algorithm::TokenBucket),
+            RateLimit {
+                key: RateLimitKey::Route,
+                ..limit(3, 60.0, Algorithm::SlidingWindow)
+            },
+        ];
+        let abusive = "127.0.0.6".parse().unwrap();
+        let other = "127.0.0.7".parse().unwrap();
+        let headers = HeaderMap::new();
+        let check = |ip| check("shared.test", "/api", &limits, ip, &headers);
+
+        assert!(check(abusive).is_ok());
+        for _ in 0..5 {
+            assert!(check(abusive).is_err());
+        }
+        // The route's limit only counts the one request let through
+        assert!(check(other).is_ok());
+        assert!(check("127.0.0.8".parse().unwrap()).is_ok());
+        assert!(check("127.0.0.9".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn period_checked_when_parsed() {
+        let parse = |per: f64| {
+            serde_json::from_value::<RateLimit>(serde_json::json!({ "limit": 1, "per": per }))
+        };
+        assert!(parse(60.0).is_ok());
+        assert!(parse(1e300).is_err());
+        assert!(parse(0.0).is_err());
+        assert!(parse(-1.0).is_err());
+    }
+
+    #[test]
+    fn connections_limited_per_ip() {
+        let ip = "127.0.0.3".parse().unwrap();
+
+        let first = connection_permit(ip, Some(2)).unwrap();
+        let second = connection_permit(ip, Some(2)).unwrap();
+        assert!(connection_permit(ip, Some(2)).is_none());
+        drop(first);
+        assert!(connection_permit(ip, Some(2)).is_some());
+        drop(second);
+        assert!(!CONNECTIONS.contains_key(&ip));
+    }
+
+    #[test]
+    fn refused_ips_not_counted() {
+        let ip = "127.0.0.4".parse().unwrap();
+
+        assert!(connection_permit(ip, Some(0)).is_none());
+        assert!(!CONNECTIONS.contains_key(&ip));
+    }
+}
@@ -0,0 +1,191 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::Request;
+use once_cell::sync::Lazy;
+
+use crate::config_loader::{RequestId, RequestIdFormat};
+use crate::proxy::ClientInfo;
+use crate::HOSTS;
+
+/// Longest ID taken from a trusted client.
+const MAX_LENGTH: usize = 128;
+
+pub static HEADER: Lazy<HeaderName> = Lazy::new(|| {
+    let name = HOSTS.load().global.request_id.header.clone();
+    HeaderName::try_from(&name).unwrap_or_else(|_| {
+        tracing::error!("Invalid request ID header name {name:?}, using X-Request-Id");
+        HeaderName::from_static("x-request-id")
+    })
+});
+
+/// Picks the request's ID and sets it on the request, for the upstream.
+pub fn assign<B>(config: &RequestId, req: &mut Request<B>) -> String {
+    assign_with(config, &HEADER, req)
+}
+
+fn assign_with<B>(config: &RequestId, header: &HeaderName, req: &mut Request<B>) -> String {
+    let trusted = req.extensions().get::<ClientInfo>().is_some_and(|c| {
+        config
+            .trusted
+            .iter()
+            .any(|net| net.0.contains(&c.addr.ip()))
+    });
+
+    let id = req
+        .headers()
+        .get(header)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| trusted && valid(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| generate(config.format));
+
+    // Anything an untrusted client sent is replaced
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        req.headers_mut().insert(header, value);
+    }
+    id
+}
+
+/// IDs end up in logs and pages, so only plain ones are kept.
+fn valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id.bytes().all(|b| {
+            b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':' | b'+' | b'/' | b'=')
+        })
+}
+
+fn generate(format: RequestIdFormat) -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let mut random = [0; 10];
+    OsRng.fill_bytes(&mut random);
+
+    match format {
+        RequestIdFormat::Uuid7 => uuid7(millis, random),
+        RequestIdFormat::Ulid => ulid(millis, random),
+    }
+}
+
+/// RFC 9562 version 7: milliseconds since the epoch, then random bits.
+fn uuid7(millis: u64, random: [u8; 10]) -> String {
+    let mut bytes = [0; 16];
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    bytes[6..].copy_from_slice(&random);
+    bytes[6] = 0x70 | (bytes[6] & 0x0f);
+    bytes[8] = 0x80 | (bytes[8] & 0x3f);
+
+    let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// 48 bits of milliseconds and 80 random bits, in Crockford's base 32.
+fn ulid(millis: u64, random: [u8; 10]) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+    let mut value = u128::from(millis & 0xffff_ffff_ffff) << 80;
+    for (i, byte) in random.iter().enumerate() {
+        value |= u128::from(*byte) << (72 - 8 * i);
+    }
+
+    (0..26)
+        .rev()
+        .map(|i| ALPHABET[((value >> (5 * i)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::config_loader::Cidr;
+    use crate::geoip::Location;
+
+    fn request(from: &str, id: Option<&str>) -> Request<()> {
+        let mut req = Request::builder();
+        if let Some(id) = id {
+            req = req.header("x-request-id", id);
+        }
+        let mut req = req.body(()).unwrap();
+        req.extensions_mut().insert(ClientInfo {
+            addr: from.parse::<SocketAddr>().unwrap(),
+            location: Location::default(),
+            tls_version: None,
+            cert: None,
+        });
+        req
+    }
+
+    #[test]
+    fn uuid7_layout() {
+        let id = uuid7(0x0192_a5b4_c3d2, [0xff; 10]);
+        assert_eq!(id, "0192a5b4-c3d2-7fff-bfff-ffffffffffff");
+
+        let id = generate(RequestIdFormat::Uuid7);
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "7");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"));
+    }
+
+    #[test]
+    fn ulid_layout() {
+        assert_eq!(ulid(0, [0; 10]), "00000000000000000000000000");
+        assert_eq!(
+            ulid(0xffff_ffff_ffff, [0xff; 10]),
+            "7ZZZZZZZZZZZZZZZZZZZZZZZZZ"
+        );
+        assert_eq!(ulid(1, [0; 10]), "0000000001".to_owned() + &"0".repeat(16));
+
+        let id = generate(RequestIdFormat::Ulid);
+        assert_eq!(id.len(), 26);
+        assert!(id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b.is_ascii_uppercase()));
+    }
+
+    #[test]
+    fn sorted_by_time() {
+        assert!(uuid7(1_000, [0xff; 10]) < uuid7(1_001, [0; 10]));
+        assert!(ulid(1_000, [0xff; 10]) < ulid(1_001, [0; 10]));
+    }
+
+    #[test]
+    fn only_trusted_ids_kept() {
+        let config = RequestId {
+            trusted: vec!["10.0.0.0/8".parse::<Cidr>().unwrap()],
+            ..RequestId::default()
+        };
+        let header = HeaderName::from_static("x-request-id");
+
+        let mut req = request("10.1.2.3:5000", Some("lb-1234"));
+        assert_eq!(assign_with(&config, &header, &mut req), "lb-1234");
+        assert_eq!(req.headers()["x-request-id"], "lb-1234");
+
+        let mut req = request("203.0.113.9:5000", Some("spoofed"));
+        let id = assign_with(&config, &header, &mut req);
+        assert_ne!(id, "spoofed");
+        assert_eq!(req.headers()["x-request-id"], id.as_str());
+
+        // Trusted, but not something to put in a page
+        let mut req = request("10.1.2.3:5000", Some("<script>"));
+        assert_ne!(assign_with(&config, &header, &mut req), "<script>");
+        let mut req = request("10.1.2.3:5000", Some(&"a".repeat(200)));
+        assert_eq!(assign_with(&config, &header, &mut req).len(), 36);
+
+        let mut req = request("10.1.2.3:5000", None);
+        assert_eq!(assign_with(&config, &header, &mut req).len(), 36);
+    }
+}
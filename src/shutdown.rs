@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Flipped to `true` once Envoi starts shutting down.
+///
+/// Every open connection holds a receiver, so once all receivers are gone
+/// there is nothing left to drain.
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// A handle held for as long as a connection is open.
+pub fn subscribe() -> watch::Receiver<bool> {
+    SHUTDOWN.subscribe()
+}
+
+/// Resolves once shutdown has been triggered.
+pub async fn triggered() {
+    let mut rx = subscribe();
+    _ = rx.wait_for(|stopping| *stopping).await;
+}
+
+pub fn trigger() {
+    SHUTDOWN.send_replace(true);
+}
+
+/// Wait for SIGTERM or SIGINT.
+pub async fn signal_received() {
+    let mut term = signal(SignalKind::terminate()).unwrap();
+
+    tokio::select! {
+        _ = term.recv() => tracing::info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("Received SIGINT"),
+    }
+}
+
+/// Wait for all connections to close, returns false if `deadline` passed first.
+pub async fn drain(deadline: Option<Duration>) -> bool {
+    tracing::info!("Draining open connections");
+
+    match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, SHUTDOWN.closed())
+            .await
+            .is_ok(),
+        None => {
+            SHUTDOWN.closed().await;
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_gives_up_at_deadline() {
+        let open = subscribe();
+        assert!(!drain(Some(Duration::from_millis(50))).await);
+
+        drop(open);
+        assert!(drain(Some(Duration::from_millis(50))).await);
+    }
+}
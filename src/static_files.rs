@@ -0,0 +1,485 @@
+use std::fmt::Write;
+use std::fs::Metadata;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use http_body_util::BodyExt;
+use hyper::header::{HeaderValue, ALLOW, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION};
+use hyper::{Method, Request, Response, StatusCode};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::config_loader::ServeDir;
+use crate::handlers;
+use crate::proxy::{full, ProxyBody};
+
+/// Characters escaped in the links of a directory listing.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Serve `req` from the directory in `config`.
+pub async fn serve<B>(config: &ServeDir, req: Request<B>) -> Response<ProxyBody>
+where
+    B: Send + 'static,
+{
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, "GET, HEAD")
+            .body(full(""))
+            .unwrap();
+    }
+
+    let root = Path::new(&config.root);
+
+    let Some(path) = resolve(root, req.uri().path()) else {
+        tracing::warn!("Rejected static path {:?}", req.uri().path());
+        return handlers::not_found();
+    };
+
+    let metadata = match tokio::fs::metadata(&path).await {
+        Ok(metadata) if within_root(root, &path).await => metadata,
+        _ => return fallback(config, req).await,
+    };
+
+    if metadata.is_dir() {
+        // Relative links in the index only work from `/dir/`, not `/dir`
+        if !req.uri().path().ends_with('/') {
+            let location = match req.uri().query() {
+                Some(query) => format!("{}/?{query}", req.uri().path()),
+                None => format!("{}/", req.uri().path()),
+            };
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(LOCATION, location)
+                .body(full(""))
+                .unwrap();
+        }
+
+        for index in &config.index {
+            let index = path.join(index);
+            if let Ok(metadata) = tokio::fs::metadata(&index).await {
+                if metadata.is_file() {
+                    return serve_file(config, &index, &metadata, req).await;
+                }
+            }
+        }
+
+        if config.listing {
+            return listing(&path, req.uri().path()).await;
+        }
+
+        return fallback(config, req).await;
+    }
+
+    serve_file(config, &path, &metadata, req).await
+}
+
+/// Map a request path onto `root`, refusing anything that could step outside it.
+fn resolve(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+
+    let mut path = root.to_path_buf();
+    for segment in decoded.split('/') {
+        if segment.contains(['\\', '\0']) {
+            return None;
+        }
+
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (None, _) | (Some(Component::CurDir), None) => {}
+            (Some(Component::Normal(name)), None) => path.push(name),
+            _ => return None,
+        }
+    }
+
+    Some(path)
+}
+
+/// Symlinks inside the root must not lead out of it.
+async fn within_root(root: &Path, path: &Path) -> bool {
+    match (
+        tokio::fs::canonicalize(root).await,
+        tokio::fs::canonicalize(path).await,
+    ) {
+        (Ok(root), Ok(path)) => path.starts_with(root),
+        _ => false,
+    }
+}
+
+async fn fallback<B>(config: &ServeDir, req: Request<B>) -> Response<ProxyBody>
+where
+    B: Send + 'static,
+{
+    if config.spa {
+        for index in &config.index {
+            let index = Path::new(&config.root).join(index);
+            if let Ok(metadata) = tokio::fs::metadata(&index).await {
+                if metadata.is_file() {
+                    return serve_file(config, &index, &metadata, req).await;
+                }
+            }
+        }
+    }
+
+    handlers::not_found()
+}
+
+/// Weak, as precompressed variants of a file share it.
+fn etag(metadata: &Metadata) -> Option<HeaderValue> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    HeaderValue::from_str(&format!(
+        "W/\"{:x}-{:x}\"",
+        metadata.len(),
+        modified.as_nanos()
+    ))
+    .ok()
+}
+
+fn etag_matches(etag: &HeaderValue, if_none_match: &HeaderValue) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let ours = etag.to_str().unwrap_or_default().trim_start_matches("W/");
+
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == ours)
+}
+
+/// Range requests, `Last-Modified` and the precompressed variants are handled by
+/// `ServeFile`; `ETag` is added here.
+async fn serve_file<B>(
+    config: &ServeDir,
+    path: &Path,
+    metadata: &Metadata,
+    req: Request<B>,
+) -> Response<ProxyBody>
+where
+    B: Send + 'static,
+{
+    // Every file served is checked, whichever way it was found
+    let root = Path::new(&config.root);
+    if !within_root(root, path).await {
+        tracing::warn!("Refused {} outside of the root", path.display());
+        return handlers::not_found();
+    }
+
+    let etag = etag(metadata);
+
+    if let (Some(etag), Some(if_none_match)) = (&etag, req.headers().get(IF_NONE_MATCH)) {
+        if etag_matches(etag, if_none_match) {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(ETAG, etag)
+                .body(full(""))
+                .unwrap();
+        }
+    }
+
+    let mut service = ServeFile::new(path);
+    if config.precompressed {
+        // `ServeFile` opens these by name, so only offer those which stay inside
+        if within_root(root, &sidecar(path, "br")).await {
+            service = service.precompressed_br();
+        }
+        if within_root(root, &sidecar(path, "gz")).await {
+            service = service.precompressed_gzip();
+        }
+    }
+
+    let Ok(res) = service.oneshot(req).await;
+    let mut res = res.map(|body| body.map_err(Into::into).boxed_unsync());
+
+    if let Some(etag) = etag {
+        if res.status().is_success() {
+            res.headers_mut().insert(ETAG, etag);
+        }
+    }
+
+    res
+}
+
+/// The precompressed variant of `path` with the extension `ext`.
+fn sidecar(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+async fn listing(dir: &Path, request_path: &str) -> Response<ProxyBody> {
+    let mut entries = Vec::new();
+
+    if let Ok(mut read_dir) = tokio::fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = read_dir.next_entry().await {
+            let is_dir = entry.file_type().await.is_ok_and(|t| t.is_dir());
+            entries.push((!is_dir, entry.file_name().to_string_lossy().into_owned()));
+        }
+    }
+
+    // Directories first, then by name
+    entries.sort();
+
+    let title = escape_html(&percent_decode_str(request_path).decode_utf8_lossy());
+    let mut html = format!(
+        "<!DOCTYPE html>\n<title>Index of {title}</title>\n<h1>Index of {title}</h1>\n<ul>\n"
+    );
+
+    if request_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+
+    for (is_file, name) in entries {
+        let slash = if is_file { "" } else { "/" };
+        _ = writeln!(
+            html,
+            "<li><a href=\"{}{slash}\">{}{slash}</a></li>",
+            utf8_percent_encode(&name, PATH_SEGMENT),
+            escape_html(&name),
+        );
+    }
+
+    html.push_str("</ul>\n");
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(full(html))
+        .unwrap()
+}
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, RANGE};
+
+    fn config(root: &Path) -> ServeDir {
+        ServeDir {
+            root: root.to_string_lossy().into_owned(),
+            index: vec!["index.html".into()],
+            listing: false,
+            spa: false,
+            precompressed: true,
+        }
+    }
+
+    fn site() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.html"), "home").unwrap();
+        std::fs::write(dir.path().join("style.css"), "0123456789").unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/a <b>.txt"), "a").unwrap();
+        dir
+    }
+
+    fn get(path: &str) -> Request<()> {
+        Request::get(path).body(()).unwrap()
+    }
+
+    async fn body(res: Response<ProxyBody>) -> String {
+        let bytes = res.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn resolve_rejects_traversal() {
+        let root = Path::new("/srv/www");
+
+        for path in [
+            "/../etc/passwd",
+            "/docs/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/%2E%2E%2Fetc%2Fpasswd",
+            "/..%5c..%5cetc%5cpasswd",
+            "/docs/..\\..\\etc",
+            "/%00",
+            "/%ff",
+        ] {
+            assert_eq!(resolve(root, path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn resolve_stays_in_root() {
+        let root = Path::new("/srv/www");
+
+        assert_eq!(resolve(root, "/"), Some(PathBuf::from("/srv/www")));
+        assert_eq!(
+            resolve(root, "/./docs//a%20b.txt"),
+            Some(PathBuf::from("/srv/www/docs/a b.txt"))
+        );
+    }
+
+    #[tokio::test]
+    async fn symlink_out_of_root_is_not_served() {
+        let dir = site();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+
+        let res = serve(&config(dir.path()), get("/link/secret")).await;
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn symlinked_index_out_of_root_is_not_served() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), dir.path().join("index.html"))
+            .unwrap();
+
+        let res = serve(&config(dir.path()), get("/")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let mut config = config(dir.path());
+        config.spa = true;
+        let res = serve(&config, get("/app/route")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn symlinked_sidecar_out_of_root_is_not_served() {
+        let dir = site();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("secret"),
+            dir.path().join("style.css.gz"),
+        )
+        .unwrap();
+
+        let req = Request::get("/style.css")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let res = serve(&config(dir.path()), req).await;
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(body(res).await, "0123456789");
+    }
+
+    #[tokio::test]
+    async fn serves_index_and_redirects_directories() {
+        let dir = site();
+
+        let res = serve(&config(dir.path()), get("/")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, "home");
+
+        let res = serve(&config(dir.path()), get("/docs?x=1")).await;
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers()[LOCATION], "/docs/?x=1");
+    }
+
+    #[tokio::test]
+    async fn listing_only_when_enabled() {
+        let dir = site();
+
+        let res = serve(&config(dir.path()), get("/docs/")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let mut config = config(dir.path());
+        config.listing = true;
+        let res = serve(&config, get("/docs/")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let html = body(res).await;
+        assert!(html.contains("href=\"a%20%3Cb%3E.txt\""), "{html}");
+        assert!(html.contains("a &lt;b&gt;.txt"), "{html}");
+    }
+
+    #[tokio::test]
+    async fn spa_falls_back_to_index() {
+        let dir = site();
+
+        let res = serve(&config(dir.path()), get("/app/route")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let mut config = config(dir.path());
+        config.spa = true;
+        let res = serve(&config, get("/app/route")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, "home");
+    }
+
+    #[tokio::test]
+    async fn prefers_precompressed_sidecar() {
+        let dir = site();
+        std::fs::write(dir.path().join("style.css.gz"), "gzipped").unwrap();
+
+        let req = Request::get("/style.css")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(())
+            .unwrap();
+        let res = serve(&config(dir.path()), req).await;
+
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/css");
+        assert_eq!(body(res).await, "gzipped");
+
+        let res = serve(&config(dir.path()), get("/style.css")).await;
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(body(res).await, "0123456789");
+    }
+
+    #[tokio::test]
+    async fn etag_revalidates() {
+        let dir = site();
+
+        let res = serve(&config(dir.path()), get("/style.css")).await;
+        let etag = res.headers()[ETAG].clone();
+
+        let req = Request::get("/style.css")
+            .header(IF_NONE_MATCH, etag)
+            .body(())
+            .unwrap();
+        let res = serve(&config(dir.path()), req).await;
+
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn serves_ranges() {
+        let dir = site();
+
+        let req = Request::get("/style.css")
+            .header(RANGE, "bytes=2-4")
+            .body(())
+            .unwrap();
+        let res = serve(&config(dir.path()), req).await;
+
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body(res).await, "234");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_methods() {
+        let dir = site();
+
+        let req = Request::post("/").body(()).unwrap();
+        let res = serve(&config(dir.path()), req).await;
+
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,505 @@
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Request, StatusCode};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::access_log::Upstream;
+use crate::config_loader::Telemetry;
+use crate::proxy::{full, ClientInfo};
+use crate::{upstream, HOSTS};
+
+static TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+static TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+
+/// Spans waiting to be exported. Once full, new spans are dropped rather than
+/// holding up requests.
+const QUEUE: usize = 4096;
+/// Most spans sent to the collector at once.
+const BATCH: usize = 512;
+const INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static SPANS: Lazy<(mpsc::Sender<Value>, Mutex<mpsc::Receiver<Value>>)> = Lazy::new(|| {
+    let (tx, rx) = mpsc::channel(QUEUE);
+    (tx, Mutex::new(rx))
+});
+
+/// W3C trace context, as carried in `traceparent`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    fn parse(value: &str) -> Option<TraceContext> {
+        let value = value.trim();
+        let version = u8::from_str_radix(value.get(..2)?, 16).ok()?;
+        // Later versions may add fields after these, but must keep these as they are
+        let valid_length = match version {
+            0 => value.len() == 55,
+            0xff => false,
+            _ => value.len() == 55 || value.as_bytes().get(55) == Some(&b'-'),
+        };
+        if !valid_length {
+            return None;
+        }
+
+        let mut fields = value[..55].split('-').skip(1);
+        let trace_id = unhex::<16>(fields.next()?)?;
+        let span_id = unhex::<8>(fields.next()?)?;
+        let flags = unhex::<1>(fields.next()?)?[0];
+
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            u8::from(self.sampled)
+        )
+    }
+
+    pub fn trace_id(&self) -> String {
+        hex(&self.trace_id)
+    }
+}
+
+/// Lowercase only, as the spec requires.
+fn unhex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2
+        || !value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Envoi's span for one request, exported once the response has been sent.
+pub struct Span {
+    pub context: TraceContext,
+    parent: Option<[u8; 8]>,
+    state: Option<String>,
+    start: SystemTime,
+    method: String,
+    path: String,
+    host: String,
+    route: String,
+    client: Option<IpAddr>,
+    request_id: String,
+}
+
+/// Continues the client's trace, or starts a new one, and passes it on upstream
+/// with Envoi's span as the parent.
+pub fn start<B>(req: &mut Request<B>, route: &str, request_id: &str) -> Span {
+    let headers = req.headers();
+    let mut traceparents = headers.get_all(&TRACEPARENT).iter();
+    let parent = match (traceparents.next(), traceparents.next()) {
+        (Some(value), None) => value.to_str().ok().and_then(TraceContext::parse),
+        _ => None,
+    };
+
+    // Only meaningful alongside the traceparent it came with
+    let state = match parent {
+        Some(_) => {
+            let state = headers
+                .get_all(&TRACESTATE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            (!state.is_empty()).then_some(state)
+        }
+        None => None,
+    };
+
+    let mut span_id = [0; 8];
+    OsRng.fill_bytes(&mut span_id);
+    let context = match &parent {
+        Some(parent) => TraceContext {
+            span_id,
+            ..parent.clone()
+        },
+        None => {
+            let mut trace_id = [0; 16];
+            OsRng.fill_bytes(&mut trace_id);
+            TraceContext {
+                trace_id,
+                span_id,
+                sampled: HOSTS.load().global.telemetry.is_some(),
+            }
+        }
+    };
+
+    let span = Span {
+        parent: parent.map(|p| p.span_id),
+        state,
+        start: SystemTime::now(),
+        method: req.method().to_string(),
+        path: req.uri().path().to_owned(),
+        host: headers
+            .get("host")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default()
+            .to_owned(),
+        route: route.to_owned(),
+        client: req.extensions().get::<ClientInfo>().map(|c| c.addr.ip()),
+        request_id: request_id.to_owned(),
+        context,
+    };
+
+    let headers = req.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&span.context.traceparent()) {
+        headers.insert(&TRACEPARENT, value);
+    }
+    if span.state.is_none() {
+        headers.remove(&TRACESTATE);
+    }
+
+    span
+}
+
+impl Span {
+    /// Queues the span for export. `status` is `None` when the connection was
+    /// dropped instead.
+    pub fn finish(self, status: Option<StatusCode>, upstream: Option<&Upstream>) {
+        if !self.context.sampled || HOSTS.load().global.telemetry.is_none() {
+            return;
+        }
+
+        if SPANS.0.try_send(self.to_otlp(status, upstream)).is_err() {
+            tracing::debug!("Span export queue full, dropping a span");
+        }
+    }
+
+    /// The OTLP/JSON form of the span.
+    fn to_otlp(&self, status: Option<StatusCode>, upstream: Option<&Upstream>) -> Value {
+        let mut attributes = vec![
+            string("http.request.method", &self.method),
+            string("url.path", &self.path),
+            string("server.address", &self.host),
+            string("envoi.request_id", &self.request_id),
+        ];
+        if !self.route.is_empty() {
+            attributes.push(string("http.route", &self.route));
+        }
+        if let Some(client) = self.client {
+            attributes.push(string("client.address", &client.to_string()));
+        }
+        if let Some(status) = status {
+            attributes.push(int("http.response.status_code", status.as_u16().into()));
+        }
+        if let Some(upstream) = upstream {
+            attributes.push(string("envoi.upstream", &upstream.destination));
+            attributes.push(int(
+                "envoi.upstream.duration_ms",
+                upstream.latency.as_millis() as i64,
+            ));
+        }
+
+        let name = match self.route.is_empty() {
+            true => self.method.clone(),
+            false => format!("{} {}", self.method, self.route),
+        };
+        // Unset, or error for server errors and dropped connections
+        let code = match status {
+            Some(status) if !status.is_server_error() => 0,
+            _ => 2,
+        };
+
+        let mut span = json!({
+            "traceId": hex(&self.context.trace_id),
+            "spanId": hex(&self.context.span_id),
+            "name": name,
+            // Server
+            "kind": 2,
+            "startTimeUnixNano": nanos(self.start).to_string(),
+            "endTimeUnixNano": nanos(SystemTime::now()).to_string(),
+            "attributes": attributes,
+            "status": { "code": code },
+        });
+        if let Some(parent) = &self.parent {
+            span["parentSpanId"] = hex(parent).into();
+        }
+        if let Some(state) = &self.state {
+            span["traceState"] = state.clone().into();
+        }
+        span
+    }
+}
+
+fn string(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn int(key: &str, value: i64) -> Value {
+    // 64 bit integers are strings in OTLP/JSON
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Sends queued spans to the collector every few seconds.
+pub async fn export(config: Telemetry) {
+    let mut interval = tokio::time::interval(INTERVAL);
+    loop {
+        interval.tick().await;
+        flush(&config).await;
+    }
+}
+
+/// Sends everything queued so far.
+pub async fn flush(config: &Telemetry) {
+    let mut queue = SPANS.1.lock().await;
+
+    let mut batch = Vec::new();
+    while let Ok(span) = queue.try_recv() {
+        batch.push(span);
+        if batch.len() == BATCH {
+            send(config, std::mem::take(&mut batch)).await;
+        }
+    }
+    if !batch.is_empty() {
+        send(config, batch).await;
+    }
+}
+
+async fn send(config: &Telemetry, spans: Vec<Value>) {
+    let count = spans.len();
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [string("service.name", &config.service_name)],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "envoi", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    });
+
+    let mut req = Request::post(&config.endpoint).header(CONTENT_TYPE, "application/json");
+    for (name, value) in &config.headers {
+        req = req.header(name, value);
+    }
+    let req = match req.body(full(body.to_string())) {
+        Ok(req) => req,
+        Err(e) => {
+            tracing::warn!("Could not export spans to {}: {e}", config.endpoint);
+            return;
+        }
+    };
+
+    match upstream::fetch(req, EXPORT_TIMEOUT).await {
+        Ok((status, _)) if status.is_success() => {}
+        Ok((status, body)) => tracing::warn!(
+            "Collector at {} refused {count} spans: {status} {}",
+            config.endpoint,
+            String::from_utf8_lossy(&body)
+        ),
+        Err(e) => tracing::warn!("Could not export {count} spans to {}: {e}", config.endpoint),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_traceparent() {
+        let parent = TraceContext::parse(PARENT).unwrap();
+        assert_eq!(parent.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&parent.span_id), "00f067aa0ba902b7");
+        assert!(parent.sampled);
+        assert_eq!(parent.traceparent(), PARENT);
+
+        let unsampled = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+        assert!(!TraceContext::parse(unsampled).unwrap().sampled);
+
+        // A later version with more fields
+        let future = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-09-extra";
+        assert!(TraceContext::parse(future).unwrap().sampled);
+
+        for invalid in [
+            "",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-600f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn continues_the_clients_trace() {
+        let mut req = Request::builder()
+            .uri("/api/items")
+            .header("host", "media.test")
+            .header(&TRACEPARENT, PARENT)
+            .header(&TRACESTATE, "vendor=abc")
+            .body(())
+            .unwrap();
+
+        let span = start(&mut req, "/api", "00000001");
+        assert_eq!(span.context.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(hex(&span.context.span_id), "00f067aa0ba902b7");
+
+        // Upstream sees Envoi's span as the parent
+        assert_eq!(
+            req.headers()[&TRACEPARENT],
+            span.context.traceparent().as_str()
+        );
+        assert_eq!(req.headers()[&TRACESTATE], "vendor=abc");
+
+        let upstream = Upstream {
+            destination: "http://10.0.0.5:8096".into(),
+            latency: Duration::from_millis(12),
+        };
+        let otlp = span.to_otlp(Some(StatusCode::OK), Some(&upstream));
+        assert_eq!(otlp["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(otlp["traceState"], "vendor=abc");
+        assert_eq!(otlp["name"], "GET /api");
+        assert_eq!(otlp["status"]["code"], 0);
+        let attributes = otlp["attributes"].as_array().unwrap();
+        assert!(attributes.contains(&string("envoi.upstream", "http://10.0.0.5:8096")));
+        assert!(attributes.contains(&int("http.response.status_code", 200)));
+    }
+
+    #[test]
+    fn starts_a_trace_when_absent() {
+        let mut req = Request::builder()
+            .header(&TRACEPARENT, "garbage")
+            .header(&TRACESTATE, "vendor=abc")
+            .body(())
+            .unwrap();
+
+        let span = start(&mut req, "", "00000002");
+        assert_ne!(span.context.trace_id, [0; 16]);
+        let sent = req.headers()[&TRACEPARENT].to_str().unwrap();
+        assert_eq!(TraceContext::parse(sent), Some(span.context.clone()));
+        // Without a valid parent, its state means nothing
+        assert!(req.headers().get(&TRACESTATE).is_none());
+
+        let otlp = span.to_otlp(None, None);
+        assert!(otlp.get("parentSpanId").is_none());
+        assert_eq!(otlp["name"], "GET");
+        assert_eq!(otlp["status"]["code"], 2);
+    }
+
+    /// Stands in for an OpenTelemetry collector, passing on what it receives.
+    async fn collector() -> (String, mpsc::Receiver<(Option<String>, Value)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel(8);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let tx = tx.clone();
+                    async move {
+                        assert_eq!(req.uri().path(), "/v1/traces");
+                        assert_eq!(req.headers()[CONTENT_TYPE], "application/json");
+                        let key = req
+                            .headers()
+                            .get("x-api-key")
+                            .map(|v| v.to_str().unwrap().to_owned());
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        tx.send((key, serde_json::from_slice(&body).unwrap()))
+                            .await
+                            .unwrap();
+                        Ok::<_, Infallible>(Response::new(full(Bytes::from_static(b"{}"))))
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (format!("http://{addr}/v1/traces"), rx)
+    }
+
+    #[tokio::test]
+    async fn exports_to_collector() {
+        let (endpoint, mut received) = collector().await;
+        let config = Telemetry {
+            endpoint,
+            service_name: "envoi-test".into(),
+            headers: HashMap::from([("x-api-key".into(), "secret".into())]),
+        };
+
+        let mut req = Request::builder()
+            .header(&TRACEPARENT, PARENT)
+            .body(())
+            .unwrap();
+        let span = start(&mut req, "", "00000003");
+        let span_id = hex(&span.context.span_id);
+        SPANS
+            .0
+            .try_send(span.to_otlp(Some(StatusCode::BAD_GATEWAY), None))
+            .unwrap();
+
+        flush(&config).await;
+
+        let (key, body) = received.recv().await.unwrap();
+        assert_eq!(key.as_deref(), Some("secret"));
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0],
+            string("service.name", "envoi-test")
+        );
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        let span = spans.iter().find(|s| s["spanId"] == span_id).unwrap();
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["status"]["code"], 2);
+    }
+}
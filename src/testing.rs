@@ -0,0 +1,74 @@
+//! Helpers for tests which need requests as hyper hands them to a server.
+
+use std::convert::Infallible;
+use std::future::{pending, Future};
+use std::net::SocketAddr;
+
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::proxy::ProxyBody;
+
+/// Serve `handler` on a local port for the rest of the test.
+pub async fn serve<F, Fut>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Incoming>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response<ProxyBody>> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let handler = handler.clone();
+            let service = service_fn(move |req| {
+                let res = handler(req);
+                async move { Ok::<_, Infallible>(res.await) }
+            });
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+
+    addr
+}
+
+/// A local address nothing is listening on.
+pub async fn closed() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// `req` as a server receives it, for what takes `Request<Incoming>`. Its
+/// connection stays open for the rest of the test, so the body can be read.
+pub async fn incoming(req: Request<ProxyBody>) -> Request<Incoming> {
+    let (sent, mut received) = mpsc::channel(1);
+    let addr = serve(move |req| {
+        let sent = sent.clone();
+        async move {
+            _ = sent.send(req).await;
+            pending().await
+        }
+    })
+    .await;
+
+    // Only the path is sent, what was asked for is put back afterwards
+    let uri = req.uri().clone();
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    tokio::spawn(async move { sender.send_request(req).await });
+
+    let mut req = received.recv().await.unwrap();
+    *req.uri_mut() = uri;
+    req
+}
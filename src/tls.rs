@@ -1,19 +1,545 @@
+use arc_swap::ArcSwap;
+use once_cell::sync::{Lazy, OnceCell};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::rustls::{
-    pki_types::{CertificateDer, PrivateKeyDer},
-    ServerConfig,
+    pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer},
+    server::{Acceptor as HelloAcceptor, WebPkiClientVerifier},
+    ProtocolVersion, RootCertStore, ServerConfig,
 };
+use tokio_rustls::LazyConfigAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
 
-pub type Acceptor = tokio_rustls::TlsAcceptor;
+use crate::config_loader::{Config, Mtls, UnknownHost};
+use crate::HOSTS;
+
+/// Looks at the client hello before choosing how to continue the handshake.
+#[derive(Clone)]
+pub struct Acceptor {
+    config: Arc<ServerConfig>,
+}
+
+/// Configs which ask for a client certificate, by server name. `None` where
+/// the host's CAs or CRLs could not be loaded, so its handshakes are refused.
+type MtlsConfigs = HashMap<String, Option<Arc<ServerConfig>>>;
+
+/// Rebuilt by `reload` when the hosts change.
+static MTLS: Lazy<ArcSwap<MtlsConfigs>> = Lazy::new(Default::default);
+
+/// The server certificate and key, kept to build the client certificate configs.
+static IDENTITY: OnceCell<(CertificateDer<'static>, PrivateKeyDer<'static>)> = OnceCell::new();
+
+/// Certificates in use, for the admin API.
+static LOADED: Lazy<ArcSwap<Vec<LoadedCert>>> = Lazy::new(Default::default);
+
+/// A certificate Envoi loaded, with its validity in seconds since the epoch.
+#[derive(Clone, Debug)]
+pub struct LoadedCert {
+    /// `server`, or `client_ca` for the CAs a host's client certificates are checked against.
+    pub usage: &'static str,
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: i64,
+    pub not_after: i64,
+    pub fingerprint: String,
+}
+
+/// A client certificate which passed verification.
+#[derive(Clone, Debug)]
+pub struct ClientCert {
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub sans: Vec<String>,
+    /// SHA-256 of the certificate, in lowercase hex.
+    pub fingerprint: String,
+    /// The handshake's server name, whose CAs verified the certificate.
+    pub server_name: String,
+}
 
 pub fn tls_acceptor_impl(key_der: &[u8], cert_der: &[u8]) -> Acceptor {
     let key = PrivateKeyDer::Pkcs1(key_der.to_owned().into());
     let cert = CertificateDer::from(cert_der).into_owned();
-    Arc::new(
-        ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(vec![cert], key)
-            .unwrap(),
-    )
-    .into()
+
+    let _ = IDENTITY.set((cert.clone(), key.clone_key()));
+    reload();
+
+    Acceptor {
+        config: Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert], key)
+                .unwrap(),
+        ),
+    }
+}
+
+/// Set up client certificates again for the current hosts, re-reading their
+/// CA bundles and revocation lists.
+pub fn reload() {
+    let Some((cert, key)) = IDENTITY.get() else {
+        return;
+    };
+    let config = HOSTS.load();
+
+    let mut loaded = loaded_cert(cert, "server", None, None)
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    let mtls = config
+        .hosts
+        .iter()
+        .filter_map(|(name, host)| Some((name.to_ascii_lowercase(), host.mtls.as_ref()?)))
+        .map(|(name, mtls)| {
+            loaded.extend(
+                ca_certs(mtls)
+                    .into_iter()
+                    .filter_map(|ca| loaded_cert(&ca, "client_ca", Some(&name), Some(&mtls.ca))),
+            );
+
+            let config = match mtls_config(mtls, vec![cert.clone()], key.clone_key()) {
+                Ok(config) => Some(Arc::new(config)),
+                Err(e) => {
+                    tracing::error!("Could not set up client certificates for {name}: {e}");
+                    None
+                }
+            };
+            (name, config)
+        })
+        .collect();
+
+    MTLS.store(Arc::new(mtls));
+    LOADED.store(Arc::new(loaded));
+}
+
+/// The server certificate and the client certificate CAs in use.
+pub fn certificates() -> Arc<Vec<LoadedCert>> {
+    LOADED.load_full()
+}
+
+/// The certificates of a host's CA bundle, for listing. Errors are reported when
+/// setting up its config.
+fn ca_certs(mtls: &Mtls) -> Vec<CertificateDer<'static>> {
+    let Ok(ca) = std::fs::read(&mtls.ca) else {
+        return Vec::new();
+    };
+    rustls_pemfile::certs(&mut ca.as_slice())
+        .filter_map(Result::ok)
+        .collect()
+}
+
+fn loaded_cert(
+    der: &[u8],
+    usage: &'static str,
+    host: Option<&str>,
+    path: Option<&str>,
+) -> Option<LoadedCert> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let validity = cert.validity();
+
+    Some(LoadedCert {
+        usage,
+        host: host.map(str::to_owned),
+        path: path.map(str::to_owned),
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        not_before: validity.not_before.timestamp(),
+        not_after: validity.not_after.timestamp(),
+        fingerprint: fingerprint(der),
+    })
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn mtls_config(
+    mtls: &Mtls,
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<ServerConfig, String> {
+    let ca = std::fs::read(&mtls.ca).map_err(|e| format!("{}: {e}", mtls.ca))?;
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut ca.as_slice()) {
+        let cert = cert.map_err(|e| format!("{}: {e}", mtls.ca))?;
+        roots.add(cert).map_err(|e| format!("{}: {e}", mtls.ca))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("no certificates in {}", mtls.ca));
+    }
+
+    let mut crls = Vec::new();
+    for path in &mtls.crls {
+        crls.extend(read_crls(path)?);
+    }
+
+    let mut verifier = WebPkiClientVerifier::builder(Arc::new(roots)).with_crls(crls);
+    if !mtls.required {
+        verifier = verifier.allow_unauthenticated();
+    }
+    let verifier = verifier.build().map_err(|e| e.to_string())?;
+
+    ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| e.to_string())
+}
+
+/// A file of PEM revocation lists, or a single DER one.
+fn read_crls(path: &str) -> Result<Vec<CertificateRevocationListDer<'static>>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let crls = rustls_pemfile::crls(&mut data.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{path}: {e}"))?;
+
+    Ok(match crls.is_empty() {
+        true => vec![CertificateRevocationListDer::from(data)],
+        false => crls,
+    })
+}
+
+/// Whether to refuse the handshake for this server name.
+fn reject_server_name(server_name: Option<&str>) -> bool {
+    rejected(&HOSTS.load(), server_name)
+}
+
+fn rejected(config: &Config, server_name: Option<&str>) -> bool {
+    if !matches!(config.global.unknown_host, Some(UnknownHost::Drop)) {
+        return false;
+    }
+
+    match server_name {
+        // Hosts are keyed in lowercase, as rustls gives the name
+        Some(name) => !config.hosts.contains_key(&name.to_ascii_lowercase()),
+        // Anything connecting by IP
+        None => true,
+    }
+}
+
+/// What the handshake settled, kept for the connection's requests.
+pub struct Session {
+    pub version: Option<&'static str>,
+    pub cert: Option<ClientCert>,
+}
+
+pub fn session<C>(stream: &tokio_rustls::server::TlsStream<C>) -> Session {
+    let version = match stream.get_ref().1.protocol_version() {
+        Some(ProtocolVersion::TLSv1_3) => Some("TLSv1.3"),
+        Some(ProtocolVersion::TLSv1_2) => Some("TLSv1.2"),
+        _ => None,
+    };
+
+    Session {
+        version,
+        cert: client_cert(stream),
+    }
+}
+
+/// The verified certificate the client presented, if any.
+fn client_cert<C>(stream: &tokio_rustls::server::TlsStream<C>) -> Option<ClientCert> {
+    let (_, conn) = stream.get_ref();
+    let der = conn.peer_certificates()?.first()?;
+    ClientCert::parse(der, conn.server_name()?)
+}
+
+impl ClientCert {
+    fn parse(der: &[u8], server_name: &str) -> Option<ClientCert> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_owned);
+
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(ext)) => ext
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => {
+                        Some(s.to_string())
+                    }
+                    GeneralName::IPAddress(bytes) => match bytes.len() {
+                        4 => Some(IpAddr::from(<[u8; 4]>::try_from(*bytes).ok()?).to_string()),
+                        16 => Some(IpAddr::from(<[u8; 16]>::try_from(*bytes).ok()?).to_string()),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Some(ClientCert {
+            subject: cert.subject().to_string(),
+            common_name,
+            sans,
+            fingerprint: fingerprint(der),
+            server_name: server_name.to_owned(),
+        })
+    }
+
+    /// Only certificates verified for `host` count there, as a client can send
+    /// any Host header once connected.
+    pub fn verified_for(&self, host: &str) -> bool {
+        self.server_name.eq_ignore_ascii_case(host)
+    }
+}
+
+/// Whether a request to `host` may go ahead with `cert`.
+pub fn client_cert_allowed(mtls: &Mtls, host: &str, cert: Option<&ClientCert>) -> bool {
+    let Some(cert) = cert.filter(|cert| cert.verified_for(host)) else {
+        return !mtls.required;
+    };
+
+    let subject_allowed = mtls.allow_subjects.is_empty()
+        || mtls.allow_subjects.iter().any(|allowed| {
+            cert.common_name.as_deref() == Some(allowed) || cert.sans.contains(allowed)
+        });
+
+    let fingerprint_allowed = mtls.allow_fingerprints.is_empty()
+        || mtls.allow_fingerprints.iter().any(|allowed| {
+            allowed
+                .replace(':', "")
+                .eq_ignore_ascii_case(&cert.fingerprint)
+        });
+
+    subject_allowed && fingerprint_allowed
+}
+
+impl<C> tls_listener::AsyncTls<C> for Acceptor
+where
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = tokio_rustls::server::TlsStream<C>;
+    type Error = io::Error;
+    type AcceptFuture = Pin<Box<dyn Future<Output = io::Result<Self::Stream>> + Send>>;
+
+    fn accept(&self, conn: C) -> Self::AcceptFuture {
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            let start = LazyConfigAcceptor::new(HelloAcceptor::default(), conn).await?;
+
+            let server_name = start.client_hello().server_name().map(str::to_owned);
+            if reject_server_name(server_name.as_deref()) {
+                // Dropping the connection without an alert
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("unknown server name {server_name:?}"),
+                ));
+            }
+
+            let mtls = server_name
+                .as_ref()
+                .and_then(|name| MTLS.load().get(name).cloned());
+            let config = match mtls {
+                Some(Some(config)) => config,
+                Some(None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!("client certificates for {server_name:?} are not set up"),
+                    ))
+                }
+                None => config,
+            };
+
+            start.into_stream(config).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::*;
+
+    const DIR: &str = "res/test/mtls";
+
+    fn certs(name: &str) -> Vec<CertificateDer<'static>> {
+        let pem = std::fs::read(format!("{DIR}/{name}.pem")).unwrap();
+        rustls_pemfile::certs(&mut pem.as_slice())
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn key(name: &str) -> PrivateKeyDer<'static> {
+        let pem = std::fs::read(format!("{DIR}/{name}.key")).unwrap();
+        rustls_pemfile::private_key(&mut pem.as_slice())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn mtls(required: bool) -> Mtls {
+        Mtls {
+            ca: format!("{DIR}/ca.pem"),
+            crls: vec![format!("{DIR}/ca.crl")],
+            required,
+            allow_subjects: Vec::new(),
+            allow_fingerprints: Vec::new(),
+        }
+    }
+
+    /// Handshake as `client`, returning what the server saw of its certificate.
+    async fn handshake(mtls: &Mtls, client: Option<&str>) -> io::Result<Option<ClientCert>> {
+        let server = mtls_config(mtls, certs("server"), key("server")).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certs("ca").remove(0)).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let client = match client {
+            Some(name) => builder
+                .with_client_auth_cert(certs(name), key(name))
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+
+        let (client_io, server_io) = duplex(16 * 1024);
+        let server_name = ServerName::try_from("admin.test").unwrap();
+
+        let (server, client) = tokio::join!(
+            async {
+                let mut stream = TlsAcceptor::from(Arc::new(server))
+                    .accept(server_io)
+                    .await?;
+                // TLS 1.3 clients only learn their certificate was refused here
+                stream.write_all(b"ok").await?;
+                stream.flush().await?;
+                Ok::<_, io::Error>(client_cert(&stream))
+            },
+            async {
+                let mut stream = TlsConnector::from(Arc::new(client))
+                    .connect(server_name, client_io)
+                    .await?;
+                let mut buf = [0; 2];
+                stream.read_exact(&mut buf).await?;
+                Ok::<_, io::Error>(())
+            }
+        );
+
+        client?;
+        server
+    }
+
+    #[tokio::test]
+    async fn verified_certificate() {
+        let cert = handshake(&mtls(true), Some("laptop"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(cert.common_name.as_deref(), Some("laptop"));
+        assert_eq!(cert.subject, "CN=laptop");
+        assert_eq!(
+            cert.sans,
+            ["laptop.citrusfire.internal", "admin@citrusfire.co.uk"]
+        );
+        assert_eq!(
+            cert.fingerprint,
+            format!("{:x}", Sha256::digest(&certs("laptop")[0]))
+        );
+        assert_eq!(cert.server_name, "admin.test");
+    }
+
+    #[tokio::test]
+    async fn refused_certificates() {
+        // Revoked in ca.crl
+        assert!(handshake(&mtls(true), Some("stolen")).await.is_err());
+        // Issued by another CA
+        assert!(handshake(&mtls(true), Some("untrusted")).await.is_err());
+        assert!(handshake(&mtls(false), Some("untrusted")).await.is_err());
+        // None at all
+        assert!(handshake(&mtls(true), None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn optional_certificate() {
+        assert!(handshake(&mtls(false), None).await.unwrap().is_none());
+        assert!(handshake(&mtls(false), Some("laptop"))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn missing_ca() {
+        let mtls = Mtls {
+            ca: format!("{DIR}/missing.pem"),
+            ..mtls(true)
+        };
+        assert!(mtls_config(&mtls, certs("server"), key("server")).is_err());
+    }
+
+    #[test]
+    fn allow_rules() {
+        let cert = ClientCert::parse(&certs("laptop")[0], "admin.test").unwrap();
+
+        let mut rules = mtls(true);
+        assert!(client_cert_allowed(&rules, "admin.test", Some(&cert)));
+        assert!(client_cert_allowed(&rules, "ADMIN.test", Some(&cert)));
+        // Verified for a different host
+        assert!(!client_cert_allowed(&rules, "other.test", Some(&cert)));
+        assert!(!client_cert_allowed(&rules, "admin.test", None));
+
+        rules.required = false;
+        assert!(client_cert_allowed(&rules, "admin.test", None));
+        assert!(client_cert_allowed(&rules, "other.test", Some(&cert)));
+
+        rules.allow_subjects = vec!["phone".into()];
+        assert!(!client_cert_allowed(&rules, "admin.test", Some(&cert)));
+        rules
+            .allow_subjects
+            .push("laptop.citrusfire.internal".into());
+        assert!(client_cert_allowed(&rules, "admin.test", Some(&cert)));
+
+        let colons = cert
+            .fingerprint
+            .to_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        rules.allow_fingerprints = vec![colons];
+        assert!(client_cert_allowed(&rules, "admin.test", Some(&cert)));
+        rules.allow_fingerprints = vec!["00".repeat(32)];
+        assert!(!client_cert_allowed(&rules, "admin.test", Some(&cert)));
+    }
+
+    #[test]
+    fn server_names_matched_ignoring_case() {
+        let config = |unknown_host| {
+            let config = serde_json::json!({
+                "unknown_host": unknown_host,
+                "hosts": [{ "host": "Emby.Example.com", "respond": {} }],
+            });
+            Config::parse(&config.to_string()).unwrap()
+        };
+
+        let drop = config(serde_json::json!("drop"));
+        assert!(!rejected(&drop, Some("emby.example.com")));
+        assert!(rejected(&drop, Some("plex.example.com")));
+        assert!(rejected(&drop, None));
+
+        let not_found = config(serde_json::Value::Null);
+        assert!(!rejected(&not_found, Some("plex.example.com")));
+        assert!(!rejected(&not_found, None));
+    }
 }
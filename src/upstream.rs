@@ -0,0 +1,585 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::{BodyExt, Limited};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::http::uri::Scheme;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use once_cell::sync::Lazy;
+use tokio::time::{Instant, Sleep};
+use tower::Service;
+use tower_http::timeout::TimeoutBody;
+
+use crate::body::{self, BytesIn, Counted};
+use crate::config_loader::Timeouts;
+use crate::error_pages::UpstreamError;
+use crate::proxy::{BoxError, ProxyBody};
+
+type UpstreamClient = Client<Tracking, ProxyBody>;
+
+/// Largest response body `fetch` reads.
+const FETCH_LIMIT: usize = 1024 * 1024;
+
+/// Failed requests in a row before an upstream counts as unhealthy.
+const UNHEALTHY_AFTER: u32 = 3;
+
+/// Connect and pool idle timeouts.
+type ClientKey = (Option<Duration>, Option<Duration>);
+
+/// Clients are shared between hosts with the same connect and idle timeouts,
+/// so each distinct pair gets its own connection pool.
+static CLIENTS: Lazy<Mutex<HashMap<ClientKey, UpstreamClient>>> = Lazy::new(Default::default);
+
+/// For services Envoi talks to itself, like OIDC issuers, which are usually on HTTPS.
+static EXTERNAL: Lazy<Client<HttpsConnector<HttpConnector>, ProxyBody>> = Lazy::new(|| {
+    let mut connector = HttpConnector::new();
+    connector.enforce_http(false);
+
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(connector);
+
+    Client::builder(TokioExecutor::new())
+        .pool_timer(TokioTimer::new())
+        .build(connector)
+});
+
+fn client(timeouts: &Timeouts) -> UpstreamClient {
+    let key = (timeouts.connect(), timeouts.upstream_idle());
+
+    CLIENTS
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| {
+            let mut connector = HttpConnector::new();
+            connector.set_connect_timeout(key.0);
+
+            Client::builder(TokioExecutor::new())
+                .pool_timer(TokioTimer::new())
+                .pool_idle_timeout(key.1)
+                .build(Tracking(connector))
+        })
+        .clone()
+}
+
+/// Whether an upstream is sent new requests, set through the admin API.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Active,
+    /// No new requests, those in flight finish.
+    Draining,
+    /// No new requests, and its open connections are cut off.
+    Disabled,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mode::Active => "active",
+            Mode::Draining => "draining",
+            Mode::Disabled => "disabled",
+        })
+    }
+}
+
+/// What is known about an upstream from the requests sent to it.
+#[derive(Default)]
+struct UpstreamState {
+    mode: AtomicU8,
+    connections: AtomicUsize,
+    in_flight: AtomicUsize,
+    requests: AtomicU64,
+    failures: AtomicU64,
+    health: Mutex<Health>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    pub consecutive_failures: u32,
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+    pub last_error: Option<String>,
+}
+
+impl UpstreamState {
+    fn mode(&self) -> Mode {
+        match self.mode.load(Ordering::Relaxed) {
+            1 => Mode::Draining,
+            2 => Mode::Disabled,
+            _ => Mode::Active,
+        }
+    }
+
+    fn record<T>(&self, result: &Result<T, UpstreamError>) {
+        let mut health = self.health.lock().unwrap();
+        match result {
+            Ok(_) => {
+                health.consecutive_failures = 0;
+                health.last_success = Some(SystemTime::now());
+            }
+            // The client's fault, or ours
+            Err(UpstreamError::ClientTimeout | UpstreamError::Unavailable(_)) => {}
+            Err(err) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                health.consecutive_failures += 1;
+                health.last_failure = Some(SystemTime::now());
+                health.last_error = Some(err.to_string());
+            }
+        }
+    }
+}
+
+/// By `host:port`, added when first configured or connected to.
+static UPSTREAMS: Lazy<DashMap<String, Arc<UpstreamState>>> = Lazy::new(DashMap::new);
+
+fn state(address: &str) -> Arc<UpstreamState> {
+    UPSTREAMS.entry(address.to_owned()).or_default().clone()
+}
+
+/// `host:port` of the upstream `uri` points to.
+pub fn address(uri: &Uri) -> Option<String> {
+    let host = uri.host()?;
+    let port = uri.port_u16().unwrap_or(match uri.scheme() {
+        Some(scheme) if *scheme == Scheme::HTTPS => 443,
+        _ => 80,
+    });
+    Some(format!("{host}:{port}"))
+}
+
+/// An upstream as seen by the admin API.
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub mode: Mode,
+    /// `None` until it has been sent a request.
+    pub healthy: Option<bool>,
+    pub connections: usize,
+    pub in_flight: usize,
+    pub requests: u64,
+    pub failures: u64,
+    pub health: Health,
+}
+
+pub fn status(address: &str) -> Status {
+    let state = state(address);
+    let health = state.health.lock().unwrap().clone();
+    let healthy = (health.last_success.is_some() || health.last_failure.is_some())
+        .then_some(health.consecutive_failures < UNHEALTHY_AFTER);
+
+    Status {
+        mode: state.mode(),
+        healthy,
+        connections: state.connections.load(Ordering::Relaxed),
+        in_flight: state.in_flight.load(Ordering::Relaxed),
+        requests: state.requests.load(Ordering::Relaxed),
+        failures: state.failures.load(Ordering::Relaxed),
+        health,
+    }
+}
+
+/// Every upstream connected to so far.
+pub fn addresses() -> Vec<String> {
+    UPSTREAMS.iter().map(|u| u.key().clone()).collect()
+}
+
+pub fn set_mode(address: &str, mode: Mode) {
+    let state = state(address);
+    state.mode.store(mode as u8, Ordering::Relaxed);
+    tracing::info!("Upstream {address} is now {mode}");
+}
+
+/// Counted as in flight until dropped.
+struct InFlight(Arc<UpstreamState>);
+
+impl InFlight {
+    fn start(state: Arc<UpstreamState>) -> Self {
+        state.requests.fetch_add(1, Ordering::Relaxed);
+        state.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(state)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Connects to upstreams, counting the connections to each of them.
+#[derive(Clone)]
+pub struct Tracking(HttpConnector);
+
+impl Service<Uri> for Tracking {
+    type Response = Tracked<<HttpConnector as Service<Uri>>::Response>;
+    type Error = <HttpConnector as Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let upstream = address(&dst).map(|address| state(&address));
+        let connecting = self.0.call(dst);
+
+        Box::pin(async move {
+            let io = connecting.await?;
+            if let Some(upstream) = &upstream {
+                upstream.connections.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Tracked {
+                inner: io,
+                upstream,
+            })
+        })
+    }
+}
+
+/// A connection to an upstream, which fails its next read or write once the
+/// upstream is disabled.
+pub struct Tracked<T> {
+    inner: T,
+    upstream: Option<Arc<UpstreamState>>,
+}
+
+impl<T> Tracked<T> {
+    fn check(&self) -> io::Result<()> {
+        match &self.upstream {
+            Some(upstream) if upstream.mode() == Mode::Disabled => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "upstream disabled",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        if let Some(upstream) = &self.upstream {
+            upstream.connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<T: Read + Unpin> Read for Tracked<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.check()?;
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: Write + Unpin> Write for Tracked<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.check()?;
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Connection> Connection for Tracked<T> {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
+
+/// Send `req` to the upstream in its URI, applying the request body,
+/// time-to-first-byte and total request timeouts.
+pub async fn forward(
+    req: Request<Incoming>,
+    timeouts: &Timeouts,
+) -> Result<Response<ProxyBody>, UpstreamError> {
+    let upstream = address(req.uri()).map(|address| state(&address));
+    if let Some(mode) = upstream.as_ref().map(|u| u.mode()) {
+        if mode != Mode::Active {
+            return Err(UpstreamError::Unavailable(format!("upstream is {mode}")));
+        }
+    }
+    let in_flight = upstream.map(InFlight::start);
+
+    let deadline = timeouts.request().map(|d| Instant::now() + d);
+    let result = request(req, timeouts, deadline).await;
+    if let Some(in_flight) = &in_flight {
+        in_flight.0.record(&result);
+    }
+    let response = result?;
+
+    let response = match deadline {
+        Some(deadline) => response.map(|body| DeadlineBody::new(body, deadline).boxed_unsync()),
+        None => response.map(|body| body.map_err(Into::into).boxed_unsync()),
+    };
+
+    // Still in flight until the client has the whole body
+    Ok(match in_flight {
+        Some(in_flight) => response.map(|body| body::on_sent(body, move |_| drop(in_flight))),
+        None => response,
+    })
+}
+
+/// Up to the response headers of `forward`.
+async fn request(
+    req: Request<Incoming>,
+    timeouts: &Timeouts,
+    deadline: Option<Instant>,
+) -> Result<Response<Incoming>, UpstreamError> {
+    let counter = req.extensions().get::<BytesIn>().cloned();
+    let req = req.map(|body| Counted::new(body, counter));
+    let req = match timeouts.request_body() {
+        Some(timeout) => req.map(|body| TimeoutBody::new(timeout, body).boxed_unsync()),
+        None => req.map(|body| body.map_err(Into::into).boxed_unsync()),
+    };
+
+    // The response headers have to arrive before whichever comes first.
+    let first_byte = match (timeouts.upstream_response(), deadline) {
+        (Some(ttfb), Some(deadline)) if deadline < Instant::now() + ttfb => {
+            Some((deadline, "request deadline exceeded"))
+        }
+        (Some(ttfb), _) => Some((Instant::now() + ttfb, "no response from upstream in time")),
+        (None, Some(deadline)) => Some((deadline, "request deadline exceeded")),
+        (None, None) => None,
+    };
+
+    let response = client(timeouts).request(req);
+
+    match first_byte {
+        Some((at, msg)) => match tokio::time::timeout_at(at, response).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(UpstreamError::Timeout(msg.to_owned())),
+        },
+        None => Ok(response.await?),
+    }
+}
+
+/// Send a request Envoi made itself, such as an auth check, with the connect
+/// and time-to-first-byte timeouts.
+pub async fn send(
+    req: Request<ProxyBody>,
+    timeouts: &Timeouts,
+) -> Result<Response<ProxyBody>, UpstreamError> {
+    let response = client(timeouts).request(req);
+
+    let response = match timeouts.upstream_response() {
+        Some(ttfb) => match tokio::time::timeout(ttfb, response).await {
+            Ok(result) => result?,
+            Err(_) => return Err(UpstreamError::Timeout("no response in time".to_owned())),
+        },
+        None => response.await?,
+    };
+
+    Ok(response.map(|body| body.map_err(Into::into).boxed_unsync()))
+}
+
+/// Send a request Envoi made itself over HTTP or HTTPS, reading the whole
+/// response within `timeout`.
+pub async fn fetch(
+    req: Request<ProxyBody>,
+    timeout: Duration,
+) -> Result<(StatusCode, Bytes), UpstreamError> {
+    let read = async {
+        let res = EXTERNAL.request(req).await?;
+        let status = res.status();
+        let body = Limited::new(res.into_body(), FETCH_LIMIT)
+            .collect()
+            .await
+            .map_err(|e| UpstreamError::Unavailable(format!("reading response: {e}")))?
+            .to_bytes();
+        Ok((status, body))
+    };
+
+    match tokio::time::timeout(timeout, read).await {
+        Ok(result) => result,
+        Err(_) => Err(UpstreamError::Timeout("no response in time".to_owned())),
+    }
+}
+
+/// Fails the response body once the request deadline passes.
+struct DeadlineBody<B> {
+    inner: B,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<B> DeadlineBody<B> {
+    fn new(inner: B, deadline: Instant) -> Self {
+        DeadlineBody {
+            inner,
+            sleep: Box::pin(tokio::time::sleep_until(deadline)),
+        }
+    }
+}
+
+impl<B> Body for DeadlineBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if self.sleep.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(Err("request deadline exceeded".into())));
+        }
+        Pin::new(&mut self.inner).poll_frame(cx).map_err(Into::into)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, StreamExt};
+    use http_body_util::StreamBody;
+    use serde_json::json;
+
+    use super::*;
+    use crate::proxy::full;
+    use crate::testing;
+
+    fn timeouts(value: serde_json::Value) -> Timeouts {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// A body which sends `first`, then nothing more, never ending.
+    fn stalled(first: &'static str) -> ProxyBody {
+        let frames = stream::iter([Ok::<_, BoxError>(Frame::data(Bytes::from(first)))])
+            .chain(stream::pending());
+        StreamBody::new(frames).boxed_unsync()
+    }
+
+    #[test]
+    fn addresses_have_ports() {
+        let address = |uri: &str| address(&uri.parse().unwrap());
+
+        assert_eq!(
+            address("http://192.168.68.100:8096/web"),
+            Some("192.168.68.100:8096".into())
+        );
+        assert_eq!(
+            address("http://emby.internal"),
+            Some("emby.internal:80".into())
+        );
+        assert_eq!(
+            address("https://emby.internal/"),
+            Some("emby.internal:443".into())
+        );
+        assert_eq!(address("/relative"), None);
+    }
+
+    #[test]
+    fn unhealthy_after_failures() {
+        let address = "health.test:80";
+        assert_eq!(status(address).healthy, None);
+
+        let state = state(address);
+        let failed = || Err::<(), _>(UpstreamError::Timeout("no response".into()));
+        state.record(&failed());
+        state.record(&failed());
+        assert_eq!(status(address).healthy, Some(true));
+        state.record(&failed());
+        assert_eq!(status(address).healthy, Some(false));
+        assert_eq!(
+            status(address).health.last_error.as_deref(),
+            Some("no response")
+        );
+
+        // Not the upstream's doing
+        state.record(&Err::<(), _>(UpstreamError::ClientTimeout));
+        assert_eq!(status(address).failures, 3);
+
+        state.record(&Ok::<_, UpstreamError>(()));
+        assert_eq!(status(address).healthy, Some(true));
+        assert_eq!(status(address).health.consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn body_cut_off_at_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let err = DeadlineBody::new(stalled("partial"), deadline)
+            .collect()
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "request deadline exceeded");
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let body = DeadlineBody::new(full("whole"), deadline).collect().await;
+        assert_eq!(body.unwrap().to_bytes(), "whole");
+    }
+
+    #[tokio::test]
+    async fn deadline_covers_response_body() {
+        let addr = testing::serve(|_| async { Response::new(stalled("partial")) }).await;
+        let req = Request::get(format!("http://{addr}/"))
+            .body(full(""))
+            .unwrap();
+
+        let timeouts = timeouts(json!({ "request": 0.1 }));
+        let res = forward(testing::incoming(req).await, &timeouts)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let err = res.into_body().collect().await.unwrap_err();
+        assert_eq!(err.to_string(), "request deadline exceeded");
+    }
+
+    #[tokio::test]
+    async fn slow_client_body_is_408() {
+        let addr = testing::serve(|req: Request<Incoming>| async move {
+            _ = req.into_body().collect().await;
+            Response::new(full(""))
+        })
+        .await;
+        let req = Request::post(format!("http://{addr}/"))
+            .body(stalled("partial"))
+            .unwrap();
+
+        let timeouts = timeouts(json!({ "request_body": 0.1 }));
+        let err = forward(testing::incoming(req).await, &timeouts)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UpstreamError::ClientTimeout), "{err}");
+        assert_eq!(err.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+}